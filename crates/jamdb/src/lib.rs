@@ -1,10 +1,11 @@
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::path::Path;
 use std::fs::{self};
 use regex::Regex;
 use serde::{Serialize, Deserialize};
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OptionalExtension, params};
 
 //fn create_and_add_jams(db: &mut app::database::Database)
 //{
@@ -31,11 +32,50 @@ pub struct Jam
     pub tracks: Vec<String>,
 }
 
-pub fn walk_directories(dir: &Path, jam_map: &mut HashMap<String, Jam>) 
+/// The naming scheme stems have to follow to be picked up, e.g. `01-KaggeN-210319_1833.wav`
+pub struct StemRules
+{
+    date: Regex,
+    track: Regex,
+}
+
+impl Default for StemRules
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl StemRules
+{
+    pub fn new() -> Self
+    {
+        StemRules
+        {
+            date: Regex::new(r"([0-9]{6}_[0-9]{4})").unwrap(),
+            track: Regex::new(r"[0-9]{2}-.*-").unwrap(),
+        }
+    }
+
+    /// Returns the jam date and track name of a stem, or None if the file isn't one
+    pub fn parse(&self, path: &Path) -> Option<(String, String)>
+    {
+        let path_str = path.to_str()?;
+        if path.extension()? != "wav" || !self.date.is_match(path_str)
+        {
+            return None;
+        }
+        let jam = self.date.find(path_str)?.as_str().to_string();
+        let mut track = self.track.find(path_str)?.as_str().to_string();
+        track.pop();
+        Some((jam, track))
+    }
+}
+
+/// Directory part of a path as it is stored in the jams table, with a trailing separator
+pub fn jam_dir(path: &Path) -> Option<String>
 {
-    //Move regex initializion to separate function
-    let regex = Regex::new(r"([0-9]{6}_[0-9]{4})").unwrap();
-    let trackre = Regex::new(r"[0-9]{2}-.*-").unwrap();
     let pathre: Regex;
 
     if cfg!(unix) 
@@ -49,40 +89,65 @@ pub fn walk_directories(dir: &Path, jam_map: &mut HashMap<String, Jam>)
     else 
     {
         println!("Platform not supported");
-        return;   
+        return None;   
     }
+    Some(pathre.find(path.to_str()?)?.as_str().to_string())
+}
 
-    for entry in fs::read_dir(dir).unwrap()
+pub fn walk_directories(dir: &Path, jam_map: &mut HashMap<String, Jam>) 
+{
+    let rules = StemRules::new();
+    walk_with_rules(dir, jam_map, &rules);
+}
+
+fn walk_with_rules(dir: &Path, jam_map: &mut HashMap<String, Jam>, rules: &StemRules)
+{
+    // Only recorded when the program installed a metrics recorder, the web server does
+    metrics::counter!("repan_scan_directories_total").increment(1);
+    let entries = match fs::read_dir(dir)
     {
-        let path = entry.unwrap().path();
+        Ok(entries) => entries,
+        Err(e) =>
+        {
+            println!("Couldn't read directory: {}, error: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries
+    {
+        let Ok(entry) = entry else
+        {
+            continue;
+        };
+        let path = entry.path();
         if path.is_dir()
         {
             println!("Found directory: {}", path.to_str().unwrap());
-            walk_directories(&path, jam_map);
+            walk_with_rules(&path, jam_map, rules);
         }
-        else if path.is_file() && path.extension().unwrap() == "wav" && regex.is_match(path.to_str().unwrap())
+        else if path.is_file()
         {
-
-            let jam = regex.find(path.to_str().unwrap()).unwrap().as_str();
-            if !trackre.is_match(path.to_str().unwrap())
+            let Some((jam, track)) = rules.parse(&path) else
             {
                 continue;
-            }
-            let mut track = trackre.find(path.to_str().unwrap()).unwrap().as_str().to_string();
-            track.pop();
+            };
+            metrics::counter!("repan_scan_stems_total").increment(1);
             
-            if jam_map.contains_key(jam)
+            // Skip the stem rather than the rest of the scan
+            let Some(dirpath) = jam_dir(&path) else
             {
-
-                let jamref = jam_map.get_mut(jam).unwrap();
-                jamref.tracks.push(track);
-            }
-            else 
+                println!("Couldn't find the directory of: {}", path.display());
+                continue;
+            };
+            // Keyed by directory too, bands recording on the same day each have their own jam
+            match jam_map.entry(format!("{}{}", dirpath, jam))
             {
-                let dirpath = pathre.find(path.to_str().unwrap()).unwrap().as_str().to_string();
-                let tracks: Vec<String> = vec![track];
-                let jam_data = Jam {date: jam.to_string(), path: dirpath, tracks};
-                jam_map.insert(jam.to_string(), jam_data);
+                Entry::Occupied(mut entry) => entry.get_mut().tracks.push(track),
+                Entry::Vacant(entry) =>
+                {
+                    let tracks: Vec<String> = vec![track];
+                    entry.insert(Jam {date: jam, path: dirpath, tracks});
+                }
             }
         }
     }
@@ -134,14 +199,15 @@ pub fn scan_and_save_jams(directories: &Vec<&Path>, save_path: &str) -> std::io:
 
 }
 
+/// Like `add_track`, a jam is its date in its directory.
 pub fn add_jam(conn: &mut Connection, jam: &Jam) -> Result<(), Box<dyn Error>>
 {
 
-    let mut check_if_exists = conn.prepare("SELECT EXISTS(SELECT 1 FROM jams WHERE date = ?1)")?;
+    let mut check_if_exists = conn.prepare("SELECT EXISTS(SELECT 1 FROM jams WHERE date = ?1 AND path = ?2)")?;
 
-    if check_if_exists.query_row([&jam.date], |row| row.get(0))?
+    if check_if_exists.query_row([&jam.date, &jam.path], |row| row.get(0))?
     {
-        println!("Attempted to add jam: {} that already exists", &jam.date);
+        println!("Attempted to add jam: {} in {} that already exists", jam.date, jam.path);
         return Ok(());
     }
 
@@ -159,6 +225,35 @@ pub fn add_jam(conn: &mut Connection, jam: &Jam) -> Result<(), Box<dyn Error>>
     }
     Ok(())
 }
+/// A jam is a date in one directory, bands recording on the same day each have their own.
+pub fn track_exists(conn: &Connection, date: &str, path: &str, track: &str) -> rusqlite::Result<bool>
+{
+    conn.query_row("SELECT EXISTS(SELECT 1 FROM tracks JOIN jams ON jams.id = tracks.jam_id WHERE jams.date = ?1 AND jams.path = ?2 AND tracks.track = ?3)",
+        params![date, path, track], |row| row.get(0))
+}
+
+/// Adds a single stem, creating its jam if this is the first stem of that date in `path`.
+/// Returns the id of the jam.
+pub fn add_track(conn: &mut Connection, date: &str, path: &str, track: &str) -> Result<i64, Box<dyn Error>>
+{
+    let jam_id: Option<i64> = conn.query_row("SELECT id FROM jams WHERE date = ?1 AND path = ?2", [date, path], |row| row.get(0))
+        .optional()?;
+
+    let jam_id = match jam_id
+    {
+        Some(id) => id,
+        None =>
+        {
+            conn.execute("INSERT INTO jams (date, path) VALUES (:date, :path)", 
+                &[(":date", date), (":path", path)])?;
+            conn.last_insert_rowid()
+        }
+    };
+
+    conn.execute("INSERT INTO tracks (jam_id, track) VALUES (?1, ?2)", params![jam_id, track])?;
+    Ok(jam_id)
+}
+
 pub fn create_jam_table(conn: &mut Connection)
 {
     conn.execute_batch(" BEGIN;
@@ -174,3 +269,32 @@ pub fn create_jam_table(conn: &mut Connection)
         COMMIT;",).unwrap();
 
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn same_date_in_another_directory_is_another_jam()
+    {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_jam_table(&mut conn);
+
+        let ours = add_track(&mut conn, "210319_1833", "/bands/ours/", "01-KaggeN").unwrap();
+        let theirs = add_track(&mut conn, "210319_1833", "/bands/theirs/", "01-KaggeN").unwrap();
+        assert_ne!(ours, theirs);
+        assert_eq!(add_track(&mut conn, "210319_1833", "/bands/ours/", "02-Bass").unwrap(), ours);
+
+        assert!(track_exists(&conn, "210319_1833", "/bands/ours/", "02-Bass").unwrap());
+        assert!(!track_exists(&conn, "210319_1833", "/bands/theirs/", "02-Bass").unwrap());
+
+        let jam = |path: &str| Jam { date: "220101_1200".to_string(), path: path.to_string(), tracks: vec!["01-KaggeN".to_string()] };
+        add_jam(&mut conn, &jam("/bands/ours/")).unwrap();
+        add_jam(&mut conn, &jam("/bands/theirs/")).unwrap();
+        add_jam(&mut conn, &jam("/bands/ours/")).unwrap();
+        let jams: i64 = conn.query_row("SELECT COUNT(*) FROM jams WHERE date = '220101_1200'", [], |row| row.get(0)).unwrap();
+        assert_eq!(jams, 2);
+        assert!(track_exists(&conn, "220101_1200", "/bands/theirs/", "01-KaggeN").unwrap());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, env, path::Path};
+use std::{collections::HashMap, env, path::PathBuf};
 
 use jamdb::{Jam, add_jam, create_jam_table, walk_directories};
 use rusqlite::Connection;
@@ -18,27 +18,47 @@ fn main()
     //println!("test.txt exists is: {}",res); 
     //println!("not.txt exists is: {}", not_res); 
 
-    if args.len() < 2 
+    let config = match repan_config::init()
     {
-        println!("Not enough arguments");
-        return;
-    }
-    else if args.len() > 2
+        Ok(config) => config,
+        Err(e) =>
+        {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Without a directory every library root of the configuration is scanned
+    let jam_dirs: Vec<PathBuf> = if args.len() > 2
     {
         println!("Too many arguments");
         return;
     }
-    let jam_dir = Path::new(args[1].as_str());
-    if !jam_dir.exists() || !jam_dir.is_dir()
+    else if args.len() == 2
     {
-
-        println!("Path given is not valid or is not a directory");
-        return;
+        vec![PathBuf::from(&args[1])]
     }
+    else if !config.library.roots.is_empty()
+    {
+        config.library.roots.clone()
+    }
+    else
+    {
+        println!("No directory given and no library roots configured");
+        return;
+    };
     let mut jam_map: HashMap<String, Jam> = HashMap::new();
-    walk_directories(jam_dir, &mut jam_map);
+    for jam_dir in &jam_dirs
+    {
+        if !jam_dir.exists() || !jam_dir.is_dir()
+        {
+            println!("{} is not valid or is not a directory", jam_dir.display());
+            return;
+        }
+        walk_directories(jam_dir, &mut jam_map);
+    }
 
-    let db_path = Path::new("jams.db");
+    let db_path = config.database.path.as_path();
     if db_path.exists()
     {
         println!("Database already exists!");
@@ -0,0 +1,261 @@
+//! A streamer without GStreamer, for testing the web server.
+//!
+//! It connects to `/ws` with a signed token like webrtctest does and speaks the same protocol.
+//! Offers and ICE restarts get a canned SDP answer and one host candidate, renders finish at
+//! once. Everything the server sends is recorded, tests wait for the messages they expect.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use futures_util::{SinkExt, StreamExt};
+use repan_protocol::{
+    Capability, Envelope, IceSettings, PROTOCOL_VERSION, ServerMsg, StreamerMsg, auth::worker_token,
+};
+use tokio::{
+    sync::{
+        Notify,
+        mpsc::{self, UnboundedSender},
+    },
+    task::JoinHandle,
+};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{HeaderValue, header::AUTHORIZATION};
+use tungstenite::{Message, Utf8Bytes};
+
+/// What the mock answers every offer with. Enough for the server, which never looks inside.
+pub const CANNED_ANSWER: &str = "v=0\r\n\
+    o=- 0 0 IN IP4 127.0.0.1\r\n\
+    s=-\r\n\
+    t=0 0\r\n\
+    m=audio 9 UDP/TLS/RTP/SAVPF 96\r\n\
+    c=IN IP4 0.0.0.0\r\n\
+    a=rtpmap:96 OPUS/48000/2\r\n\
+    a=sendonly\r\n";
+
+/// The candidate sent after every answer, a host one so LAN-only servers pass it on too.
+pub const CANNED_CANDIDATE: &str = "candidate:1 1 UDP 2122252543 127.0.0.1 40000 typ host";
+
+/// Plenty for `wait_for` on localhost, where the server replies within milliseconds.
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The server turned the mock away after its `Hello`.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Turned away by the server: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// How the mock introduces itself and answers.
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    /// Also the worker its token is signed for.
+    pub name: String,
+    pub capacity: usize,
+    pub capabilities: Vec<Capability>,
+    /// Sent in `Hello`, another version than the server's gets the mock rejected.
+    pub protocol: u32,
+    /// Left unanswered, offers run into the server's answer timeout.
+    pub answer_offers: bool,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            name: "mock".to_string(),
+            capacity: 4,
+            capabilities: Capability::ALL.to_vec(),
+            protocol: PROTOCOL_VERSION,
+            answer_offers: true,
+        }
+    }
+}
+
+/// A connected mock streamer. Dropping it drops the connection, the server sees the streamer
+/// disappear.
+pub struct MockStreamer {
+    ice: IceSettings,
+    received: Arc<Mutex<Vec<Envelope<ServerMsg>>>>,
+    arrived: Arc<Notify>,
+    out_tx: UnboundedSender<Message>,
+    task: JoinHandle<()>,
+}
+
+impl MockStreamer {
+    /// Connects to the websocket at `url` and waits for the server's welcome.
+    pub async fn connect(
+        url: &str,
+        secret: &str,
+        config: MockConfig,
+    ) -> Result<MockStreamer, anyhow::Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let token = worker_token(secret, &config.name, now);
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await?;
+
+        let hello = StreamerMsg::Hello {
+            protocol: config.protocol,
+            name: config.name.clone(),
+            capacity: config.capacity,
+            capabilities: config.capabilities.clone(),
+        };
+        ws.send(encode(&config.name, hello)?).await?;
+        let ice = loop {
+            match ws.next().await {
+                Some(Ok(Message::Text(msg))) => {
+                    let reply: Envelope<ServerMsg> = serde_json::from_str(&msg)?;
+                    match reply.msg {
+                        ServerMsg::Welcome { ice, .. } => break ice,
+                        ServerMsg::Rejected { reason } => return Err(Rejected(reason).into()),
+                        other => return Err(anyhow!("Expected a welcome, got {:?}", other)),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(anyhow!("The server closed the socket before welcoming us"));
+                }
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            }
+        };
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let arrived = Arc::new(Notify::new());
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        let task = {
+            let received = received.clone();
+            let arrived = arrived.clone();
+            let out_tx = out_tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        msg = ws.next() => match msg {
+                            Some(Ok(Message::Text(msg))) => {
+                                let Ok(msg) = serde_json::from_str::<Envelope<ServerMsg>>(&msg)
+                                else {
+                                    eprintln!("Mock got a message it can't read: {}", msg);
+                                    continue;
+                                };
+                                reply(&config, &msg, &out_tx);
+                                received.lock().unwrap().push(msg);
+                                arrived.notify_waiters();
+                            }
+                            Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                            // Pings are answered by tungstenite.
+                            Some(Ok(_)) => (),
+                        },
+                        Some(msg) = out_rx.recv() => {
+                            if ws.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                arrived.notify_waiters();
+            })
+        };
+
+        Ok(MockStreamer {
+            ice,
+            received,
+            arrived,
+            out_tx,
+            task,
+        })
+    }
+
+    /// The ICE settings of the server's welcome.
+    pub fn ice(&self) -> &IceSettings {
+        &self.ice
+    }
+
+    /// Every message the server sent since the welcome, oldest first.
+    pub fn received(&self) -> Vec<Envelope<ServerMsg>> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The first message received so far for which `matches` holds, waiting up to `timeout` for
+    /// it to arrive.
+    pub async fn wait_for(
+        &self,
+        timeout: Duration,
+        matches: impl Fn(&Envelope<ServerMsg>) -> bool,
+    ) -> Option<Envelope<ServerMsg>> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // Registered before looking, a message arriving in between still wakes us.
+                let arrived = self.arrived.notified();
+                if let Some(msg) = self.received.lock().unwrap().iter().find(|m| matches(m)) {
+                    return msg.clone();
+                }
+                arrived.await;
+            }
+        })
+        .await
+        .ok()
+    }
+
+    /// Sends `msg` about client or job `id`, for what the mock doesn't do by itself.
+    pub fn send(&self, id: &str, msg: StreamerMsg) {
+        if let Ok(msg) = encode(id, msg) {
+            let _ = self.out_tx.send(msg);
+        }
+    }
+
+    /// Whether the server still has the socket open.
+    pub fn is_connected(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+impl Drop for MockStreamer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn encode(id: &str, msg: StreamerMsg) -> Result<Message, serde_json::Error> {
+    let msg = serde_json::to_string(&Envelope::new(id, msg))?;
+    Ok(Message::Text(Utf8Bytes::from(msg)))
+}
+
+/// What a real streamer would send back once its pipeline did the work.
+fn reply(config: &MockConfig, msg: &Envelope<ServerMsg>, out_tx: &UnboundedSender<Message>) {
+    let replies = match &msg.msg {
+        ServerMsg::Offer { .. } | ServerMsg::IceRestart { .. } if config.answer_offers => vec![
+            StreamerMsg::Answer {
+                sdp: CANNED_ANSWER.to_string(),
+            },
+            StreamerMsg::Ice {
+                candidate: CANNED_CANDIDATE.to_string(),
+                sdp_mline_index: 0,
+            },
+            StreamerMsg::Ice {
+                candidate: String::new(),
+                sdp_mline_index: 0,
+            },
+        ],
+        ServerMsg::Render { output, .. } => vec![
+            StreamerMsg::RenderProgress { progress: 1.0 },
+            StreamerMsg::RenderDone {
+                output: output.clone(),
+            },
+        ],
+        _ => vec![],
+    };
+    for reply in replies {
+        if let Ok(reply) = encode(&msg.id, reply) {
+            let _ = out_tx.send(reply);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Stands in for webrtctest where GStreamer isn't installed. Browsers get their answers but no
+//! audio, renders finish without writing anything.
+
+use std::time::Duration;
+
+use mock_streamer::{MockConfig, MockStreamer};
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let config = match repan_config::init() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let streamer = &config.streamer;
+    let mock = MockStreamer::connect(
+        &streamer.server_url,
+        &streamer.secret,
+        MockConfig {
+            name: streamer.name.clone(),
+            capacity: streamer.capacity,
+            capabilities: streamer.capabilities.clone(),
+            ..MockConfig::default()
+        },
+    )
+    .await?;
+    println!("Connected to {} as {}", streamer.server_url, streamer.name);
+
+    let mut shown = 0;
+    while mock.is_connected() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let received = mock.received();
+        for msg in &received[shown..] {
+            println!("{} {}", msg.id, msg.msg.kind());
+        }
+        shown = received.len();
+    }
+    eprintln!("Server closed the connection");
+    Ok(())
+}
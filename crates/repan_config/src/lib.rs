@@ -0,0 +1,706 @@
+//! Settings shared by the web server, the streamer and jamdb.
+//!
+//! They come from the TOML file named by `REPAN_CONFIG`, or `repan.toml` in the working
+//! directory. Keys left out keep their defaults, so a missing file is a valid configuration.
+//! Every key can be overridden from the environment as `REPAN_<SECTION>_<KEY>`, e.g.
+//! `REPAN_DATABASE_PATH` for `[database] path`. Lists are comma separated there.
+
+use std::{
+    env, fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use repan_protocol::{Capability, IceServer, IceSettings};
+use serde::{Deserialize, Serialize};
+
+/// Names the configuration file, if it is not `repan.toml` in the working directory.
+pub const CONFIG_ENV: &str = "REPAN_CONFIG";
+pub const DEFAULT_CONFIG_FILE: &str = "repan.toml";
+const ENV_PREFIX: &str = "REPAN_";
+
+/// The upload page sends chunks of this size, the server has to take at least that much.
+pub const MIN_CHUNK_SIZE: usize = 1024 * 1024;
+/// Shortest `streamer.secret` accepted, anything shorter is too easy to guess.
+pub const MIN_SECRET_LEN: usize = 16;
+/// Sample rates opusenc accepts without resampling.
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub library: LibraryConfig,
+    pub server: ServerConfig,
+    pub streamer: StreamerConfig,
+    pub ice: IceConfig,
+    pub audio: AudioConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// The sqlite file jamdb creates and the web server opens.
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Directories holding recordings. jamdb scans them when not given a directory, and band
+    /// roots have to lie inside one of them. Empty allows band roots anywhere.
+    pub roots: Vec<PathBuf>,
+    /// Partial uploads are kept here until they are complete.
+    pub uploads: PathBuf,
+    /// Written by the streamer and served by the web server, so both need to see it.
+    pub renders: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// Where the server serves `/metrics`, apart from the site since it has no login. Only
+    /// reachable from the machine itself by default.
+    pub metrics_listen: SocketAddr,
+    /// Marks the session cookie `Secure`, so browsers only send it over TLS or to
+    /// http://localhost. Turn it off only for a plain http site on a network you trust, logging
+    /// in there fails otherwise.
+    pub secure_cookie: bool,
+    /// How long a browser waits for the streamer to answer its offer before giving up.
+    pub answer_timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamerConfig {
+    /// Websocket of the web server the streamer connects to.
+    pub server_url: String,
+    pub rtmp_url: String,
+    /// Where the streamer serves its own `/metrics`.
+    pub metrics_listen: SocketAddr,
+    /// Shown in the server's list of streamers, give every worker its own.
+    pub name: String,
+    /// Listener sessions and renders this streamer takes at once.
+    pub capacity: usize,
+    /// A listener whose heartbeats stopped this long ago has its pipeline torn down.
+    pub idle_timeout_secs: u64,
+    /// Shared by the server and its streamers, streamers sign their token for `/ws` with it.
+    /// Empty lets no streamer connect.
+    pub secret: String,
+    /// What this streamer takes on, listeners, renders or both.
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceConfig {
+    /// Given to browsers as is, the streamer uses the first one.
+    pub stun_servers: Vec<String>,
+    /// Relays for peers whose NAT defeats STUN, like `turn:host:3478` or `turns:host:5349`.
+    pub turn_servers: Vec<String>,
+    /// Every TURN server takes the same credentials.
+    pub turn_username: String,
+    pub turn_credential: String,
+    /// Only host candidates, for a network without internet access. The servers are ignored.
+    pub lan_only: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Bits per second of the live opus stream.
+    pub stream_bitrate: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_upload_size: u64,
+    /// Largest upload chunk accepted in one request.
+    pub max_chunk_size: usize,
+    pub session_days: u32,
+    /// Uploads nothing was added to for this long are deleted.
+    pub upload_expiry_hours: u32,
+    /// Renders nobody fetched or checked on for this long are deleted.
+    pub render_expiry_hours: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: PathBuf::from("jams.db"),
+        }
+    }
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        LibraryConfig {
+            roots: vec![],
+            uploads: PathBuf::from(".uploads"),
+            renders: PathBuf::from("/tmp/repan_renders"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            metrics_listen: SocketAddr::from(([127, 0, 0, 1], 9101)),
+            secure_cookie: true,
+            answer_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for StreamerConfig {
+    fn default() -> Self {
+        StreamerConfig {
+            server_url: "ws://127.0.0.1:3000/ws".to_string(),
+            rtmp_url: "rtmp://rtmp:1935/stream/".to_string(),
+            metrics_listen: SocketAddr::from(([0, 0, 0, 0], 9100)),
+            name: "streamer".to_string(),
+            capacity: 32,
+            idle_timeout_secs: 120,
+            secret: String::new(),
+            capabilities: Capability::ALL.to_vec(),
+        }
+    }
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        IceConfig {
+            stun_servers: vec!["stun:stun.l.google.com:19302".to_string()],
+            turn_servers: vec![],
+            turn_username: String::new(),
+            turn_credential: String::new(),
+            lan_only: false,
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: 48000,
+            channels: 2,
+            stream_bitrate: 64000,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_upload_size: 4 * 1024 * 1024 * 1024,
+            max_chunk_size: 8 * 1024 * 1024,
+            session_days: 30,
+            upload_expiry_hours: 24,
+            render_expiry_hours: 24,
+        }
+    }
+}
+
+impl AudioConfig {
+    /// Raw audio caps every pipeline converts to before mixing.
+    pub fn caps(&self) -> String {
+        format!(
+            "audio/x-raw,rate={},channels={}",
+            self.sample_rate, self.channels
+        )
+    }
+}
+
+impl IceConfig {
+    /// What the server hands to browsers and streamers. STUN URLs are written the way browsers
+    /// expect them, `stun:host:port`.
+    pub fn settings(&self) -> IceSettings {
+        if self.lan_only {
+            return IceSettings {
+                servers: vec![],
+                lan_only: true,
+            };
+        }
+        let mut servers = vec![];
+        let stun_urls: Vec<String> = self
+            .stun_servers
+            .iter()
+            .filter_map(|url| stun_host(url))
+            .map(|host| format!("stun:{}", host))
+            .collect();
+        if !stun_urls.is_empty() {
+            servers.push(IceServer {
+                urls: stun_urls,
+                username: None,
+                credential: None,
+            });
+        }
+        if !self.turn_servers.is_empty() {
+            servers.push(IceServer {
+                urls: self.turn_servers.clone(),
+                username: Some(self.turn_username.clone()),
+                credential: Some(self.turn_credential.clone()),
+            });
+        }
+        IceSettings {
+            servers,
+            lan_only: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn answer_timeout(&self) -> Duration {
+        Duration::from_secs(self.answer_timeout_secs)
+    }
+}
+
+impl StreamerConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl LimitsConfig {
+    pub fn session_lifetime_secs(&self) -> i64 {
+        i64::from(self.session_days) * 24 * 60 * 60
+    }
+    pub fn upload_expiry(&self) -> Duration {
+        Duration::from_secs(u64::from(self.upload_expiry_hours) * 60 * 60)
+    }
+    pub fn render_expiry(&self) -> Duration {
+        Duration::from_secs(u64::from(self.render_expiry_hours) * 60 * 60)
+    }
+}
+
+/// The `host:port` of a STUN URL, browsers write `stun:host`, gstreamer `stun://host`.
+fn stun_host(url: &str) -> Option<&str> {
+    let host = url.strip_prefix("stun:")?;
+    let host = host.strip_prefix("//").unwrap_or(host);
+    (!host.is_empty() && !host.contains('/')).then_some(host)
+}
+
+/// Whether `url` is a TURN URL the way browsers write them, `turn:host:port` or
+/// `turns:host:port`, optionally followed by `?transport=udp` or `?transport=tcp`.
+fn is_turn_url(url: &str) -> bool {
+    let Some(rest) = url
+        .strip_prefix("turn:")
+        .or_else(|| url.strip_prefix("turns:"))
+    else {
+        return false;
+    };
+    let host = match rest.split_once('?') {
+        Some((host, "transport=udp" | "transport=tcp")) => host,
+        Some(_) => return false,
+        None => rest,
+    };
+    !host.is_empty() && !host.contains('/')
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    Env {
+        name: String,
+        message: String,
+    },
+    /// Every problem found, not just the first.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "can't read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, message } => write!(f, "{}: {}", path.display(), message),
+            ConfigError::Env { name, message } => write!(f, "{}: {}", name, message),
+            ConfigError::Invalid(problems) => {
+                write!(f, "{}", problems.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration file, applies the environment and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let config = match env::var_os(CONFIG_ENV) {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        let vars = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        let config = config.with_overrides(vars)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Applies every `REPAN_<SECTION>_<KEY>` in `vars`. A variable with that prefix naming no
+    /// setting is an error, it is most likely a typo.
+    pub fn with_overrides(
+        self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, ConfigError> {
+        let mut table = toml::Table::try_from(&self).expect("Config serializes to a table");
+        for (name, value) in vars {
+            let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            let setting = rest.split_once('_').and_then(|(section, key)| {
+                table
+                    .get_mut(&section.to_lowercase())?
+                    .as_table_mut()?
+                    .get_mut(&key.to_lowercase())
+            });
+            let Some(setting) = setting else {
+                return Err(ConfigError::Env {
+                    name,
+                    message: "doesn't name a setting".to_string(),
+                });
+            };
+            *setting = env_value(setting, &value).map_err(|message| ConfigError::Env {
+                name: name.clone(),
+                message,
+            })?;
+        }
+        table
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Env {
+                name: "environment".to_string(),
+                message: e.message().to_string(),
+            })
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if self.database.path.as_os_str().is_empty() {
+            problems.push("database.path is empty".to_string());
+        }
+        for root in &self.library.roots {
+            if !root.is_absolute() {
+                problems.push(format!(
+                    "library.roots: {} is not an absolute path",
+                    root.display()
+                ));
+            }
+        }
+        for (key, dir) in [
+            ("library.uploads", &self.library.uploads),
+            ("library.renders", &self.library.renders),
+        ] {
+            if dir.as_os_str().is_empty() {
+                problems.push(format!("{} is empty", key));
+            }
+        }
+
+        if self.server.answer_timeout_secs == 0 {
+            problems.push("server.answer_timeout_secs must be above 0".to_string());
+        }
+
+        let server_url = &self.streamer.server_url;
+        if !(server_url.starts_with("ws://") || server_url.starts_with("wss://")) {
+            problems.push(format!(
+                "streamer.server_url: {} is not a ws:// or wss:// URL",
+                server_url
+            ));
+        }
+        let rtmp_url = &self.streamer.rtmp_url;
+        if !(rtmp_url.starts_with("rtmp://") || rtmp_url.starts_with("rtmps://")) {
+            problems.push(format!(
+                "streamer.rtmp_url: {} is not an rtmp:// URL",
+                rtmp_url
+            ));
+        }
+        if self.streamer.name.is_empty() {
+            problems.push("streamer.name is empty".to_string());
+        }
+        if self.streamer.capacity == 0 {
+            problems.push("streamer.capacity must be above 0".to_string());
+        }
+        if self.streamer.idle_timeout_secs == 0 {
+            problems.push("streamer.idle_timeout_secs must be above 0".to_string());
+        }
+        let secret = &self.streamer.secret;
+        if !secret.is_empty() && secret.len() < MIN_SECRET_LEN {
+            problems.push(format!(
+                "streamer.secret must be at least {} characters",
+                MIN_SECRET_LEN
+            ));
+        }
+        if self.streamer.capabilities.is_empty() {
+            problems
+                .push("streamer.capabilities is empty, the streamer would do nothing".to_string());
+        }
+        for url in &self.ice.stun_servers {
+            if stun_host(url).is_none() {
+                problems.push(format!(
+                    "ice.stun_servers: {} is not a STUN URL like stun:stun.l.google.com:19302",
+                    url
+                ));
+            }
+        }
+        for url in &self.ice.turn_servers {
+            if !is_turn_url(url) {
+                problems.push(format!(
+                    "ice.turn_servers: {} is not a TURN URL like turn:turn.example.com:3478",
+                    url
+                ));
+            }
+        }
+        if !self.ice.turn_servers.is_empty()
+            && (self.ice.turn_username.is_empty() || self.ice.turn_credential.is_empty())
+        {
+            problems.push(
+                "ice.turn_servers need ice.turn_username and ice.turn_credential".to_string(),
+            );
+        }
+
+        let audio = &self.audio;
+        if !OPUS_SAMPLE_RATES.contains(&audio.sample_rate) {
+            problems.push(format!(
+                "audio.sample_rate must be one of {:?}, not {}",
+                OPUS_SAMPLE_RATES, audio.sample_rate
+            ));
+        }
+        if !(1..=2).contains(&audio.channels) {
+            problems.push(format!(
+                "audio.channels must be 1 or 2, not {}",
+                audio.channels
+            ));
+        }
+        if !(6000..=510000).contains(&audio.stream_bitrate) {
+            problems.push(format!(
+                "audio.stream_bitrate must be between 6000 and 510000, not {}",
+                audio.stream_bitrate
+            ));
+        }
+
+        let limits = &self.limits;
+        if limits.max_upload_size == 0 {
+            problems.push("limits.max_upload_size must be above 0".to_string());
+        }
+        if limits.max_chunk_size < MIN_CHUNK_SIZE {
+            problems.push(format!(
+                "limits.max_chunk_size must be at least {}, the chunk size of the upload page",
+                MIN_CHUNK_SIZE
+            ));
+        }
+        if limits.max_chunk_size as u64 > limits.max_upload_size {
+            problems.push("limits.max_chunk_size is above limits.max_upload_size".to_string());
+        }
+        if limits.session_days == 0 {
+            problems.push("limits.session_days must be above 0".to_string());
+        }
+        if limits.upload_expiry_hours == 0 {
+            problems.push("limits.upload_expiry_hours must be above 0".to_string());
+        }
+        if limits.render_expiry_hours == 0 {
+            problems.push("limits.render_expiry_hours must be above 0".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+/// Loads the configuration once per process. Programs call this first thing at startup, so a
+/// bad configuration stops them before they do anything.
+pub fn init() -> Result<&'static Config, ConfigError> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+    let config = Config::load()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The configuration loaded by [`init`].
+pub fn get() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("repan_config::init() has to run at startup")
+}
+
+/// Reads `raw` as the type `current` already has, which is what the defaults say it is.
+fn env_value(current: &toml::Value, raw: &str) -> Result<toml::Value, String> {
+    use toml::Value;
+
+    let raw = raw.trim();
+    Ok(match current {
+        Value::String(_) => Value::String(raw.to_string()),
+        Value::Integer(_) => Value::Integer(
+            raw.parse()
+                .map_err(|_| format!("{} is not a whole number", raw))?,
+        ),
+        Value::Float(_) => Value::Float(
+            raw.parse()
+                .map_err(|_| format!("{} is not a number", raw))?,
+        ),
+        Value::Boolean(_) => Value::Boolean(match raw {
+            "true" | "1" | "yes" => true,
+            "false" | "0" | "no" => false,
+            _ => return Err(format!("{} is not true or false", raw)),
+        }),
+        Value::Array(_) => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        ),
+        _ => return Err("can't be set from the environment".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// A file in the temp directory, named after the test so tests running at once keep apart.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path =
+            env::temp_dir().join(format!("repan-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let path = config_file(
+            "precedence",
+            "[database]\npath = \"/srv/file.db\"\n[streamer]\ncapacity = 2\nname = \"file\"\n",
+        );
+        let config = Config::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(config.streamer.capacity, 2);
+        // Keys left out of the file keep their defaults.
+        assert_eq!(config.audio, AudioConfig::default());
+
+        let config = config
+            .with_overrides(vars(&[
+                ("REPAN_STREAMER_CAPACITY", "8"),
+                ("REPAN_SERVER_SECURE_COOKIE", "no"),
+                ("REPAN_LIBRARY_ROOTS", "/srv/jams, /srv/more,"),
+                ("REPAN_CONFIG", "ignored.toml"),
+                ("HOME", "/root"),
+            ]))
+            .unwrap();
+        assert_eq!(config.streamer.capacity, 8);
+        assert_eq!(config.streamer.name, "file");
+        assert_eq!(config.database.path, Path::new("/srv/file.db"));
+        assert!(!config.server.secure_cookie);
+        assert_eq!(
+            config.library.roots,
+            [PathBuf::from("/srv/jams"), PathBuf::from("/srv/more")]
+        );
+    }
+
+    #[test]
+    fn bad_environment_values() {
+        let error = |name: &str, value: &str| match Config::default()
+            .with_overrides(vars(&[(name, value)]))
+        {
+            Err(ConfigError::Env { name, .. }) => name,
+            other => panic!("{}={} gave {:?}", name, value, other),
+        };
+        assert_eq!(
+            error("REPAN_STREAMER_CAPACITY", "many"),
+            "REPAN_STREAMER_CAPACITY"
+        );
+        assert_eq!(
+            error("REPAN_SERVER_SECURE_COOKIE", "maybe"),
+            "REPAN_SERVER_SECURE_COOKIE"
+        );
+        assert_eq!(
+            error("REPAN_STREAMER_CAPACTY", "8"),
+            "REPAN_STREAMER_CAPACTY"
+        );
+        assert_eq!(error("REPAN_NOSECTION", "8"), "REPAN_NOSECTION");
+        // Parses as an integer but not as a usize.
+        assert_eq!(error("REPAN_STREAMER_CAPACITY", "-1"), "environment");
+    }
+
+    #[test]
+    fn every_invalid_value_is_reported() {
+        let mut config = Config::default();
+        config.streamer.capacity = 0;
+        config.streamer.secret = "short".to_string();
+        config.audio.sample_rate = 44100;
+        config.library.roots = vec![PathBuf::from("relative/jams")];
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("an invalid configuration passed");
+        };
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.starts_with("streamer.capacity")));
+        assert!(problems.iter().any(|p| p.starts_with("streamer.secret")));
+        assert!(problems.iter().any(|p| p.starts_with("audio.sample_rate")));
+        assert!(problems.iter().any(|p| p.starts_with("library.roots")));
+    }
+
+    #[test]
+    fn file_errors() {
+        let missing = env::temp_dir().join("repan-config-missing.toml");
+        assert!(matches!(
+            Config::from_file(&missing),
+            Err(ConfigError::Read { .. })
+        ));
+
+        let path = config_file("unknown-key", "[streamer]\ncapacty = 2\n");
+        let parsed = Config::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(parsed, Err(ConfigError::Parse { .. })));
+    }
+}
@@ -0,0 +1,152 @@
+//! The token a streamer connects to `/ws` with.
+//!
+//! The server and its streamers share a secret. A streamer signs its worker name and the time
+//! with it and sends the result as `Authorization: Bearer <token>` on the websocket upgrade, so
+//! the secret itself never crosses the network. Tokens are only good for a few minutes, a
+//! recorded one can't be used to connect later.
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// How far the time in a token may be from the server's clock, either way.
+pub const TOKEN_MAX_AGE_SECS: i64 = 5 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    /// Too old, or the clocks of the streamer and the server are too far apart.
+    Expired,
+}
+
+impl TokenError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::Malformed => "malformed",
+            TokenError::BadSignature => "bad_signature",
+            TokenError::Expired => "expired",
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::BadSignature => write!(f, "token not signed with the shared secret"),
+            TokenError::Expired => write!(
+                f,
+                "token more than {} seconds off the server's clock",
+                TOKEN_MAX_AGE_SECS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+fn signer(secret: &str, worker: &str, issued_at: i64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", worker, issued_at).as_bytes());
+    mac
+}
+
+/// A token for `worker`, issued at `now` in unix seconds.
+pub fn worker_token(secret: &str, worker: &str, now: i64) -> String {
+    let signature = signer(secret, worker, now).finalize().into_bytes();
+    format!("{}.{}.{}", worker, now, hex(&signature))
+}
+
+/// The worker `token` was signed for, if it was signed with `secret` and is still fresh.
+pub fn verify_worker_token(secret: &str, token: &str, now: i64) -> Result<String, TokenError> {
+    // Worker names may contain dots, the time and the signature can't.
+    let mut parts = token.rsplitn(3, '.');
+    let (Some(signature), Some(issued_at), Some(worker)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(TokenError::Malformed);
+    };
+    let issued_at: i64 = issued_at.parse().map_err(|_| TokenError::Malformed)?;
+    let signature = unhex(signature).ok_or(TokenError::Malformed)?;
+    signer(secret, worker, issued_at)
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::BadSignature)?;
+    if (now - issued_at).abs() > TOKEN_MAX_AGE_SECS {
+        return Err(TokenError::Expired);
+    }
+    Ok(worker.to_string())
+}
+
+/// Lowercase hex, how signatures travel in tokens.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The bytes back from [`hex`], `None` for anything that isn't hex.
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+    const NOW: i64 = 1_800_000_000;
+
+    #[test]
+    fn signed_token_names_its_worker() {
+        let token = worker_token(SECRET, "worker.eu-1", NOW);
+        assert_eq!(
+            verify_worker_token(SECRET, &token, NOW + 10),
+            Ok("worker.eu-1".to_string())
+        );
+    }
+
+    #[test]
+    fn other_secret_is_refused() {
+        let token = worker_token("guessed", "streamer", NOW);
+        assert_eq!(
+            verify_worker_token(SECRET, &token, NOW),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn changed_worker_is_refused() {
+        let token = worker_token(SECRET, "streamer", NOW);
+        let forged = token.replacen("streamer", "other", 1);
+        assert_eq!(
+            verify_worker_token(SECRET, &forged, NOW),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn old_token_is_refused() {
+        let token = worker_token(SECRET, "streamer", NOW);
+        assert_eq!(
+            verify_worker_token(SECRET, &token, NOW + TOKEN_MAX_AGE_SECS + 1),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        for token in ["", "streamer", "streamer.now.00", "streamer.1.xyz"] {
+            assert_eq!(
+                verify_worker_token(SECRET, token, NOW),
+                Err(TokenError::Malformed)
+            );
+        }
+    }
+}
@@ -1,28 +1,99 @@
 #![allow(dead_code)]
 #![allow(unused)]
 use anyhow::anyhow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use futures_util::{SinkExt, StreamExt, stream::SplitStream};
 use rand::Rng;
+use repan_protocol::auth::worker_token;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, accept_async};
 use tungstenite::Message;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{HeaderValue, StatusCode, header::AUTHORIZATION};
 
-use crate::pipeline_handler::Connection;
+use crate::pipeline_handler::{Connection, Incompatible};
 
 //use crate::{webrtc_conn::UserConn};
 
+mod metrics;
 mod user_connection;
 //mod webrtc_conn;
 mod pipeline_handler;
+mod render;
+
+/// Wait before the first reconnect, doubled after every failed attempt up to `RECONNECT_MAX`.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let config = match repan_config::init() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let metrics_handle = metrics::install()?;
+    let metrics_listen = config.streamer.metrics_listen;
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_handle, metrics_listen).await {
+            eprintln!("Metrics endpoint failed: {:?}", e);
+        }
+    });
+    if config.streamer.secret.is_empty() {
+        eprintln!("streamer.secret is not set, the server won't take this streamer");
+        std::process::exit(1);
+    }
     gstreamer::init().unwrap();
 
-    let (mut ws, response) = tokio_tungstenite::connect_async("ws://127.0.0.1:3000/ws").await?;
-    let mut conn = Connection::new(ws).await;
-    conn.streamer_to_website_handler().await?;
-
-    Ok(())
+    // The server restarting or the network going away only costs the current listeners, they
+    // renegotiate on their own once we are back.
+    let mut backoff = RECONNECT_MIN;
+    loop {
+        // Signed anew for every attempt, the server only takes fresh tokens.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let token = worker_token(&config.streamer.secret, &config.streamer.name, now);
+        let mut request = config.streamer.server_url.as_str().into_client_request()?;
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((ws, _)) => {
+                backoff = RECONNECT_MIN;
+                let mut conn = Connection::new(ws).await;
+                match conn.streamer_to_website_handler().await {
+                    Ok(()) => eprintln!("Server closed the connection"),
+                    // Deploy a streamer built from the same protocol version as the server.
+                    Err(e) if e.is::<Incompatible>() => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                    Err(e) => eprintln!("Lost the connection to the server: {:?}", e),
+                }
+            }
+            // Keep trying, the server may just not have the new secret yet.
+            Err(tungstenite::Error::Http(response))
+                if response.status() == StatusCode::UNAUTHORIZED =>
+            {
+                eprintln!(
+                    "{} refused our token, check streamer.secret and that both clocks are right",
+                    config.streamer.server_url
+                )
+            }
+            Err(e) => eprintln!(
+                "Couldn't connect to {}: {:?}",
+                config.streamer.server_url, e
+            ),
+        }
+        // Jitter, so a fleet of streamers doesn't hit a restarted server all at once.
+        let wait = backoff + backoff.mul_f64(rand::rng().random_range(0.0..0.25));
+        eprintln!("Reconnecting in {:.1}s", wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX);
+    }
 }
 
 //#[tokio::main]
@@ -0,0 +1,126 @@
+//! Prometheus metrics of the streamer, served from its own `/metrics` so every streamer box can
+//! be scraped separately. Together they show how many listeners one box handles.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{Router, http::header, routing::get};
+use gstreamer as gst;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use tokio::sync::Mutex;
+
+use crate::user_connection::UserConn;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+const PIPELINE_STATES: [gst::State; 5] = [
+    gst::State::VoidPending,
+    gst::State::Null,
+    gst::State::Ready,
+    gst::State::Paused,
+    gst::State::Playing,
+];
+
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new().install_recorder()?;
+
+    describe_gauge!(
+        "repan_streamer_connections",
+        "Listeners with a WebRTC pipeline on this streamer"
+    );
+    describe_gauge!("repan_streamer_pipelines", "Listener pipelines by state");
+    describe_gauge!(
+        "repan_streamer_mixed_tracks",
+        "Tracks mixed over all listener pipelines"
+    );
+    describe_counter!(
+        "repan_streamer_datachannel_messages_total",
+        "Data channel messages, by direction"
+    );
+    describe_counter!(
+        "repan_streamer_server_messages_total",
+        "Messages exchanged with the web server, by direction and kind"
+    );
+    describe_gauge!("repan_streamer_renders_running", "Renders in progress");
+    describe_counter!(
+        "repan_streamer_renders_total",
+        "Finished renders, by result"
+    );
+    describe_histogram!(
+        "repan_streamer_render_seconds",
+        "Wall clock time of finished renders"
+    );
+    Ok(handle)
+}
+
+pub async fn serve(handle: PrometheusHandle, addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let app = Router::new().route(
+        "/metrics",
+        get(move || {
+            let handle = handle.clone();
+            async move {
+                (
+                    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    handle.render(),
+                )
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Metrics on http://{}/metrics", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Pipeline states change on GStreamer's threads, so they are sampled rather than tracked.
+pub async fn sample_pipelines(clients: Arc<Mutex<HashMap<String, UserConn>>>) {
+    let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let clients = clients.lock().await;
+        gauge!("repan_streamer_connections").set(clients.len() as f64);
+        for state in PIPELINE_STATES {
+            let count = clients
+                .values()
+                .filter(|conn| conn.state() == state)
+                .count();
+            gauge!("repan_streamer_pipelines", "state" => state_name(state)).set(count as f64);
+        }
+        let tracks: usize = clients.values().map(UserConn::mixed_tracks).sum();
+        gauge!("repan_streamer_mixed_tracks").set(tracks as f64);
+    }
+}
+
+fn state_name(state: gst::State) -> &'static str {
+    match state {
+        gst::State::VoidPending => "void_pending",
+        gst::State::Null => "null",
+        gst::State::Ready => "ready",
+        gst::State::Paused => "paused",
+        gst::State::Playing => "playing",
+        _ => "unknown",
+    }
+}
+
+pub fn connections(count: usize) {
+    gauge!("repan_streamer_connections").set(count as f64);
+}
+
+pub fn datachannel_message(direction: &'static str) {
+    counter!("repan_streamer_datachannel_messages_total", "direction" => direction).increment(1);
+}
+
+pub fn server_message(direction: &'static str, kind: &'static str) {
+    counter!("repan_streamer_server_messages_total", "direction" => direction, "kind" => kind)
+        .increment(1);
+}
+
+pub fn render_started() {
+    gauge!("repan_streamer_renders_running").increment(1.0);
+}
+
+pub fn render_finished(ok: bool, took: Duration) {
+    gauge!("repan_streamer_renders_running").decrement(1.0);
+    let result = if ok { "done" } else { "failed" };
+    counter!("repan_streamer_renders_total", "result" => result).increment(1);
+    histogram!("repan_streamer_render_seconds").record(took.as_secs_f64());
+}
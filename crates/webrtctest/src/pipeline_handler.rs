@@ -5,41 +5,46 @@ use futures_util::{
     SinkExt, StreamExt,
     stream::{Fuse, SplitSink, SplitStream},
 };
-use serde::{Deserialize, Serialize};
+use repan_protocol::{
+    Envelope, IceSettings, PROTOCOL_VERSION, ServerMsg, SessionState, StreamerMsg,
+};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{
+        Mutex,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+    task::JoinHandle,
+    time::Instant,
 };
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, accept_async};
 use tungstenite::{Message, Utf8Bytes};
 
+use crate::metrics;
+use crate::render;
 use crate::user_connection::UserConn;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ClientMessage {
-    gst_msg: GstJsonMsg,
-    id: String,
+/// The server speaks another protocol version, reconnecting won't help.
+#[derive(Debug)]
+pub struct Incompatible(pub String);
+
+impl std::fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Turned away by the server: {}", self.0)
+    }
 }
 
-// JSON messages we communicate with
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum GstJsonMsg {
-    Ice {
-        candidate: String,
-        #[serde(rename = "sdpMLineIndex")]
-        sdp_mline_index: u32,
-    },
-    Sdp {
-        sdp: String,
-        #[serde(rename = "type")]
-        r#type: String,
-    },
-    ChangeJam {
-        path: String,
-        date: String,
-        tracks: Vec<String>,
-    },
+impl std::error::Error for Incompatible {}
+
+/// Queues a message for client or job `id` on the handler loop's channel to the server.
+pub fn send_to_server(out_tx: &UnboundedSender<Message>, id: &str, msg: StreamerMsg) {
+    metrics::server_message("out", msg.kind());
+    match serde_json::to_string(&Envelope::new(id, msg)) {
+        Ok(msg) => {
+            let _ = out_tx.send(Message::Text(Utf8Bytes::from(msg)));
+        }
+        Err(e) => eprintln!("Couldn't serialize message for {}: {:?}", id, e),
+    }
 }
 
 // JSON messages we communicate with
@@ -57,6 +62,15 @@ pub enum GstJsonMsg {
 //        sdp: String,
 //    },
 //}
+/// How often listener sessions are checked for heartbeats and failed connections.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often listeners get the playhead's position.
+const POSITION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The server pings every 10 seconds, without anything for this long the connection is dead.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub type RepanStream = Fuse<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>;
 pub type RepanSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 
@@ -67,90 +81,184 @@ pub struct Connection {
     stream: RepanStream,
     sink: RepanSink,
     clients: Arc<Mutex<HashMap<String, UserConn>>>,
+    // Messages from spawned tasks, forwarded to the server by the handler loop
+    out_tx: UnboundedSender<Message>,
+    out_rx: UnboundedReceiver<Message>,
+    sampler: JoinHandle<()>,
+    watcher: JoinHandle<()>,
+    reporter: JoinHandle<()>,
+    /// Sent with the server's welcome, before any offer.
+    ice: IceSettings,
+}
+
+/// The pipelines of this connection's listeners stop with it, the server moves them elsewhere.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.sampler.abort();
+        self.watcher.abort();
+        self.reporter.abort();
+        metrics::connections(0);
+    }
 }
 
 impl Connection {
     pub async fn new(mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
-        ws.send(Message::text("gstreamer connecting...")).await;
+        let config = &repan_config::get().streamer;
+        let hello = Envelope::new(
+            config.name.as_str(),
+            StreamerMsg::Hello {
+                protocol: PROTOCOL_VERSION,
+                name: config.name.clone(),
+                capacity: config.capacity,
+                capabilities: config.capabilities.clone(),
+            },
+        );
+        let hello = serde_json::to_string(&hello).unwrap();
+        ws.send(Message::Text(Utf8Bytes::from(hello))).await;
+        metrics::server_message("out", "hello");
         let (mut sink, mut stream) = ws.split();
         let mut stream = stream.fuse();
         let mut sink = sink;
 
         println!("Connection to server established");
+        let (out_tx, out_rx) = mpsc::unbounded_channel::<Message>();
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let sampler = tokio::spawn(metrics::sample_pipelines(clients.clone()));
+        let watcher = tokio::spawn(watch_sessions(clients.clone(), out_tx.clone()));
+        let reporter = tokio::spawn(report_positions(clients.clone()));
         Connection {
             sink,
             stream,
-            clients: Arc::new(Mutex::new(HashMap::new())),
+            clients,
+            out_tx,
+            out_rx,
+            sampler,
+            watcher,
+            reporter,
+            ice: IceSettings::default(),
         }
     }
+    /// Runs until the server closes the connection or stops answering.
     pub async fn streamer_to_website_handler(&mut self) -> Result<(), anyhow::Error> {
+        let mut last_seen = Instant::now();
         loop {
             tokio::select! {
-                msg = self.stream.select_next_some() =>
+                msg = self.stream.next() =>
                 {
                     println!("Message received");
-                    if let Ok(msg) = msg
+                    last_seen = Instant::now();
+                    match msg
                     {
-                        match msg
-                        {
-                            Message::Text(msg) => self.parse_websocket_msg(msg.to_string()).await? ,
-                            Message::Binary(bytes) => todo!(),
-                            Message::Ping(bytes) => todo!(),
-                            Message::Pong(bytes) => todo!(),
-                            Message::Close(close_frame) => todo!(),
-                            Message::Frame(frame) => todo!(),
+                        Some(Ok(Message::Text(msg))) => {
+                            if let Err(e) = self.parse_websocket_msg(msg.to_string()).await {
+                                if e.is::<Incompatible>() {
+                                    return Err(e);
+                                }
+                                eprintln!("Couldn't handle message from the server: {:?}", e);
+                            }
                         }
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        // The server pings to check on us, tungstenite answers by itself.
+                        Some(Ok(_)) => (),
+                        Some(Err(e)) => return Err(e.into()),
                     }
-                    else
-                    {
-                        eprintln!("Problem");
-                    }
+                }
+                Some(msg) = self.out_rx.recv() =>
+                {
+                    self.sink.send(msg).await?;
+                }
+                _ = tokio::time::sleep_until(last_seen + SERVER_TIMEOUT) =>
+                {
+                    return Err(anyhow!("No message from the server for {:?}", SERVER_TIMEOUT));
                 }
             };
         }
     }
     async fn parse_websocket_msg(&mut self, msg: String) -> Result<(), anyhow::Error> {
-        //println!("{}", msg.as_str());
-        let client_msg: ClientMessage = serde_json::from_str(msg.as_str())?;
-        //println!("Was able to parse json");
-
-        //let ClientMessage::Payload { gst_msg, id } = client_msg;
-        match client_msg.gst_msg {
-            GstJsonMsg::Sdp { r#type, sdp } => {
-                let mut user_conn = UserConn::new()?;
-                let set_answer = user_conn.set_remote_description(sdp.as_str()).await;
-                let sdp_answer = user_conn.create_sdp_answer().await.unwrap();
-                let set_desc = user_conn
-                    .set_local_description(sdp_answer.clone())
-                    .await
-                    .unwrap();
-                //println!("{:?}", set_desc);
-                let answer = ClientMessage {
-                    gst_msg: GstJsonMsg::Sdp {
-                        sdp: sdp_answer,
-                        r#type: "answer".to_string(),
-                    },
-                    id: client_msg.id.clone(),
-                };
-                let answer = serde_json::to_string(&answer).unwrap();
-                self.sink.send(Message::Text(Utf8Bytes::from(answer))).await;
-
-                println!("Locked before user has been created");
-                let clients = self.clients.clone();
-                let mut guard = clients.lock().await;
-                guard.insert(client_msg.id.clone(), user_conn);
-
-                //std::thread::sleep(Duration::new(3, 0))
-
-                //println!("{:?}", sdp_answer);
-                //println!("{:?}", sdp_answer);
-
-                //let _ = user_conn.deliver_sdp(&sdp.as_str()).unwrap();
-                //let _ = self.sink.send(Message::Text(Utf8Bytes::from(sdp_answer))).await?;
+        let client_msg: Envelope<ServerMsg> = serde_json::from_str(msg.as_str())?;
+        metrics::server_message("in", client_msg.msg.kind());
 
+        match client_msg.msg {
+            ServerMsg::Welcome { protocol, ice } => {
+                println!("Server accepted us, protocol version {}", protocol);
+                if ice.lan_only {
+                    println!("LAN only, exchanging host candidates only");
+                }
+                self.ice = ice;
                 Ok(())
             }
-            GstJsonMsg::Ice {
+            ServerMsg::Rejected { reason } => Err(Incompatible(reason).into()),
+            ServerMsg::Offer { sdp } => {
+                // A re-offer renegotiates the session's existing webrtcbin.
+                let existing = self.clients.lock().await.get(&client_msg.id).cloned();
+                let user_conn = match existing {
+                    Some(conn) => conn,
+                    None => UserConn::new(client_msg.id.clone(), &self.ice, self.out_tx.clone())?,
+                };
+                match user_conn.answer_offer(&sdp).await {
+                    Ok(answer) => {
+                        send_to_server(
+                            &self.out_tx,
+                            &client_msg.id,
+                            StreamerMsg::Answer { sdp: answer },
+                        );
+                        let mut guard = self.clients.lock().await;
+                        guard.entry(client_msg.id.clone()).or_insert(user_conn);
+                        metrics::connections(guard.len());
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't answer the offer for {}: {:?}", client_msg.id, e);
+                        send_to_server(
+                            &self.out_tx,
+                            &client_msg.id,
+                            StreamerMsg::OfferFailed {
+                                reason: e.to_string(),
+                            },
+                        );
+                    }
+                }
+                Ok(())
+            }
+            ServerMsg::IceRestart { sdp } => {
+                let clients = self.clients.lock().await;
+                let Some(conn) = clients.get(&client_msg.id) else {
+                    // Gone already, the server renegotiates from scratch.
+                    send_to_server(
+                        &self.out_tx,
+                        &client_msg.id,
+                        StreamerMsg::Session {
+                            state: SessionState::Closed,
+                        },
+                    );
+                    return Ok(());
+                };
+                match conn.restart_ice(&sdp).await {
+                    Ok(answer) => {
+                        println!("Restarted ICE for {}", client_msg.id);
+                        send_to_server(
+                            &self.out_tx,
+                            &client_msg.id,
+                            StreamerMsg::Answer { sdp: answer },
+                        );
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't restart ICE for {}: {:?}", client_msg.id, e);
+                        send_to_server(
+                            &self.out_tx,
+                            &client_msg.id,
+                            StreamerMsg::OfferFailed {
+                                reason: e.to_string(),
+                            },
+                        );
+                    }
+                }
+                Ok(())
+            }
+            ServerMsg::Ice { candidate, .. } if !self.ice.allows(&candidate) => {
+                println!("Ignored candidate {}", candidate);
+                Ok(())
+            }
+            ServerMsg::Ice {
                 candidate,
                 sdp_mline_index,
             } => {
@@ -163,10 +271,83 @@ impl Connection {
 
                 Ok(())
             }
-            _ => Ok(()),
+            ServerMsg::ChangeJam { path, date, tracks } => {
+                let clients = self.clients.clone();
+                let guard = clients.lock().await;
+                match guard.get(&client_msg.id) {
+                    Some(conn) => {
+                        if let Err(e) = conn.change_jam(&path, &date, &tracks) {
+                            eprintln!("Couldn't change jam for {}: {:?}", client_msg.id, e);
+                        }
+                    }
+                    None => eprintln!("Jam change for unknown client {}", client_msg.id),
+                }
+                Ok(())
+            }
+            ServerMsg::Hangup { reason } => {
+                let mut clients = self.clients.lock().await;
+                if clients.remove(&client_msg.id).is_some() {
+                    println!("Hung up on {}: {}", client_msg.id, reason);
+                }
+                metrics::connections(clients.len());
+                Ok(())
+            }
+            ServerMsg::Render {
+                output,
+                format,
+                stems,
+            } => {
+                tokio::spawn(render::render(
+                    client_msg.id,
+                    output,
+                    format,
+                    stems,
+                    self.out_tx.clone(),
+                ));
+                Ok(())
+            }
         }
     }
     async fn handle_user_messages(&self, user_conn: UserConn) {}
 }
 
+/// Tells the server when a listener session changes state, and tears down the pipelines of
+/// closed ones.
+async fn watch_sessions(
+    clients: Arc<Mutex<HashMap<String, UserConn>>>,
+    out_tx: UnboundedSender<Message>,
+) {
+    let mut ticker = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut clients = clients.lock().await;
+        let mut closed = vec![];
+        for (id, conn) in clients.iter() {
+            let state = conn.session_state();
+            if conn.report_state(state) {
+                send_to_server(&out_tx, id, StreamerMsg::Session { state });
+            }
+            if state == SessionState::Closed {
+                closed.push(id.clone());
+            }
+        }
+        for id in closed {
+            println!("Closing session {}", id);
+            clients.remove(&id);
+        }
+        metrics::connections(clients.len());
+    }
+}
+
+/// Sends every listener the position of its jam, for a live playhead.
+async fn report_positions(clients: Arc<Mutex<HashMap<String, UserConn>>>) {
+    let mut ticker = tokio::time::interval(POSITION_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for conn in clients.lock().await.values() {
+            conn.report_position();
+        }
+    }
+}
+
 async fn test(user_conn: UserConn, sink: Arc<RepanSink>) {}
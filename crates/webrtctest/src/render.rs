@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use futures_util::StreamExt;
+use gst::prelude::*;
+use gstreamer::{self as gst};
+use repan_protocol::{RenderFormat, RenderStem, StreamerMsg};
+use tokio::sync::mpsc::UnboundedSender;
+use tungstenite::Message;
+
+use crate::metrics;
+use crate::pipeline_handler::send_to_server;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Mixes the stems into `output` and reports back to the server under the job `id`.
+///
+/// Uses the same audiomixer/audiopanorama/volume graph as `UserConn`, but with nothing live in
+/// it, so the pipeline runs as fast as the disk and encoder allow.
+pub async fn render(
+    id: String,
+    output: String,
+    format: RenderFormat,
+    stems: Vec<RenderStem>,
+    out_tx: UnboundedSender<Message>,
+) {
+    println!("Rendering {} to {}", id, output);
+    metrics::render_started();
+    let started = Instant::now();
+    let result = run_render(&id, &output, format, &stems, &out_tx).await;
+    metrics::render_finished(result.is_ok(), started.elapsed());
+
+    let msg = match result {
+        Ok(()) => StreamerMsg::RenderDone { output },
+        Err(e) => {
+            eprintln!("Render {} failed: {:?}", id, e);
+            StreamerMsg::RenderFailed {
+                error: e.to_string(),
+            }
+        }
+    };
+    send_to_server(&out_tx, &id, msg);
+}
+
+async fn run_render(
+    id: &str,
+    output: &str,
+    format: RenderFormat,
+    stems: &[RenderStem],
+    out_tx: &UnboundedSender<Message>,
+) -> Result<(), anyhow::Error> {
+    let encoder = match format {
+        RenderFormat::Wav => "wavenc",
+        RenderFormat::Flac => "flacenc",
+        RenderFormat::Mp3 => "lamemp3enc target=bitrate bitrate=320 cbr=true ! id3v2mux",
+        RenderFormat::Opus => "opusenc bitrate=192000 ! oggmux",
+    };
+    if stems.is_empty() {
+        bail!("Nothing to render");
+    }
+
+    let mut desc = format!(
+        "audiomixer name=mix ! audioconvert ! audioresample ! {} ! filesink location={}",
+        encoder,
+        quote(output)
+    );
+    let caps = repan_config::get().audio.caps();
+    for stem in stems {
+        desc.push_str(&format!(
+            " filesrc location={} ! wavparse ! audioconvert ! audioresample ! {} ! audiopanorama panorama={} ! volume volume={} mute={} ! mix.",
+            quote(&stem.location),
+            caps,
+            stem.pan.clamp(-1.0, 1.0),
+            stem.volume.clamp(0.0, 10.0),
+            stem.mute,
+        ));
+    }
+
+    let pipeline = gst::parse::launch(&desc)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Render description was not a pipeline"))?;
+    let mut bus = pipeline
+        .bus()
+        .ok_or_else(|| anyhow!("Pipeline without bus"))?
+        .stream();
+
+    pipeline.set_state(gst::State::Playing)?;
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+
+    let result = loop {
+        tokio::select! {
+            msg = bus.next() => {
+                let Some(msg) = msg else {
+                    break Err(anyhow!("Bus closed before the render finished"));
+                };
+                match msg.view() {
+                    gst::MessageView::Eos(_) => break Ok(()),
+                    gst::MessageView::Error(err) => {
+                        break Err(anyhow!("{} ({:?})", err.error(), err.debug()));
+                    }
+                    _ => (),
+                }
+            }
+            _ = ticker.tick() => {
+                let position = pipeline.query_position::<gst::ClockTime>();
+                let duration = pipeline.query_duration::<gst::ClockTime>();
+                if let (Some(position), Some(duration)) = (position, duration) {
+                    if duration.nseconds() > 0 {
+                        let progress = position.nseconds() as f64 / duration.nseconds() as f64;
+                        send_to_server(out_tx, id, StreamerMsg::RenderProgress { progress });
+                    }
+                }
+            }
+        }
+    };
+
+    // Null finalizes the file, only report done after that.
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
+
+/// Quotes a path for gst-launch syntax, library paths tend to contain spaces.
+pub fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -1,6 +1,7 @@
 use std::{
     cell::RefCell,
     sync::{Arc, RwLock, Weak},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, anyhow, bail};
@@ -13,19 +14,27 @@ use gstreamer::{
     glib::{self, GString},
 };
 use gstreamer_webrtc::{
-    WebRTCDataChannel, WebRTCSDPType, WebRTCSessionDescription, ffi::GstWebRTCDataChannel,
-    gst::Message, gst_sdp,
+    WebRTCDataChannel, WebRTCICEGatheringState, WebRTCPeerConnectionState, WebRTCSDPType,
+    WebRTCSessionDescription, ffi::GstWebRTCDataChannel, gst::Message, gst_sdp,
+};
+use repan_protocol::{
+    DataChannelMsg, IceSettings, LoopRegion, SessionState, StreamerMsg, TrackChange, TrackState,
+    Transpose, check_playback_rate,
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::{
-    Mutex, futures,
-    mpsc::{self, UnboundedReceiver},
+    futures,
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch,
 };
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::pipeline_handler::{GstJsonMsg, RepanSink};
+use crate::metrics;
+use crate::pipeline_handler::{RepanSink, send_to_server};
+use crate::render::quote;
 
-const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
+/// Browsers send a heartbeat every 5 seconds, a few missed ones make the session idle.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
 
 // upgrade weak reference or return
 #[macro_export]
@@ -73,11 +82,30 @@ pub struct UserConnectionInner {
     pipeline: gst::Pipeline,
     webrtcbin: gst::Element,
     send_msg_tx: mpsc::UnboundedSender<tungstenite::Message>,
-    pipeline_stream: Arc<Mutex<BusStream>>,
+    /// Whether the pipeline has played yet, webrtcbin only takes an offer once it has.
+    started: watch::Receiver<bool>,
     dc: std::sync::Mutex<Option<WebRTCDataChannel>>,
+    created: Instant,
+    /// Last message over the data channel, none before the browser connected.
+    last_heartbeat: std::sync::Mutex<Option<Instant>>,
+    /// The state the server was last told about.
+    reported: std::sync::Mutex<SessionState>,
+    /// Every track of the jam being played, in the order of its `pan`/`vol` elements.
+    mix: std::sync::Mutex<Vec<TrackState>>,
+    /// One bin per stem of the jam being played, linked to a request pad of the mixer.
+    stems: std::sync::Mutex<Vec<gst::Bin>>,
+    /// The passage played over and over, none when playback goes on to the end.
+    looping: std::sync::Mutex<Option<LoopRegion>>,
+    /// Speed of playback, seeks on the mixer carry it and `scaletempo` keeps the pitch.
+    rate: std::sync::Mutex<f64>,
+    /// The only reader of the pipeline's bus, see `watch_bus`.
+    bus_watch: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
 }
 impl Drop for UserConnectionInner {
     fn drop(&mut self) {
+        if let Some(watch) = self.bus_watch.lock().unwrap().take() {
+            watch.abort();
+        }
         let _ = self.pipeline.set_state(gst::State::Null);
     }
 }
@@ -98,21 +126,38 @@ impl UserConn {
         WeakConn(Arc::downgrade(&self.0))
     }
 
-    pub fn new() -> Result<Self, anyhow::Error> {
-        let pipeline = gst::parse::launch(
-            "audiomixer name=mix ! audioconvert ! audioresample ! audio/x-raw,rate=48000,channels=2 ! opusenc ! rtpopuspay pt=111 ! webrtcbin. webrtcbin name=webrtcbin",
-        )
+    /// A pipeline streaming to the browser of client `id`, gathering candidates the way `ice`
+    /// says. Messages for the browser go out on `out_tx`.
+    pub fn new(
+        id: String,
+        ice: &IceSettings,
+        out_tx: UnboundedSender<tungstenite::Message>,
+    ) -> Result<Self, anyhow::Error> {
+        let config = repan_config::get();
+        let pipeline = gst::parse::launch(&format!(
+            "audiomixer name=mix ! scaletempo ! audioconvert ! pitch name=pitch ! audioconvert ! audioresample ! {} ! opusenc bitrate={} ! rtpopuspay pt=111 ! webrtcbin. webrtcbin name=webrtcbin",
+            config.audio.caps(),
+            config.audio.stream_bitrate,
+        ))
         .unwrap()
         .downcast::<gst::Pipeline>()
         .unwrap();
 
         let webrtcbin = pipeline.by_name("webrtcbin").unwrap();
 
-        webrtcbin.set_property_from_str("stun-server", STUN_SERVER);
+        if let Some(stun_server) = ice.gst_stun_server() {
+            webrtcbin.set_property_from_str("stun-server", &stun_server);
+        }
+        for turn_server in ice.gst_turn_servers() {
+            if !webrtcbin.emit_by_name::<bool>("add-turn-server", &[&turn_server]) {
+                eprintln!("webrtcbin refused a TURN server");
+            }
+        }
         webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
 
         let bus = pipeline.bus().unwrap();
-        let mut pipeline_stream = bus.stream();
+        let pipeline_stream = bus.stream();
+        let (started_tx, started) = watch::channel(false);
 
         let (send_msg_tx, send_msg_rx) = mpsc::unbounded_channel::<tungstenite::Message>();
 
@@ -126,9 +171,19 @@ impl UserConn {
             pipeline,
             webrtcbin,
             send_msg_tx,
-            pipeline_stream: Arc::new(Mutex::new(pipeline_stream)),
+            started,
             dc: std::sync::Mutex::new(None),
+            created: Instant::now(),
+            last_heartbeat: std::sync::Mutex::new(None),
+            reported: std::sync::Mutex::new(SessionState::Negotiating),
+            mix: std::sync::Mutex::new(vec![]),
+            stems: std::sync::Mutex::new(vec![]),
+            looping: std::sync::Mutex::new(None),
+            rate: std::sync::Mutex::new(1.0),
+            bus_watch: std::sync::Mutex::new(None),
         }));
+        let bus_watch = tokio::spawn(watch_bus(conn.downgrade(), pipeline_stream, started_tx));
+        *conn.bus_watch.lock().unwrap() = Some(bus_watch);
         println!("User connection created");
         let (tx, rx) = mpsc::channel::<String>(128);
 
@@ -199,23 +254,42 @@ impl UserConn {
                 None
             });
 
-        conn.webrtcbin.connect("on-ice-candidate", false, |values| {
-            for v in values {
-                let t = v.type_();
-                println!("Type of value in on-ice-candidate: {:?}", t);
-            }
-            let num = values.len();
-            println!("Number of messages on-ice-candidate: {:?}", num);
-
-            //let obj = values[1].get::<glib::Object>().unwrap();
-            //let tr = obj
-            //    .downcast::<gstreamer_webrtc::WebRTCRTPTransceiver>()
-            //    .unwrap();
-            //println!("Got transceiver");
-            //let sender = tr.sender().unwrap();
-            //println!("Unwrapped sender");
-            None
-        });
+        // Trickled to the browser through the server, the answer goes out before gathering ends.
+        let ice_tx = out_tx.clone();
+        let ice_id = id.clone();
+        let ice = ice.clone();
+        conn.webrtcbin
+            .connect("on-ice-candidate", false, move |values| {
+                let sdp_mline_index = values[1].get::<u32>().unwrap();
+                let candidate = values[2].get::<String>().unwrap();
+                if !ice.allows(&candidate) {
+                    return None;
+                }
+                send_to_server(
+                    &ice_tx,
+                    &ice_id,
+                    StreamerMsg::Ice {
+                        candidate,
+                        sdp_mline_index,
+                    },
+                );
+                None
+            });
+        // An empty candidate tells the browser that no more are coming.
+        conn.webrtcbin
+            .connect_notify(Some("ice-gathering-state"), move |webrtcbin, _| {
+                let state = webrtcbin.property::<WebRTCICEGatheringState>("ice-gathering-state");
+                if state == WebRTCICEGatheringState::Complete {
+                    send_to_server(
+                        &out_tx,
+                        &id,
+                        StreamerMsg::Ice {
+                            candidate: String::new(),
+                            sdp_mline_index: 0,
+                        },
+                    );
+                }
+            });
         //conn.webrtcbin
         //    .connect("on-negotiation-needed", false, |values| {
         //        for v in values {
@@ -238,34 +312,43 @@ impl UserConn {
         Ok(conn)
     }
 
-    pub async fn set_remote_description(&mut self, sdp_offer: &str) -> String {
-        let pipeline = self.pipeline_stream.clone();
-        let mut guard = pipeline.lock().await;
-        let ret = gst_sdp::SDPMessage::parse_buffer(sdp_offer.as_bytes())
-            .map_err(|_| anyhow!("Failed to parse SDP offer"))
-            .unwrap();
-
-        let offer = WebRTCSessionDescription::new(WebRTCSDPType::Offer, ret);
-
-        loop {
-            if let Some(msg) = guard.next().await {
-                match msg.view() {
-                    gst::MessageView::StateChanged(s) => {
-                        if s.current() == gst::State::Playing {
-                            self.webrtcbin.emit_by_name::<()>(
-                                "set-remote-description",
-                                &[&offer, &None::<gst::Promise>],
-                            );
-
-                            return "Played!".to_string();
-                        }
-                    }
-                    _ => (),
-                }
-            }
-        }
-        drop(guard);
-        "Did not pass".to_string()
+    /// Applies a browser's offer and returns the answer. A first offer waits for the pipeline
+    /// to play, later ones renegotiate the running peer connection.
+    pub async fn answer_offer(&self, sdp_offer: &str) -> Result<String, anyhow::Error> {
+        self.set_remote_description(sdp_offer).await?;
+        let answer = self
+            .create_sdp_answer()
+            .await
+            .ok_or_else(|| anyhow!("webrtcbin had no answer to the offer"))?;
+        self.set_local_description(answer.clone())
+            .await
+            .ok_or_else(|| anyhow!("Couldn't set the answer"))?;
+        Ok(answer)
+    }
+    pub async fn set_remote_description(&self, sdp_offer: &str) -> Result<(), anyhow::Error> {
+        let sdp = gst_sdp::SDPMessage::parse_buffer(sdp_offer.as_bytes())
+            .map_err(|_| anyhow!("Failed to parse SDP offer"))?;
+        let offer = WebRTCSessionDescription::new(WebRTCSDPType::Offer, sdp);
+
+        // Later offers don't wait, the user may have paused by then.
+        self.started
+            .clone()
+            .wait_for(|started| *started)
+            .await
+            .map_err(|_| anyhow!("The pipeline stopped before it played"))?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let promise = gst::Promise::with_change_func(move |_| {
+            let _ = tx.send(());
+        });
+        self.webrtcbin
+            .emit_by_name::<()>("set-remote-description", &[&offer, &promise]);
+        rx.await.map_err(|_| anyhow!("The offer was not applied"))
+    }
+    /// Answers a browser's ICE restart offer on the running pipeline, playback carries on
+    /// while the new candidates are gathered.
+    pub async fn restart_ice(&self, sdp_offer: &str) -> Result<String, anyhow::Error> {
+        self.answer_offer(sdp_offer).await
     }
     pub async fn create_sdp_answer(&self) -> Option<String> {
         let conn_clone = self.downgrade();
@@ -273,19 +356,18 @@ impl UserConn {
 
         self.pipeline.call_async(move |_| {
             let conn = upgrade_weak!(conn_clone);
+            // Dropping `tx` without sending is how failures show up, as None.
             let promise = gst::Promise::with_change_func(move |reply| {
-                let answer = reply.unwrap().unwrap();
+                let Ok(Some(answer)) = reply else {
+                    eprintln!("create-answer gave no reply");
+                    return;
+                };
                 if let Ok(e) = answer.get::<glib::Error>("error") {
                     eprintln!("Answer: {e}");
-                } else {
-                    let thing = answer
-                        .value("answer")
-                        .unwrap()
-                        .get::<WebRTCSessionDescription>()
-                        .unwrap();
+                } else if let Ok(thing) = answer.get::<WebRTCSessionDescription>("answer") {
                     //println!("{:?}", thing.sdp().to_string());
 
-                    tx.send(thing.sdp().to_string());
+                    let _ = tx.send(thing.sdp().to_string());
                 }
             });
             println!("Async was called");
@@ -310,9 +392,10 @@ impl UserConn {
             let promise = gst::Promise::with_change_func(move |reply| {
                 tx.send("Set local description successfully".to_string());
             });
-            let ret = gst_sdp::SDPMessage::parse_buffer(local_desc.as_bytes())
-                .map_err(|_| anyhow!("Failed to parse local description"))
-                .unwrap();
+            let Ok(ret) = gst_sdp::SDPMessage::parse_buffer(local_desc.as_bytes()) else {
+                eprintln!("Failed to parse local description");
+                return;
+            };
 
             let answer = WebRTCSessionDescription::new(WebRTCSDPType::Answer, ret);
 
@@ -331,11 +414,16 @@ impl UserConn {
         self.webrtcbin
             .emit_by_name::<()>("add-ice-candidate", &[&mlineindex, &candidate]);
     }
+    /// Does nothing before the browser opened the data channel.
     pub fn send_data_channel_msg(&self, msg: &str) {
-        let guard = self.clone();
-        let guard = guard.dc.lock().unwrap();
-        let dc = guard.clone().unwrap();
-        dc.send_string_full(Some(msg)).unwrap();
+        let Some(dc) = self.dc.lock().unwrap().clone() else {
+            return;
+        };
+        if let Err(e) = dc.send_string_full(Some(msg)) {
+            eprintln!("Couldn't send on the data channel: {}", e);
+            return;
+        }
+        metrics::datachannel_message("out");
         //let conn_clone = self.downgrade();
         //dc.connect_on_message_string(move |_, msg| {
         //    let conn = upgrade_weak!(conn_clone);
@@ -348,39 +436,379 @@ impl UserConn {
     }
     fn change_audio_src(&self, tracks: &[String]) -> Result<(), anyhow::Error> {
         self.pipeline.set_state(gstreamer::State::Paused);
+        let caps = repan_config::get().audio.caps();
+        let mixer = self
+            .pipeline
+            .by_name("mix")
+            .context("No mixer in the pipeline")?;
+        let mut stems = self.stems.lock().unwrap();
+        // The old jam's elements go first, or `pan{i}`/`vol{i}` could still find them and the
+        // mixer would wait for their pads.
+        for bin in stems.drain(..) {
+            let _ = bin.set_state(gst::State::Null);
+            if let Some(src) = bin.static_pad("src") {
+                if let Some(mix_pad) = src.peer() {
+                    let _ = src.unlink(&mix_pad);
+                    mixer.release_request_pad(&mix_pad);
+                }
+            }
+            let _ = self.pipeline.remove(&bin);
+        }
         for (index, track) in tracks.iter().enumerate() {
+            let location = quote(track);
             let desc = format!(
-                r#"filesrc location={track} !
+                r#"filesrc location={location} !
                                 wavparse !
                                 audioconvert !
                                 audioresample ! 
-                                audio/x-raw,rate=48000,channels=2 !
+                                {caps} !
                                 audiopanorama name=pan{index} !
                                 volume name=vol{index} !
                                 queue name=q"#
             );
 
-            let mixer = self.pipeline.by_name("mix").unwrap();
-            let mix_pad = mixer.request_pad_simple("sink_%u").unwrap();
-            let bin = gst::parse::bin_from_description(desc.as_str(), true).unwrap();
-            self.pipeline.add(&bin);
-            let src = bin.static_pad("src").unwrap();
-            src.link(&mix_pad).unwrap();
-            bin.sync_state_with_parent().unwrap();
+            let mix_pad = mixer
+                .request_pad_simple("sink_%u")
+                .context("Mixer has no pad left")?;
+            let bin = gst::parse::bin_from_description(desc.as_str(), true)?;
+            self.pipeline.add(&bin)?;
+            let src = bin.static_pad("src").context("Stem has no src pad")?;
+            src.link(&mix_pad)?;
+            bin.sync_state_with_parent()?;
+            stems.push(bin);
+        }
+        drop(stems);
+        *self.mix.lock().unwrap() = vec![TrackState::default(); tracks.len()];
+        // A loop is a passage of the old jam.
+        if self.looping.lock().unwrap().take().is_some() {
+            self.reply(&DataChannelMsg::LoopSet { region: None });
+        }
+        // The new stems start their segments at normal speed.
+        if std::mem::replace(&mut *self.rate.lock().unwrap(), 1.0) != 1.0 {
+            self.reply(&DataChannelMsg::RateSet { rate: 1.0 });
         }
         self.pipeline.set_state(gstreamer::State::Playing);
         Ok(())
     }
+    /// Plays, pauses, stops or seeks the jam. Every stem goes through the mixer, a flushing,
+    /// accurate seek on it moves all of them to the same sample.
+    fn transport(&self, msg: &DataChannelMsg) -> Result<(), anyhow::Error> {
+        match *msg {
+            DataChannelMsg::Play => {
+                self.pipeline.set_state(gst::State::Playing)?;
+            }
+            DataChannelMsg::Pause => {
+                self.pipeline.set_state(gst::State::Paused)?;
+            }
+            DataChannelMsg::Stop => {
+                self.pipeline.set_state(gst::State::Paused)?;
+                self.seek(gst::ClockTime::ZERO)?;
+            }
+            DataChannelMsg::Seek { position_ms } => {
+                self.seek(gst::ClockTime::from_mseconds(position_ms))?
+            }
+            DataChannelMsg::SeekBy { offset_ms } => {
+                let (position, _) = self.position().context("Nothing is playing")?;
+                let target = position.mseconds().saturating_add_signed(offset_ms);
+                self.seek(gst::ClockTime::from_mseconds(target))?
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    /// While looping, playback stays inside the loop and its segment ends with the loop, the
+    /// bus then says `SegmentDone` and `wrap_loop` goes back to the start.
+    fn seek(&self, position: gst::ClockTime) -> Result<(), anyhow::Error> {
+        let mixer = self
+            .pipeline
+            .by_name("mix")
+            .context("No mixer in the pipeline")?;
+        let position = match self.position().and_then(|(_, duration)| duration) {
+            Some(duration) => position.min(duration),
+            None => position,
+        };
+        let flags = gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE;
+        // Without a loop the stop is set to none, or the old loop's end would stay.
+        let (flags, position, stop) = match *self.looping.lock().unwrap() {
+            Some(region) => (
+                flags | gst::SeekFlags::SEGMENT,
+                gst::ClockTime::from_mseconds(region.clamp(position.mseconds())),
+                Some(gst::ClockTime::from_mseconds(region.end_ms)),
+            ),
+            None => (flags, position, None),
+        };
+        let rate = *self.rate.lock().unwrap();
+        mixer.seek(
+            rate,
+            flags,
+            gst::SeekType::Set,
+            Some(position),
+            gst::SeekType::Set,
+            stop,
+        )?;
+        Ok(())
+    }
+    /// Starts looping `region`, or stops looping with none. Playback carries on from where it is
+    /// if that is inside the loop, otherwise from the loop's start.
+    fn set_loop(&self, region: Option<LoopRegion>) -> Result<Option<LoopRegion>, anyhow::Error> {
+        let Some((position, duration)) = self.position() else {
+            if region.is_some() {
+                bail!("No jam is playing");
+            }
+            *self.looping.lock().unwrap() = None;
+            return Ok(None);
+        };
+        let position = match region {
+            Some(region) => {
+                region.validate().map_err(|e| anyhow!(e))?;
+                if let Some(duration) = duration.filter(|d| region.start_ms >= d.mseconds()) {
+                    bail!("The jam is only {} s long", duration.seconds());
+                }
+                if (region.start_ms..region.end_ms).contains(&position.mseconds()) {
+                    position
+                } else {
+                    gst::ClockTime::from_mseconds(region.start_ms)
+                }
+            }
+            None => position,
+        };
+        *self.looping.lock().unwrap() = region;
+        self.seek(position)?;
+        Ok(region)
+    }
+    /// Plays on from where playback is at `rate` times the speed. Positions are in the jam's own
+    /// time, so the playhead and seeks stay right at any speed.
+    fn set_rate(&self, rate: f64) -> Result<f64, anyhow::Error> {
+        let rate = check_playback_rate(rate).map_err(|e| anyhow!(e))?;
+        let (position, _) = self.position().context("No jam is playing")?;
+        *self.rate.lock().unwrap() = rate;
+        self.seek(position)?;
+        Ok(rate)
+    }
+    /// Shifts the pitch of the whole mix. `pitch` comes after `scaletempo`, so it combines with
+    /// any speed and leaves the tempo alone. It stays with the listener from jam to jam.
+    fn set_transpose(&self, transpose: Transpose) -> Result<Transpose, anyhow::Error> {
+        transpose.validate().map_err(|e| anyhow!(e))?;
+        let pitch = self
+            .pipeline
+            .by_name("pitch")
+            .context("No pitch element in the pipeline")?;
+        pitch.set_property("pitch", transpose.ratio() as f32);
+        Ok(transpose)
+    }
+    /// Called when playback reached the end of the loop. Not flushing, the data of the loop's
+    /// end still plays out and the start follows without a gap, on every stem at once.
+    fn wrap_loop(&self) {
+        let Some(region) = *self.looping.lock().unwrap() else {
+            return;
+        };
+        let Some(mixer) = self.pipeline.by_name("mix") else {
+            return;
+        };
+        let rate = *self.rate.lock().unwrap();
+        if let Err(e) = mixer.seek(
+            rate,
+            gst::SeekFlags::ACCURATE | gst::SeekFlags::SEGMENT,
+            gst::SeekType::Set,
+            Some(gst::ClockTime::from_mseconds(region.start_ms)),
+            gst::SeekType::Set,
+            Some(gst::ClockTime::from_mseconds(region.end_ms)),
+        ) {
+            eprintln!("Couldn't go back to the start of the loop: {}", e);
+        }
+    }
+    /// Position and duration of the mix, none before a jam is loaded.
+    fn position(&self) -> Option<(gst::ClockTime, Option<gst::ClockTime>)> {
+        if self.mix.lock().unwrap().is_empty() {
+            return None;
+        }
+        let src = self.pipeline.by_name("mix")?.static_pad("src")?;
+        let position = src.query_position::<gst::ClockTime>()?;
+        Some((position, src.query_duration::<gst::ClockTime>()))
+    }
+    /// Tells the browser where playback is, for its playhead.
+    pub fn report_position(&self) {
+        let Some((position, duration)) = self.position() else {
+            return;
+        };
+        self.reply(&DataChannelMsg::Position {
+            position_ms: position.mseconds(),
+            duration_ms: duration.map(|duration| duration.mseconds()),
+            playing: self.pipeline.current_state() == gst::State::Playing,
+        });
+    }
+    fn reply(&self, msg: &DataChannelMsg) {
+        match serde_json::to_string(msg) {
+            Ok(msg) => self.send_data_channel_msg(&msg),
+            Err(e) => eprintln!("Couldn't encode {:?}: {}", msg, e),
+        }
+    }
+    /// Applies `change` to track `index` of the live mix. A solo changing affects what every
+    /// other track sounds like, so all of them are set again.
+    fn set_track(&self, index: usize, change: &TrackChange) -> Result<TrackState, anyhow::Error> {
+        let mut mix = self.mix.lock().unwrap();
+        let Some(current) = mix.get(index) else {
+            bail!("No track {}, the jam has {}", index, mix.len());
+        };
+        let state = current.apply(change).map_err(|e| anyhow!(e))?;
+        mix[index] = state;
+
+        let any_solo = mix.iter().any(|track| track.solo);
+        for (index, track) in mix.iter().enumerate() {
+            let volume = self
+                .pipeline
+                .by_name(&format!("vol{}", index))
+                .with_context(|| format!("No volume element for track {}", index))?;
+            let pan = self
+                .pipeline
+                .by_name(&format!("pan{}", index))
+                .with_context(|| format!("No panorama element for track {}", index))?;
+            volume.set_property("volume", track.volume);
+            volume.set_property("mute", !track.audible(any_solo));
+            pan.set_property("panorama", track.pan as f32);
+        }
+        Ok(state)
+    }
+    /// Plays another jam. Only called for messages from the server, which checked that the user may
+    /// listen to it.
+    pub fn change_jam(
+        &self,
+        path: &str,
+        date: &str,
+        tracks: &[String],
+    ) -> Result<(), anyhow::Error> {
+        let tracks: Vec<String> = tracks
+            .iter()
+            .map(|s| format!("{}{}-{}.wav", path, s, date))
+            .collect();
+        println!("{:?}", tracks);
+        self.change_audio_src(tracks.as_slice())
+    }
+    pub fn state(&self) -> gst::State {
+        self.pipeline.current_state()
+    }
+    /// Inputs of the mixer, one per track of the jam being played.
+    pub fn mixed_tracks(&self) -> usize {
+        self.pipeline
+            .by_name("mix")
+            .map(|mixer| mixer.sink_pads().len())
+            .unwrap_or(0)
+    }
+    /// Where the session is, from the peer connection and the browser's heartbeats. Closed
+    /// sessions are torn down. A failed connection isn't closed yet, the browser may restart
+    /// ICE until it goes idle.
+    pub fn session_state(&self) -> SessionState {
+        let connection = self
+            .webrtcbin
+            .property::<WebRTCPeerConnectionState>("connection-state");
+        if connection == WebRTCPeerConnectionState::Closed {
+            return SessionState::Closed;
+        }
+        let idle_timeout = repan_config::get().streamer.idle_timeout();
+        match *self.last_heartbeat.lock().unwrap() {
+            // Never connected, the browser may have gone before it got that far.
+            None if self.created.elapsed() > idle_timeout => SessionState::Closed,
+            None => SessionState::Negotiating,
+            Some(beat) if beat.elapsed() < HEARTBEAT_TIMEOUT => SessionState::Connected,
+            Some(beat) if beat.elapsed() < idle_timeout => SessionState::Idle,
+            Some(_) => SessionState::Closed,
+        }
+    }
+    /// Records `state` as reported, false if the server knew it already.
+    pub fn report_state(&self, state: SessionState) -> bool {
+        let mut reported = self.reported.lock().unwrap();
+        if *reported == state {
+            return false;
+        }
+        *reported = state;
+        true
+    }
     fn parse_data_channel_msg(&self, unparsed_msg: String) {
-        if let Ok(GstJsonMsg::ChangeJam { path, date, tracks }) =
-            serde_json::from_str(unparsed_msg.as_str())
-        {
-            let tracks: Vec<String> = tracks
-                .iter()
-                .map(move |s| format!("{}{}-{}.wav", path, s, date))
-                .collect();
-            println!("{:?}", tracks);
-            self.change_audio_src(tracks.as_slice());
+        metrics::datachannel_message("in");
+        // Anything from the browser shows it is still there, it sends heartbeats when it has
+        // nothing else to say.
+        *self.last_heartbeat.lock().unwrap() = Some(Instant::now());
+        match serde_json::from_str::<DataChannelMsg>(unparsed_msg.as_str()) {
+            Ok(DataChannelMsg::Heartbeat) => (),
+            Ok(DataChannelMsg::SetTrack { index, change }) => {
+                let reply = match self.set_track(index, &change) {
+                    Ok(state) => DataChannelMsg::TrackSet { index, state },
+                    Err(e) => DataChannelMsg::TrackRefused {
+                        index,
+                        reason: e.to_string(),
+                    },
+                };
+                self.reply(&reply);
+            }
+            Ok(DataChannelMsg::SetRate { rate }) => {
+                let reply = match self.set_rate(rate) {
+                    Ok(rate) => DataChannelMsg::RateSet { rate },
+                    Err(e) => DataChannelMsg::RateRefused {
+                        reason: e.to_string(),
+                    },
+                };
+                self.reply(&reply);
+                self.report_position();
+            }
+            Ok(DataChannelMsg::SetTranspose { transpose }) => {
+                let reply = match self.set_transpose(transpose) {
+                    Ok(transpose) => DataChannelMsg::TransposeSet { transpose },
+                    Err(e) => DataChannelMsg::TransposeRefused {
+                        reason: e.to_string(),
+                    },
+                };
+                self.reply(&reply);
+            }
+            Ok(msg @ (DataChannelMsg::SetLoop { .. } | DataChannelMsg::ClearLoop)) => {
+                let region = match msg {
+                    DataChannelMsg::SetLoop { region } => Some(region),
+                    _ => None,
+                };
+                let reply = match self.set_loop(region) {
+                    Ok(region) => DataChannelMsg::LoopSet { region },
+                    Err(e) => DataChannelMsg::LoopRefused {
+                        reason: e.to_string(),
+                    },
+                };
+                self.reply(&reply);
+                self.report_position();
+            }
+            Ok(
+                msg @ (DataChannelMsg::Play
+                | DataChannelMsg::Pause
+                | DataChannelMsg::Stop
+                | DataChannelMsg::Seek { .. }
+                | DataChannelMsg::SeekBy { .. }),
+            ) => {
+                if let Err(e) = self.transport(&msg) {
+                    eprintln!("Couldn't apply {:?}: {:?}", msg, e);
+                }
+                // The playhead moves right away, not at the next report.
+                self.report_position();
+            }
+            // Only the streamer sends these.
+            Ok(msg) => println!("Unexpected message on the data channel: {:?}", msg),
+            Err(e) => println!("Unknown message on the data channel: {}", e),
+        }
+    }
+}
+
+/// Reads the pipeline's bus for as long as the connection is there. Tells `started` when the
+/// pipeline first plays, and sends playback back to a loop's start at its end.
+async fn watch_bus(conn: WeakConn, mut bus: BusStream, started: watch::Sender<bool>) {
+    while let Some(msg) = bus.next().await {
+        let Some(conn) = conn.upgrade() else {
+            break;
+        };
+        match msg.view() {
+            gst::MessageView::StateChanged(s)
+                if s.current() == gst::State::Playing
+                    && msg.src() == Some(conn.pipeline.upcast_ref::<gst::Object>()) =>
+            {
+                started.send_if_modified(|started| !std::mem::replace(started, true));
+            }
+            gst::MessageView::SegmentDone(_) => conn.wrap_loop(),
+            _ => (),
         }
     }
 }
@@ -10,8 +10,6 @@ use serde::{Deserialize, Serialize};
 use tokio::{sync::{futures, mpsc::{self, UnboundedReceiver}}};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
-
 // upgrade weak reference or return
 #[macro_export]
 macro_rules! upgrade_weak {
@@ -98,7 +96,9 @@ impl UserConn
 
         let webrtcbin = pipeline.by_name("webrtcbin").unwrap();
 
-        webrtcbin.set_property_from_str("stun-server", STUN_SERVER);
+        if let Some(stun_server) = repan_config::get().ice.settings().gst_stun_server() {
+            webrtcbin.set_property_from_str("stun-server", &stun_server);
+        }
         webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
 
         let bus = pipeline.bus().unwrap();
@@ -491,7 +491,9 @@ pub fn connect_user_test()
 
     let webrtcbin = pipeline.by_name("webrtcbin").unwrap();
 
-    webrtcbin.set_property_from_str("stun-server", STUN_SERVER);
+    if let Some(stun_server) = repan_config::get().ice.settings().gst_stun_server() {
+        webrtcbin.set_property_from_str("stun-server", &stun_server);
+    }
     webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
 
     let bus = pipeline.bus().unwrap();
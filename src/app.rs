@@ -2,13 +2,23 @@ use leptos::{logging::log, mount, prelude::*, task::spawn_local};
 use leptos_meta::{provide_meta_context, MetaTags, Stylesheet, Title};
 use leptos_router::{
     components::{Route, Router, Routes},
-    StaticSegment,
+    ParamSegment, StaticSegment,
 };
-use uuid::Uuid;
 use web_sys::{RtcDataChannel, RtcPeerConnection};
 
+use crate::backend::auth::current_user;
 use crate::frontend::{
-    calendar::Calendar, jamselector::JamSelector, track_list::TrackList, webrtc::OfferComp,
+    bands::BandsPage,
+    calendar::Calendar,
+    jamselector::JamSelector,
+    login::{LoginPage, LogoutButton},
+    mixdown::Mixdown,
+    sessions::SessionsPage,
+    share::{ShareJam, SharePage},
+    track_list::{LiveMix, TrackList},
+    transport::{Playhead, Transport},
+    upload::UploadPage,
+    webrtc::OfferComp,
 };
 
 pub fn shell(options: LeptosOptions) -> impl IntoView {
@@ -47,6 +57,11 @@ pub fn App() -> impl IntoView {
             <main>
                 <Routes fallback=|| "Page not found.".into_view()>
                     <Route path=StaticSegment("") view=HomePage />
+                    <Route path=StaticSegment("upload") view=UploadPage />
+                    <Route path=StaticSegment("bands") view=BandsPage />
+                    <Route path=StaticSegment("sessions") view=SessionsPage />
+                    <Route path=(StaticSegment("share"), ParamSegment("token")) view=SharePage />
+                    <Route path=StaticSegment("login") view=LoginPage />
                 </Routes>
             </main>
         </Router>
@@ -56,6 +71,30 @@ pub fn App() -> impl IntoView {
 /// Renders the home page of your application.
 #[component]
 fn HomePage() -> impl IntoView {
+    let user = Resource::new(|| (), |_| current_user());
+
+    view! {
+        <Suspense fallback=|| view! { <p>"Loading..."</p> }>
+            {move || Suspend::new(async move {
+                match user.await {
+                    Ok(Some(user)) => {
+                        view! { <Library username=user.username admin=user.admin /> }.into_any()
+                    }
+                    _ => {
+                        view! {
+                            <h1>"Welcome to Repan!"</h1>
+                            <p><a href="/login">"Log in"</a>" to listen to the rehearsals."</p>
+                        }
+                            .into_any()
+                    }
+                }
+            })}
+        </Suspense>
+    }
+}
+
+#[component]
+fn Library(username: String, admin: bool) -> impl IntoView {
     let (pc, pc_set) = signal_local::<Option<RtcPeerConnection>>(None);
     let (dc, dc_set) = signal_local::<Option<RtcDataChannel>>(None);
 
@@ -63,10 +102,6 @@ fn HomePage() -> impl IntoView {
 
     let (selected_day, set_selected_day) = signal(String::new());
     let (selected_jam_id, set_selected_jam_id) = signal(0 as i64);
-    //let uuid = Uuid::new_v4();
-    let uuid = Uuid::new_v4();
-    let uuid = uuid.as_u128().to_string();
-    let (user_id, _) = signal(uuid);
     //*pc_set.write() = Some(RtcPeerConnection::new().unwrap());
 
     provide_context(dc_set);
@@ -74,12 +109,21 @@ fn HomePage() -> impl IntoView {
     provide_context(pc_set);
     provide_context(pc);
     provide_context(set_selected_day);
+    provide_context(LiveMix::default());
+    provide_context(Playhead::default());
 
     view! {
         <Router>
             <h1>"Welcome to Repan!"</h1>
             <nav>
                 <a href="/">"Home"</a>
+                <a href="/upload">"Upload"</a>
+                <a href="/bands">"Bands"</a>
+                <Show when=move || admin>
+                    <a href="/sessions">"Sessions"</a>
+                </Show>
+                <span>{username}</span>
+                <LogoutButton />
             </nav>
         </Router>
         <Calendar></Calendar>
@@ -87,7 +131,10 @@ fn HomePage() -> impl IntoView {
             selected_day=selected_day
             set_selected_jam_id=set_selected_jam_id
         ></JamSelector>
+        <Transport selected_jam_id=selected_jam_id />
         <TrackList selected_jam_id=selected_jam_id />
+        <Mixdown selected_jam_id=selected_jam_id />
+        <ShareJam selected_jam_id=selected_jam_id />
         //<Show when=move || { !started.get() } fallback=|| view! { <p>"Connect"</p> }>
         //    <button on:click=move |_| {
         //        spawn_local(async {
@@ -100,7 +147,7 @@ fn HomePage() -> impl IntoView {
         //    spawn_local(async {});
         //}>"Play pipeline"</button>
         <div>
-                <OfferComp user_id=user_id></OfferComp>
+                <OfferComp></OfferComp>
             //<WebRtcComp user_id=user_id></WebRtcComp>
         </div>
 
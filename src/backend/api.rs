@@ -0,0 +1,757 @@
+//! Versioned JSON API, mounted under `/api/v1` next to the Leptos server functions.
+//!
+//! The shapes in this module are part of the public contract described in `openapi.json`.
+//! Add fields rather than renaming or removing them.
+
+use axum::body::{Body, Bytes};
+use axum::extract::DefaultBodyLimit;
+use axum::extract::{Extension, Path, Query};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{middleware, Json, Router};
+use chrono::NaiveDateTime;
+use leptos::logging::log;
+use leptos::prelude::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::auth::{require_login, AuthSession};
+use crate::backend::bands::MyBand;
+use crate::backend::client_connections::{self, SessionInfo};
+use crate::backend::database::{
+    get_database, JamQueryResult, Marker, MixPreset, QueryAmount, QueryTarget, QueryType, Role,
+    StemFile, TrackMix,
+};
+use crate::backend::library::{scan_status, start_scan, ScanStatus};
+use crate::backend::render::{
+    render_job, share_render, start_render_job, start_share_render, RenderFormat, RenderState,
+    RenderStatus,
+};
+use crate::backend::share::{
+    create_share_link, open_share, own_shares, resolve_share, revoke_share, OwnShare, ShareError,
+    SharedJam,
+};
+use crate::backend::stemserve::{serve_file, serve_stem};
+use crate::backend::streamers::{self, StreamerInfo};
+use crate::backend::upload::{
+    append_chunk, complete_upload_file, create_upload_file, upload_status, IngestResult,
+    UploadError, UploadStatus,
+};
+use crate::backend::zipstream::{ZipChunk, ZipStream};
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            error: error.into(),
+        }
+    }
+    pub fn not_found(what: &str) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, format!("{} not found", what))
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        log!("Database error in api: {:?}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+    }
+}
+
+impl From<ServerFnError> for ApiError {
+    fn from(e: ServerFnError) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<UploadError> for ApiError {
+    fn from(e: UploadError) -> Self {
+        let status = match e {
+            UploadError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UploadError::NotFound => StatusCode::NOT_FOUND,
+            UploadError::Forbidden(_) => StatusCode::FORBIDDEN,
+            UploadError::OffsetMismatch(_) | UploadError::Duplicate(_) => StatusCode::CONFLICT,
+            UploadError::Io(_) | UploadError::Database(_) => {
+                log!("Upload failed: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        ApiError::new(status, e.to_string())
+    }
+}
+
+impl From<ShareError> for ApiError {
+    fn from(e: ShareError) -> Self {
+        let status = match e {
+            ShareError::NotFound(_) => StatusCode::NOT_FOUND,
+            ShareError::Expired | ShareError::Revoked => StatusCode::GONE,
+            ShareError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ShareError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ShareError::Database(_) => {
+                log!("Share link failed: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        ApiError::new(status, e.to_string())
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.error)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+#[derive(Debug, Serialize)]
+pub struct JamSummary {
+    pub id: i64,
+    pub date: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Track {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Jam {
+    pub id: i64,
+    pub date: String,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JamFilter {
+    /// `yymm`, matching the format the calendar uses.
+    pub month: Option<String>,
+    /// `yymmdd`
+    pub day: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StemSelection {
+    /// Comma separated track ids. Every track of the jam when left out.
+    pub tracks: Option<String>,
+}
+
+/// Written as `manifest.json` at the root of a stem archive.
+#[derive(Debug, Serialize)]
+pub struct StemManifest {
+    pub jam_id: i64,
+    pub date: String,
+    pub tracks: Vec<ManifestTrack>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestTrack {
+    pub id: i64,
+    pub name: String,
+    pub file: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewRender {
+    pub jam_id: i64,
+    pub format: RenderFormat,
+    /// Tracks left out play at unity gain, centered.
+    #[serde(default)]
+    pub tracks: Vec<TrackMix>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewMarker {
+    pub name: String,
+    pub position_ms: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewMixPreset {
+    pub name: String,
+    pub tracks: Vec<TrackMix>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUpload {
+    pub band_id: i64,
+    pub file_name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewShare {
+    /// Locks the player to this preset of the jam.
+    pub preset_id: Option<i64>,
+    #[serde(default)]
+    pub allow_download: bool,
+    pub expires_in_days: u32,
+}
+
+pub fn api_router<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/jams", get(list_jams))
+        .route("/jams/{id}", get(get_jam))
+        .route("/jams/{id}/tracks", get(list_tracks))
+        .route("/jams/{id}/stems.zip", get(download_stems))
+        .route("/jams/{id}/markers", get(list_markers).post(create_marker))
+        .route("/jams/{id}/markers/{marker_id}", delete(delete_marker))
+        .route("/jams/{id}/presets", get(list_presets).post(create_preset))
+        .route("/jams/{id}/presets/{preset_id}", delete(delete_preset))
+        .route("/tracks/{id}/audio", get(track_audio))
+        .route("/renders", post(create_render))
+        .route("/renders/{id}", get(get_render))
+        .route("/renders/{id}/file", get(render_file))
+        .route("/uploads", post(create_upload))
+        .route(
+            "/uploads/{id}",
+            get(get_upload)
+                .patch(upload_chunk)
+                .layer(DefaultBodyLimit::max(
+                    repan_config::get().limits.max_chunk_size,
+                )),
+        )
+        .route("/uploads/{id}/complete", post(complete_upload))
+        .route("/jams/{id}/shares", post(create_share))
+        .route("/shares", get(list_shares))
+        .route("/shares/{id}", delete(delete_share))
+        .route("/bands", get(list_bands))
+        .route("/sessions", get(list_sessions))
+        .route("/streamers", get(list_streamers))
+        .route(
+            "/library/scan",
+            get(get_library_scan).post(start_library_scan),
+        )
+        .route_layer(middleware::from_fn(require_login))
+        .route("/openapi.json", get(openapi))
+        // Share links carry their own permission, no session needed.
+        .route("/shared/{token}", get(get_shared))
+        .route("/shared/{token}/tracks/{id}/audio", get(shared_track_audio))
+        .route("/shared/{token}/stems.zip", get(shared_stems))
+        .route(
+            "/shared/{token}/mix",
+            get(shared_mix).post(start_shared_mix),
+        )
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+async fn list_jams(
+    Extension(session): Extension<AuthSession>,
+    Query(filter): Query<JamFilter>,
+) -> ApiResult<Vec<JamSummary>> {
+    let mut db = get_database()?;
+
+    let amount = match (filter.month, filter.day) {
+        (_, Some(day)) => QueryAmount::Day(day),
+        (Some(month), None) => QueryAmount::Month(month),
+        (None, None) => QueryAmount::All,
+    };
+    let jams: Vec<JamQueryResult<String>> = db.query(session.user.id, QueryTarget::Date, amount)?;
+
+    Ok(Json(
+        jams.into_iter()
+            .map(|jam| JamSummary {
+                id: jam.id,
+                date: jam.data,
+            })
+            .collect(),
+    ))
+}
+
+async fn get_jam(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+) -> ApiResult<Jam> {
+    let mut db = get_database()?;
+
+    let date: Vec<JamQueryResult<String>> = db.query(
+        session.user.id,
+        QueryTarget::Date,
+        QueryAmount::One(QueryType::FromID(id)),
+    )?;
+    let Some(date) = date.into_iter().next() else {
+        return Err(ApiError::not_found("Jam"));
+    };
+    let tracks = tracks_of(session.user.id, id)?;
+
+    Ok(Json(Jam {
+        id,
+        date: date.data,
+        tracks,
+    }))
+}
+
+async fn list_tracks(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+) -> ApiResult<Vec<Track>> {
+    require_jam(&session, id, Role::Viewer)?;
+    Ok(Json(tracks_of(session.user.id, id)?))
+}
+
+async fn download_stems(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+    Query(selection): Query<StemSelection>,
+) -> Result<Response, ApiError> {
+    let mut db = get_database()?;
+
+    let date: Vec<JamQueryResult<String>> = db.query(
+        session.user.id,
+        QueryTarget::Date,
+        QueryAmount::One(QueryType::FromID(id)),
+    )?;
+    let Some(date) = date.into_iter().next().map(|d| d.data) else {
+        return Err(ApiError::not_found("Jam"));
+    };
+
+    let stems = db.stem_files(session.user.id, id)?;
+    stems_archive(id, date, stems, selection).await
+}
+
+/// Streams `stems` of a jam as a ZIP archive, narrowed down to the selected tracks.
+async fn stems_archive(
+    id: i64,
+    date: String,
+    mut stems: Vec<StemFile>,
+    selection: StemSelection,
+) -> Result<Response, ApiError> {
+    if let Some(tracks) = selection.tracks {
+        let mut wanted = tracks
+            .split(',')
+            .map(|t| t.trim().parse::<i64>())
+            .collect::<Result<Vec<i64>, _>>()
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "tracks must be track ids"))?;
+        // A track asked for twice is still one file in the archive.
+        wanted.sort_unstable();
+        wanted.dedup();
+        stems.retain(|stem| wanted.contains(&stem.track_id));
+        if stems.len() != wanted.len() {
+            return Err(ApiError::not_found("Track"));
+        }
+    }
+    // Check up front, once the first bytes are out the status can't change anymore.
+    for stem in &stems {
+        if tokio::fs::metadata(&stem.path).await.is_err() {
+            log!("Stem missing on disk: {:?}", stem.path);
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Stem {} is missing on the server", stem.name),
+            ));
+        }
+    }
+
+    let manifest = StemManifest {
+        jam_id: id,
+        date: date.clone(),
+        tracks: stems
+            .iter()
+            .map(|stem| ManifestTrack {
+                id: stem.track_id,
+                name: stem.name.clone(),
+                file: stem.file_name(),
+            })
+            .collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Manifest failed"))?;
+    let recorded = NaiveDateTime::parse_from_str(&date, "%y%m%d_%H%M")
+        .unwrap_or_else(|_| chrono::Local::now().naive_local());
+
+    let (tx, rx) = futures::channel::mpsc::channel::<ZipChunk>(4);
+    tokio::spawn(async move {
+        let mut zip = ZipStream::new(tx, recorded);
+        if let Err(e) = write_stems(&mut zip, &manifest, &stems).await {
+            log!("Stem archive for jam {} failed: {:?}", id, e);
+            zip.abort(e).await;
+            return;
+        }
+        if let Err(e) = zip.finish().await {
+            log!("Stem archive for jam {} failed: {:?}", id, e);
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.zip\"", date),
+            ),
+        ],
+        Body::from_stream(rx),
+    )
+        .into_response())
+}
+
+async fn write_stems(
+    zip: &mut ZipStream,
+    manifest: &[u8],
+    stems: &[StemFile],
+) -> Result<(), std::io::Error> {
+    zip.add_bytes("manifest.json", manifest).await?;
+    for stem in stems {
+        zip.add_file(&stem.file_name(), &stem.path).await?;
+    }
+    Ok(())
+}
+
+async fn list_markers(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+) -> ApiResult<Vec<Marker>> {
+    require_jam(&session, id, Role::Viewer)?;
+    let mut db = get_database()?;
+    Ok(Json(db.markers(id)?))
+}
+
+async fn create_marker(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+    Json(marker): Json<NewMarker>,
+) -> Result<(StatusCode, Json<Marker>), ApiError> {
+    require_jam(&session, id, Role::Editor)?;
+    if marker.position_ms < 0 {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "position_ms must not be negative",
+        ));
+    }
+    let mut db = get_database()?;
+    let marker = db.add_marker(id, &marker.name, marker.position_ms)?;
+    Ok((StatusCode::CREATED, Json(marker)))
+}
+
+async fn delete_marker(
+    Extension(session): Extension<AuthSession>,
+    Path((id, marker_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    require_jam(&session, id, Role::Editor)?;
+    let mut db = get_database()?;
+    if !db.delete_marker(id, marker_id)? {
+        return Err(ApiError::not_found("Marker"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_presets(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+) -> ApiResult<Vec<MixPreset>> {
+    require_jam(&session, id, Role::Viewer)?;
+    let mut db = get_database()?;
+    Ok(Json(db.mix_presets(id)?))
+}
+
+async fn create_preset(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+    Json(preset): Json<NewMixPreset>,
+) -> Result<(StatusCode, Json<MixPreset>), ApiError> {
+    require_jam(&session, id, Role::Editor)?;
+    let mut db = get_database()?;
+    let preset = db.add_mix_preset(id, &preset.name, preset.tracks)?;
+    Ok((StatusCode::CREATED, Json(preset)))
+}
+
+async fn delete_preset(
+    Extension(session): Extension<AuthSession>,
+    Path((id, preset_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    require_jam(&session, id, Role::Editor)?;
+    let mut db = get_database()?;
+    if !db.delete_mix_preset(id, preset_id)? {
+        return Err(ApiError::not_found("Preset"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn track_audio(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    serve_stem(session.user.id, id, method, headers).await
+}
+
+async fn create_render(
+    Extension(session): Extension<AuthSession>,
+    Json(render): Json<NewRender>,
+) -> Result<(StatusCode, Json<RenderStatus>), ApiError> {
+    require_jam(&session, render.jam_id, Role::Viewer)?;
+    let status =
+        start_render_job(&session.user, render.jam_id, render.format, render.tracks).await?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+async fn get_render(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<String>,
+) -> ApiResult<RenderStatus> {
+    match render_job(session.user.id, &id).await {
+        Some(job) => Ok(Json(job.status)),
+        None => Err(ApiError::not_found("Render")),
+    }
+}
+
+async fn render_file(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Some(job) = render_job(session.user.id, &id).await else {
+        return Err(ApiError::not_found("Render"));
+    };
+    if job.status.state != RenderState::Done {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Render is not finished",
+        ));
+    }
+    let name = format!(
+        "mix-{}.{}",
+        job.status.jam_id,
+        job.status.format.extension()
+    );
+    serve_file(&job.output, &name, method, headers).await
+}
+
+async fn create_upload(
+    Extension(session): Extension<AuthSession>,
+    Json(upload): Json<NewUpload>,
+) -> Result<(StatusCode, Json<UploadStatus>), ApiError> {
+    let status =
+        create_upload_file(&session.user, upload.band_id, upload.file_name, upload.size).await?;
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+async fn get_upload(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<String>,
+) -> ApiResult<UploadStatus> {
+    Ok(Json(upload_status(&session.user, &id).await?))
+}
+
+/// The chunk has to start at `Upload-Offset`. On a mismatch the client fetches the upload to
+/// find out where to continue.
+async fn upload_chunk(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<UploadStatus> {
+    let offset = headers
+        .get("upload-offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Missing Upload-Offset header"))?;
+    Ok(Json(append_chunk(&session.user, &id, offset, &body).await?))
+}
+
+async fn complete_upload(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<String>,
+) -> ApiResult<IngestResult> {
+    Ok(Json(complete_upload_file(&session.user, &id).await?))
+}
+
+async fn create_share(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+    Json(share): Json<NewShare>,
+) -> Result<(StatusCode, Json<OwnShare>), ApiError> {
+    let share = create_share_link(
+        &session.user,
+        id,
+        share.preset_id,
+        share.allow_download,
+        share.expires_in_days,
+    )?;
+    Ok((StatusCode::CREATED, Json(share)))
+}
+
+async fn list_shares(Extension(session): Extension<AuthSession>) -> ApiResult<Vec<OwnShare>> {
+    Ok(Json(own_shares(&session.user)?))
+}
+
+async fn delete_share(
+    Extension(session): Extension<AuthSession>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    revoke_share(&session.user, id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_shared(Path(token): Path<String>) -> ApiResult<SharedJam> {
+    Ok(Json(open_share(&token)?))
+}
+
+async fn shared_track_audio(
+    Path((token, id)): Path<(String, i64)>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let link = resolve_share(&token)?;
+    // The preset is only applied when mixing, raw stems would get around it.
+    if !link.allow_download {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This link doesn't allow downloads, play its mix instead",
+        ));
+    }
+    let mut db = get_database()?;
+    let Some(stem) = db
+        .shared_stem_files(link.jam_id)?
+        .into_iter()
+        .find(|stem| stem.track_id == id)
+    else {
+        return Err(ApiError::not_found("Track"));
+    };
+    serve_file(&stem.path, &stem.file_name(), method, headers).await
+}
+
+async fn start_shared_mix(
+    Path(token): Path<String>,
+) -> Result<(StatusCode, Json<RenderStatus>), ApiError> {
+    let link = resolve_share(&token)?;
+    let status = start_share_render(&link, &token).await?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+async fn shared_mix(
+    Path(token): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let link = resolve_share(&token)?;
+    let Some(job) = share_render(link.id).await else {
+        return Err(ApiError::not_found("Mix"));
+    };
+    if job.status.state != RenderState::Done {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Mix is not rendered yet",
+        ));
+    }
+    let name = format!("{}.{}", link.jam_date, job.status.format.extension());
+    serve_file(&job.output, &name, method, headers).await
+}
+
+async fn shared_stems(
+    Path(token): Path<String>,
+    Query(selection): Query<StemSelection>,
+) -> Result<Response, ApiError> {
+    let link = resolve_share(&token)?;
+    if !link.allow_download {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This link doesn't allow downloads",
+        ));
+    }
+    let stems = get_database()?.shared_stem_files(link.jam_id)?;
+    stems_archive(link.jam_id, link.jam_date, stems, selection).await
+}
+
+async fn list_bands(Extension(session): Extension<AuthSession>) -> ApiResult<Vec<MyBand>> {
+    let mut db = get_database()?;
+    let bands = db
+        .bands_of(&session.user)?
+        .into_iter()
+        .map(|(band, role)| MyBand { band, role })
+        .collect();
+    Ok(Json(bands))
+}
+
+/// Streaming sessions of every user, so only for site admins.
+async fn list_sessions(Extension(session): Extension<AuthSession>) -> ApiResult<Vec<SessionInfo>> {
+    require_site_admin(&session)?;
+    Ok(Json(client_connections::list_sessions().await))
+}
+
+async fn list_streamers(
+    Extension(session): Extension<AuthSession>,
+) -> ApiResult<Vec<StreamerInfo>> {
+    require_site_admin(&session)?;
+    Ok(Json(streamers::list().await))
+}
+
+async fn get_library_scan(Extension(session): Extension<AuthSession>) -> ApiResult<ScanStatus> {
+    require_site_admin(&session)?;
+    Ok(Json(scan_status()))
+}
+
+/// Runs in the background, poll `GET /library/scan` for the result.
+async fn start_library_scan(
+    Extension(session): Extension<AuthSession>,
+) -> Result<(StatusCode, Json<ScanStatus>), ApiError> {
+    require_site_admin(&session)?;
+    match start_scan() {
+        Some(status) => Ok((StatusCode::ACCEPTED, Json(status))),
+        None => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "A scan is running already",
+        )),
+    }
+}
+
+fn require_site_admin(session: &AuthSession) -> Result<(), ApiError> {
+    if !session.user.admin {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Only site admins can do that",
+        ));
+    }
+    Ok(())
+}
+
+/// Jams of other bands are reported as missing, not as forbidden.
+fn require_jam(session: &AuthSession, id: i64, min: Role) -> Result<Role, ApiError> {
+    let mut db = get_database()?;
+    match db.jam_role(session.user.id, id)? {
+        Some(role) if role >= min => Ok(role),
+        Some(_) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("Needs the {} role in this band", min.as_str()),
+        )),
+        None => Err(ApiError::not_found("Jam")),
+    }
+}
+
+fn tracks_of(user_id: i64, jam_id: i64) -> Result<Vec<Track>, ApiError> {
+    let mut db = get_database()?;
+    let tracks: Vec<JamQueryResult<String>> =
+        db.query(user_id, QueryTarget::Track(jam_id), QueryAmount::All)?;
+    Ok(tracks
+        .into_iter()
+        .map(|track| Track {
+            id: track.id,
+            name: track.data,
+        })
+        .collect())
+}
@@ -0,0 +1,236 @@
+//! Accounts and login sessions.
+//!
+//! Passwords are stored as argon2 hashes. Logging in creates a row in `sessions` and hands the
+//! browser its random token in an `HttpOnly` cookie, every server function and `/api/v1` route
+//! looks the user up from that cookie instead of trusting anything the client sends.
+
+#[cfg(feature = "ssr")]
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use leptos::{prelude::*, server};
+
+#[cfg(feature = "ssr")]
+use crate::backend::database::get_database;
+use crate::backend::database::User;
+
+/// The cookie holding the session token.
+#[cfg(feature = "ssr")]
+pub const SESSION_COOKIE: &str = "repan_session";
+#[cfg(feature = "ssr")]
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// The logged in user of a request.
+#[cfg(feature = "ssr")]
+#[derive(Clone, Debug)]
+pub struct AuthSession {
+    /// Row id of the session, unlike the token this may be passed around.
+    pub id: i64,
+    pub user: User,
+}
+
+#[cfg(feature = "ssr")]
+impl AuthSession {
+    /// Identifies this login to the streamer, one WebRTC connection per session.
+    pub fn signalling_id(&self) -> String {
+        format!("session-{}", self.id)
+    }
+}
+
+#[cfg(feature = "ssr")]
+fn hash_password(password: &str) -> Result<String, ServerFnError> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    let salt = SaltString::generate(&mut OsRng);
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ServerFnError::ServerError(e.to_string()))
+}
+
+#[cfg(feature = "ssr")]
+fn verify_password(password: &str, hash: &str) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+#[cfg(feature = "ssr")]
+fn new_token() -> String {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    repan_protocol::auth::hex(&bytes)
+}
+
+#[cfg(feature = "ssr")]
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token.to_string())
+}
+
+#[cfg(feature = "ssr")]
+fn session_cookie(token: &str, max_age: i64) -> HeaderValue {
+    let secure = if repan_config::get().server.secure_cookie {
+        "; Secure"
+    } else {
+        ""
+    };
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SESSION_COOKIE, token, max_age, secure
+    );
+    HeaderValue::from_str(&cookie).expect("session cookie is plain ascii")
+}
+
+#[cfg(feature = "ssr")]
+pub fn session_from_headers(headers: &HeaderMap) -> Result<Option<AuthSession>, ServerFnError> {
+    let Some(token) = session_token(headers) else {
+        return Ok(None);
+    };
+    let now = chrono::Utc::now().timestamp();
+    let session = get_database()?
+        .session(&token, now)?
+        .map(|(id, user)| AuthSession { id, user });
+    Ok(session)
+}
+
+/// The session of the request a server function is running for, if logged in.
+#[cfg(feature = "ssr")]
+pub async fn current_session() -> Result<Option<AuthSession>, ServerFnError> {
+    let headers: HeaderMap = leptos_axum::extract().await?;
+    session_from_headers(&headers)
+}
+
+#[cfg(feature = "ssr")]
+pub async fn require_session() -> Result<AuthSession, ServerFnError> {
+    current_session()
+        .await?
+        .ok_or_else(|| ServerFnError::ServerError("Not logged in".to_string()))
+}
+
+#[cfg(feature = "ssr")]
+pub async fn require_user() -> Result<User, ServerFnError> {
+    Ok(require_session().await?.user)
+}
+
+/// Rejects requests without a valid session and makes the session available as an extension.
+#[cfg(feature = "ssr")]
+pub async fn require_login(mut req: Request, next: Next) -> Response {
+    use crate::backend::api::ApiError;
+    use axum::http::StatusCode;
+
+    match session_from_headers(req.headers()) {
+        Ok(Some(session)) => {
+            req.extensions_mut().insert(session);
+            next.run(req).await
+        }
+        Ok(None) => ApiError::new(StatusCode::UNAUTHORIZED, "Not logged in").into_response(),
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+#[cfg(feature = "ssr")]
+fn start_session(user: &User) -> Result<(), ServerFnError> {
+    let token = new_token();
+    let lifetime = repan_config::get().limits.session_lifetime_secs();
+    let now = chrono::Utc::now().timestamp();
+    let mut db = get_database()?;
+    db.delete_expired_sessions(now)?;
+    db.add_session(&token, user.id, now + lifetime)?;
+
+    let response = expect_context::<leptos_axum::ResponseOptions>();
+    response.append_header(header::SET_COOKIE, session_cookie(&token, lifetime));
+    Ok(())
+}
+
+#[server(CurrentUser)]
+pub async fn current_user() -> Result<Option<User>, ServerFnError> {
+    Ok(current_session().await?.map(|session| session.user))
+}
+
+/// The first account can be created by anyone and becomes the site admin, after that only site
+/// admins add accounts.
+#[server(Register)]
+pub async fn register(username: String, password: String) -> Result<User, ServerFnError> {
+    let username = username.trim().to_string();
+    if username.is_empty() {
+        return Err(ServerFnError::ServerError("Username is empty".to_string()));
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(ServerFnError::ServerError(format!(
+            "Password needs at least {} characters",
+            MIN_PASSWORD_LEN
+        )));
+    }
+    let logged_in = current_session().await?;
+    let creator = logged_in.as_ref().map(|session| &session.user);
+    let hash = hash_password(&password)?;
+    let user = match get_database()?.register_user(&username, &hash, creator) {
+        Ok(Some(user)) => user,
+        Ok(None) if creator.is_none() => {
+            return Err(ServerFnError::ServerError(
+                "Ask an admin to create your account".to_string(),
+            ))
+        }
+        Ok(None) => {
+            return Err(ServerFnError::ServerError(
+                "Only site admins add accounts".to_string(),
+            ))
+        }
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            return Err(ServerFnError::ServerError(format!("{} is taken", username)))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if logged_in.is_none() {
+        start_session(&user)?;
+        leptos_axum::redirect("/");
+    }
+    Ok(user)
+}
+
+#[server(Login)]
+pub async fn login(username: String, password: String) -> Result<User, ServerFnError> {
+    let credentials = get_database()?.user_credentials(username.trim())?;
+    // Same answer for unknown users and wrong passwords.
+    let user = match credentials {
+        Some((user, hash)) if verify_password(&password, &hash) => user,
+        _ => {
+            return Err(ServerFnError::ServerError(
+                "Wrong username or password".to_string(),
+            ))
+        }
+    };
+    start_session(&user)?;
+    leptos_axum::redirect("/");
+    Ok(user)
+}
+
+#[server(Logout)]
+pub async fn logout() -> Result<(), ServerFnError> {
+    let headers: HeaderMap = leptos_axum::extract().await?;
+    if let Some(token) = session_token(&headers) {
+        get_database()?.delete_session(&token)?;
+    }
+    let response = expect_context::<leptos_axum::ResponseOptions>();
+    response.append_header(header::SET_COOKIE, session_cookie("", 0));
+    leptos_axum::redirect("/login");
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Bands sharing the library. A jam belongs to the band owning the library root it was found
+//! under, and only that band's members get to see it.
+//!
+//! Site admins create bands and assign roots. Band admins manage who is in their band.
+
+use leptos::{prelude::ServerFnError, server};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ssr")]
+use crate::backend::auth::require_user;
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, DatabaseError, User};
+use crate::backend::database::{Band, BandMember, Role};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MyBand {
+    pub band: Band,
+    /// `None` when a site admin looks at a band they aren't a member of.
+    pub role: Option<Role>,
+}
+
+/// Fails unless `user` has at least `min` in the band owning the jam. Jams of other bands are
+/// reported as missing, not as forbidden.
+#[cfg(feature = "ssr")]
+pub fn require_jam_role(user: &User, jam_id: i64, min: Role) -> Result<Role, ServerFnError> {
+    match get_database()?.jam_role(user.id, jam_id)? {
+        Some(role) if role >= min => Ok(role),
+        Some(_) => Err(ServerFnError::ServerError(format!(
+            "Needs the {} role in this band",
+            min.as_str()
+        ))),
+        None => Err(ServerFnError::ServerError("Jam not found".to_string())),
+    }
+}
+
+/// Band admins and site admins may manage a band's members.
+#[cfg(feature = "ssr")]
+fn require_band_admin(user: &User, band_id: i64) -> Result<(), ServerFnError> {
+    if user.admin || get_database()?.band_role(user.id, band_id)? == Some(Role::Admin) {
+        return Ok(());
+    }
+    Err(ServerFnError::ServerError(
+        "Only band admins can do that".to_string(),
+    ))
+}
+
+#[cfg(feature = "ssr")]
+pub fn require_site_admin(user: &User) -> Result<(), ServerFnError> {
+    if user.admin {
+        return Ok(());
+    }
+    Err(ServerFnError::ServerError(
+        "Only site admins can do that".to_string(),
+    ))
+}
+
+#[server(GetBands)]
+pub async fn get_bands() -> Result<Vec<MyBand>, ServerFnError> {
+    let user = require_user().await?;
+    let bands = get_database()?
+        .bands_of(&user)?
+        .into_iter()
+        .map(|(band, role)| MyBand { band, role })
+        .collect();
+    Ok(bands)
+}
+
+#[server(CreateBand)]
+pub async fn create_band(name: String) -> Result<Band, ServerFnError> {
+    let user = require_user().await?;
+    require_site_admin(&user)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServerFnError::ServerError("Band name is empty".to_string()));
+    }
+    Ok(get_database()?.add_band(name)?)
+}
+
+/// Hands every jam under `root` to the band, including ones indexed later.
+#[server(AddBandRoot)]
+pub async fn add_band_root(band_id: i64, root: String) -> Result<Band, ServerFnError> {
+    let user = require_user().await?;
+    require_site_admin(&user)?;
+
+    let mut root = root.trim().to_string();
+    if root.is_empty() {
+        return Err(ServerFnError::ServerError("Root is empty".to_string()));
+    }
+    // Jam paths end in a separator, so this keeps /library/a from claiming /library/ab.
+    if !root.ends_with(std::path::MAIN_SEPARATOR) {
+        root.push(std::path::MAIN_SEPARATOR);
+    }
+    let library = &repan_config::get().library.roots;
+    if !library.is_empty()
+        && !library
+            .iter()
+            .any(|dir| std::path::Path::new(&root).starts_with(dir))
+    {
+        return Err(ServerFnError::ServerError(format!(
+            "{} is outside the library roots of the configuration",
+            root
+        )));
+    }
+
+    let mut db = get_database()?;
+    if db.band(band_id)?.is_none() {
+        return Err(ServerFnError::ServerError("Band not found".to_string()));
+    }
+    match db.add_band_root(band_id, &root) {
+        Ok(()) => (),
+        Err(DatabaseError::AlreadyExists) => {
+            return Err(ServerFnError::ServerError(format!(
+                "{} overlaps with a root that is already assigned",
+                root
+            )))
+        }
+        Err(DatabaseError::Sqlite(e)) => return Err(e.into()),
+    }
+    db.band(band_id)?
+        .ok_or_else(|| ServerFnError::ServerError("Band not found".to_string()))
+}
+
+#[server(GetBandMembers)]
+pub async fn get_band_members(band_id: i64) -> Result<Vec<BandMember>, ServerFnError> {
+    let user = require_user().await?;
+    require_band_admin(&user, band_id)?;
+    Ok(get_database()?.band_members(band_id)?)
+}
+
+/// Adds `username` to the band, or changes their role if they're in it already.
+#[server(SetBandMember)]
+pub async fn set_band_member(
+    band_id: i64,
+    username: String,
+    role: Role,
+) -> Result<Vec<BandMember>, ServerFnError> {
+    let user = require_user().await?;
+    require_band_admin(&user, band_id)?;
+
+    let mut db = get_database()?;
+    if db.band(band_id)?.is_none() {
+        return Err(ServerFnError::ServerError("Band not found".to_string()));
+    }
+    let Some(member) = db.user_by_name(username.trim())? else {
+        return Err(ServerFnError::ServerError(format!(
+            "No user called {}",
+            username.trim()
+        )));
+    };
+    db.set_band_member(band_id, member.id, role)?;
+    Ok(db.band_members(band_id)?)
+}
+
+#[server(RemoveBandMember)]
+pub async fn remove_band_member(
+    band_id: i64,
+    user_id: i64,
+) -> Result<Vec<BandMember>, ServerFnError> {
+    let user = require_user().await?;
+    require_band_admin(&user, band_id)?;
+
+    let mut db = get_database()?;
+    if !db.remove_band_member(band_id, user_id)? {
+        return Err(ServerFnError::ServerError("Not a member".to_string()));
+    }
+    Ok(db.band_members(band_id)?)
+}
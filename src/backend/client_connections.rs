@@ -1,182 +1,656 @@
 #[cfg(feature = "ssr")]
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 #[cfg(feature = "ssr")]
-use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+use axum::extract::ws::{Message, WebSocket};
 #[cfg(feature = "ssr")]
 use axum::extract::WebSocketUpgrade;
 #[cfg(feature = "ssr")]
-use axum::response::IntoResponse;
+use axum::http::{header, HeaderMap, StatusCode};
 #[cfg(feature = "ssr")]
-use futures::stream::{SplitSink, SplitStream};
+use axum::response::{IntoResponse, Response};
 #[cfg(feature = "ssr")]
-use futures::{SinkExt, StreamExt};
+use futures::channel::mpsc::UnboundedSender;
+#[cfg(feature = "ssr")]
+use futures::stream::SplitStream;
+#[cfg(feature = "ssr")]
+use futures::StreamExt;
 use leptos::logging::log;
 use leptos::prelude::guards;
+use leptos::server_fn::{codec::JsonEncoding, BoxedStream, Websocket};
 use leptos::{prelude::ServerFnError, server};
 
 #[cfg(feature = "ssr")]
 use once_cell::sync::Lazy;
+#[cfg(feature = "ssr")]
+use repan_protocol::auth::verify_worker_token;
+#[cfg(feature = "ssr")]
+use repan_protocol::{Capability, Envelope, ServerMsg, StreamerMsg, PROTOCOL_VERSION};
+pub use repan_protocol::{IceSettings, SessionState, SignalEvent, SignalRequest};
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "ssr")]
 use tokio::sync::mpsc::{Receiver, Sender};
+
+#[cfg(feature = "ssr")]
+use crate::backend::auth::{require_session, require_user};
+#[cfg(feature = "ssr")]
+use crate::backend::bands::{require_jam_role, require_site_admin};
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, QueryAmount, QueryTarget, QueryType, Role};
+#[cfg(feature = "ssr")]
+use crate::backend::metrics;
+#[cfg(feature = "ssr")]
+use crate::backend::render::{update_render_job, RenderUpdate};
+#[cfg(feature = "ssr")]
+use crate::backend::streamers::{self, StreamerId};
 #[cfg(feature = "ssr")]
 use tokio::sync::Mutex;
 #[cfg(feature = "ssr")]
-use tokio::time::{sleep_until, Duration, Instant};
+use tokio::time::{sleep_until, Instant};
+
+/// Sessions the streamer answered an offer for, counted for the metrics.
+#[cfg(feature = "ssr")]
+static ANSWERED_SESSIONS: Lazy<Arc<Mutex<HashSet<String>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
 
+/// Sessions whose offer went to the streamer and wasn't answered yet, with when it was sent.
 #[cfg(feature = "ssr")]
-pub static CLIENT_SDP_ANSWERS: Lazy<Arc<Mutex<HashMap<String, String>>>> =
+static PENDING_OFFERS: Lazy<Arc<Mutex<HashMap<String, Instant>>>> =
     Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
 
 #[cfg(feature = "ssr")]
-pub static GST_MSG_SINK: Lazy<Arc<Mutex<Option<SplitSink<WebSocket, Message>>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(None)));
+type SignalSender = UnboundedSender<Result<SignalEvent, ServerFnError>>;
 
-//#[derive(Serialize, Deserialize)]
-//#[serde(rename_all = "lowercase", untagged)]
-//pub enum ClientMessage {
-//    Payload { gst_msg: GstJsonMsg, id: String },
-//    Test { test: String },
-//}
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ClientMessage {
-    pub gst_msg: GstJsonMsg,
-    id: String,
+/// Sessions that lost their streamer and weren't asked to renegotiate yet.
+#[cfg(feature = "ssr")]
+static LOST_SESSIONS: Lazy<Arc<Mutex<HashSet<String>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
+
+/// Sessions whose signalling channel closed, with when. A browser that switched networks
+/// reconnects within `RECONNECT_GRACE` and restarts ICE, so its pipeline is kept until then.
+#[cfg(feature = "ssr")]
+static DETACHED_SESSIONS: Lazy<Arc<Mutex<HashMap<String, Instant>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+#[cfg(feature = "ssr")]
+const RECONNECT_GRACE: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Every session with an open signalling channel, or one that closed moments ago, for the admin
+/// view.
+#[cfg(feature = "ssr")]
+static SESSIONS: Lazy<Arc<Mutex<HashMap<String, SessionInfo>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// The open signalling channel of every session.
+#[cfg(feature = "ssr")]
+static SIGNAL_CHANNELS: Lazy<Arc<Mutex<HashMap<String, SignalSender>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user: String,
+    pub state: SessionState,
+    /// Name of the streamer running the session, none while it has no streamer.
+    pub streamer: Option<String>,
+    pub opened_at: i64,
+    /// When the state last changed.
+    pub changed_at: i64,
 }
 
-// JSON messages we communicate with
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase", untagged)]
-pub enum GstJsonMsg {
-    Ice {
-        candidate: String,
-        #[serde(rename = "sdpMLineIndex")]
-        sdp_mline_index: u32,
-    },
-    Sdp {
-        sdp: String,
-        #[serde(rename = "type")]
-        r#type: String,
-    },
-    ChangeJam {
-        path: String,
-        date: String,
-        tracks: Vec<String>,
-    },
-}
-
-#[server(GetGstSdpAnswer)]
-pub async fn get_gst_sdp_answer(id: String) -> Result<String, ServerFnError> {
-    let mut sdp: Option<String> = None;
-    loop {
-        let sdp_map = CLIENT_SDP_ANSWERS.clone();
-        let guard = sdp_map.lock().await;
-        if guard.contains_key(&id) {
-            sdp = Some(guard.get(&id).unwrap().to_owned());
-            drop(guard);
-            break;
+/// How the browser should gather candidates, the same way the streamer does. Members only, it
+/// may hold the TURN credentials.
+#[server(GetIceServers)]
+pub async fn get_ice_servers() -> Result<IceSettings, ServerFnError> {
+    require_user().await?;
+    Ok(repan_config::get().ice.settings())
+}
+
+/// Opens this session's signalling channel. The browser sends its offer and ICE candidates over
+/// it, the server pushes the streamer's answer and candidates back, or why the offer failed.
+/// Opening a second channel for the same session closes the first. A browser that lost its
+/// channel opens a new one and keeps its session, pipeline included.
+#[server(name = OpenSignalling, protocol = Websocket<JsonEncoding, JsonEncoding>)]
+pub async fn open_signalling(
+    requests: BoxedStream<SignalRequest, ServerFnError>,
+) -> Result<BoxedStream<SignalEvent, ServerFnError>, ServerFnError> {
+    let session = require_session().await?;
+    let id = session.signalling_id();
+    let now = chrono::Utc::now().timestamp();
+    // A browser coming back after losing its channel keeps its session.
+    DETACHED_SESSIONS.lock().await.remove(&id);
+    SESSIONS
+        .lock()
+        .await
+        .entry(id.clone())
+        .or_insert_with(|| SessionInfo {
+            id: id.clone(),
+            user: session.user.username,
+            state: SessionState::Negotiating,
+            streamer: None,
+            opened_at: now,
+            changed_at: now,
+        });
+    let (events, rx) = futures::channel::mpsc::unbounded();
+    SIGNAL_CHANNELS
+        .lock()
+        .await
+        .insert(id.clone(), events.clone());
+    tokio::spawn(handle_signal_requests(id, requests, events));
+    Ok(rx.into())
+}
+
+#[cfg(feature = "ssr")]
+async fn handle_signal_requests(
+    id: String,
+    mut requests: BoxedStream<SignalRequest, ServerFnError>,
+    events: SignalSender,
+) {
+    while let Some(request) = requests.next().await {
+        let result = match request {
+            Ok(SignalRequest::Offer { sdp }) => send_offer(&id, sdp).await,
+            Ok(SignalRequest::IceRestart { sdp }) => send_ice_restart(&id, sdp).await,
+            Ok(SignalRequest::Ice {
+                candidate,
+                sdp_mline_index,
+            }) => {
+                let ice = ServerMsg::Ice {
+                    candidate,
+                    sdp_mline_index,
+                };
+                send_to_streamer(id.clone(), ice).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let reason = e.to_string();
+            let _ = events.unbounded_send(Ok(SignalEvent::Failed { reason }));
         }
-        sleep_until(Instant::now() + Duration::from_millis(100)).await;
     }
-    Ok(sdp.unwrap())
+    log!("Signalling channel of {} closed", id);
+    close_signalling(&id, &events).await;
 }
-#[server(PostClientSdpOffer)]
-pub async fn post_client_sdp_offer(id: String, offer: String) -> Result<(), ServerFnError> {
-    log!("Sent from server");
-    let sdp_json = serde_json::from_str::<GstJsonMsg>(offer.as_str()).unwrap();
-    let msg = ClientMessage {
-        id,
-        gst_msg: sdp_json,
-    };
-    let msg = serde_json::to_string(&msg).unwrap();
-
-    let msg = Message::Text(Utf8Bytes::from(msg));
-    let gst_sink = GST_MSG_SINK.clone();
-    let mut guard = gst_sink.lock().await;
-    let guard = guard.as_mut().unwrap();
 
-    let _ = guard.send(msg).await;
-    drop(guard);
+#[cfg(feature = "ssr")]
+async fn send_offer(id: &str, sdp: String) -> Result<(), ServerFnError> {
+    LOST_SESSIONS.lock().await.remove(id);
+    // A new offer replaces the session's connection, it counts once the streamer answers.
+    {
+        let mut answered = ANSWERED_SESSIONS.lock().await;
+        answered.remove(id);
+        metrics::webrtc_sessions(answered.len());
+    }
+    set_session_state(id, SessionState::Negotiating).await;
+    streamers::assign(id, Capability::Stream).await?;
+    if let Err(e) = send_to_streamer(id.to_string(), ServerMsg::Offer { sdp }).await {
+        streamers::release(id).await;
+        return Err(e);
+    }
+    await_answer(id).await;
+    Ok(())
+}
 
+/// Only the streamer running the session's pipeline can restart it. Without one the browser
+/// renegotiates from scratch, as when its streamer went away.
+#[cfg(feature = "ssr")]
+async fn send_ice_restart(id: &str, sdp: String) -> Result<(), ServerFnError> {
+    if streamers::name_of_owner(id).await.is_none() {
+        LOST_SESSIONS.lock().await.insert(id.to_string());
+        let reason = "The session's streamer is gone".to_string();
+        push_signal(id, SignalEvent::Lost { reason }).await;
+        offer_renegotiation().await;
+        return Ok(());
+    }
+    set_session_state(id, SessionState::Negotiating).await;
+    send_to_streamer(id.to_string(), ServerMsg::IceRestart { sdp }).await?;
+    await_answer(id).await;
     Ok(())
 }
-#[server(PostICECandidateToServer)]
-pub async fn post_ice_candidate_to_server(
-    id: String,
-    candidate: String,
-    mline: u32,
-) -> Result<(), ServerFnError> {
-    log!("Attempting to send ice from server");
-    let ice = GstJsonMsg::Ice {
-        candidate,
-        sdp_mline_index: mline,
+
+/// Waits for the streamer's answer to the offer just sent for `id`.
+#[cfg(feature = "ssr")]
+async fn await_answer(id: &str) {
+    let sent = Instant::now();
+    let mut pending = PENDING_OFFERS.lock().await;
+    pending.insert(id.to_string(), sent);
+    metrics::pending_offers(pending.len());
+    tokio::spawn(expire_offer(id.to_string(), sent));
+}
+
+/// Fails the offer sent at `sent` if the streamer hasn't answered it in time.
+#[cfg(feature = "ssr")]
+async fn expire_offer(id: String, sent: Instant) {
+    let timeout = repan_config::get().server.answer_timeout();
+    sleep_until(sent + timeout).await;
+
+    let mut pending = PENDING_OFFERS.lock().await;
+    // Answered, or replaced by a newer offer.
+    if pending.get(&id) != Some(&sent) {
+        return;
+    }
+    pending.remove(&id);
+    metrics::pending_offers(pending.len());
+    drop(pending);
+
+    let reason = format!(
+        "The streamer didn't answer within {} seconds",
+        timeout.as_secs()
+    );
+    push_signal(&id, SignalEvent::Failed { reason }).await;
+}
+
+/// Sends `event` to the browser of session `id`, if it still has a signalling channel open.
+#[cfg(feature = "ssr")]
+async fn push_signal(id: &str, event: SignalEvent) {
+    if let Some(events) = SIGNAL_CHANNELS.lock().await.get(id) {
+        let _ = events.unbounded_send(Ok(event));
+    }
+}
+
+/// Detaches the session from its closed channel, unless a newer channel took over already. The
+/// session ends if the browser doesn't come back within `RECONNECT_GRACE`.
+#[cfg(feature = "ssr")]
+async fn close_signalling(id: &str, events: &SignalSender) {
+    let mut channels = SIGNAL_CHANNELS.lock().await;
+    if !channels
+        .get(id)
+        .is_some_and(|current| current.same_receiver(events))
+    {
+        return;
+    }
+    channels.remove(id);
+    drop(channels);
+
+    let closed = Instant::now();
+    DETACHED_SESSIONS
+        .lock()
+        .await
+        .insert(id.to_string(), closed);
+    tokio::spawn(end_detached_session(id.to_string(), closed));
+}
+
+/// Forgets the session detached at `closed` and stops its pipeline, unless its browser came
+/// back in time.
+#[cfg(feature = "ssr")]
+async fn end_detached_session(id: String, closed: Instant) {
+    sleep_until(closed + RECONNECT_GRACE).await;
+
+    let mut detached = DETACHED_SESSIONS.lock().await;
+    if detached.get(&id) != Some(&closed) {
+        return;
+    }
+    detached.remove(&id);
+    drop(detached);
+
+    log!("Session {} ended", id);
+    let id = id.as_str();
+    SESSIONS.lock().await.remove(id);
+    // Fails when the session has no streamer, then there is no pipeline to stop either.
+    let hangup = ServerMsg::Hangup {
+        reason: "The browser closed the session".to_string(),
     };
-    let msg = ClientMessage { gst_msg: ice, id };
-    let msg = serde_json::to_string(&msg).unwrap();
+    let _ = send_to_streamer(id.to_string(), hangup).await;
+    streamers::release(id).await;
+    LOST_SESSIONS.lock().await.remove(id);
+    forget_connection(id).await;
+}
 
-    let msg = Message::Text(Utf8Bytes::from(msg));
-    let gst_sink = GST_MSG_SINK.clone();
-    let mut guard = gst_sink.lock().await;
-    let guard = guard.as_mut().unwrap();
+/// Drops the session's pending offer and its answered mark, its peer connection is gone.
+#[cfg(feature = "ssr")]
+async fn forget_connection(id: &str) {
+    let mut pending = PENDING_OFFERS.lock().await;
+    pending.remove(id);
+    metrics::pending_offers(pending.len());
+    drop(pending);
+    let mut answered = ANSWERED_SESSIONS.lock().await;
+    answered.remove(id);
+    metrics::webrtc_sessions(answered.len());
+}
 
-    let _ = guard.send(msg).await;
-    drop(guard);
+#[cfg(feature = "ssr")]
+async fn set_session_state(id: &str, state: SessionState) {
+    if let Some(session) = SESSIONS.lock().await.get_mut(id) {
+        if session.state != state {
+            session.state = state;
+            session.changed_at = chrono::Utc::now().timestamp();
+        }
+    }
+}
 
-    log!("Sent Ice candidate from client");
+/// A streamer reported a new state for one of its sessions. Closed ones give up their place on
+/// it, the browser is told why its stream stopped.
+#[cfg(feature = "ssr")]
+async fn session_state_changed(id: &str, state: SessionState) {
+    log!("Session {} is {:?}", id, state);
+    set_session_state(id, state).await;
+    if state != SessionState::Closed {
+        return;
+    }
+    streamers::release(id).await;
+    forget_connection(id).await;
+    let reason = "The stream stopped, reload the page to listen again".to_string();
+    push_signal(id, SignalEvent::Closed { reason }).await;
+}
+
+/// Live sessions of every user, oldest first.
+#[cfg(feature = "ssr")]
+pub async fn list_sessions() -> Vec<SessionInfo> {
+    let mut sessions: Vec<SessionInfo> = SESSIONS.lock().await.values().cloned().collect();
+    for session in &mut sessions {
+        session.streamer = streamers::name_of_owner(&session.id).await;
+    }
+    sessions.sort_by_key(|session| session.opened_at);
+    sessions
+}
+
+/// Listening sessions of every user, so only for site admins.
+#[server(GetSessions)]
+pub async fn get_sessions() -> Result<Vec<SessionInfo>, ServerFnError> {
+    require_site_admin(&require_user().await?)?;
+    Ok(list_sessions().await)
+}
+
+/// Points this session's stream at another jam. The paths are looked up here, so a client can
+/// only ever play jams of its own bands.
+#[server(ChangeJam)]
+pub async fn change_jam(jam_id: i64) -> Result<(), ServerFnError> {
+    let session = require_session().await?;
+    require_jam_role(&session.user, jam_id, Role::Viewer)?;
+
+    let mut db = get_database()?;
+    let one = || QueryAmount::One(QueryType::FromID(jam_id));
+    let not_found = || ServerFnError::ServerError("Jam not found".to_string());
+    let path = db
+        .query(session.user.id, QueryTarget::Path, one())?
+        .into_iter()
+        .next()
+        .ok_or_else(not_found)?
+        .data;
+    let date = db
+        .query(session.user.id, QueryTarget::Date, one())?
+        .into_iter()
+        .next()
+        .ok_or_else(not_found)?
+        .data;
+    let tracks = db
+        .query(
+            session.user.id,
+            QueryTarget::Track(jam_id),
+            QueryAmount::All,
+        )?
+        .into_iter()
+        .map(|track| track.data)
+        .collect();
+
+    send_to_streamer(
+        session.signalling_id(),
+        ServerMsg::ChangeJam { path, date, tracks },
+    )
+    .await
+}
+
+/// Sends a message for the client or job `id` to the streamer it was assigned to.
+#[cfg(feature = "ssr")]
+pub async fn send_to_streamer(id: String, msg: ServerMsg) -> Result<(), ServerFnError> {
+    let kind = msg.kind();
+    let text = serde_json::to_string(&Envelope::new(id.as_str(), msg))?;
+
+    streamers::send(&id, text).await?;
+    metrics::streamer_message("out", kind);
     Ok(())
 }
 
+/// Accepts a streamer that speaks our protocol version under the name its token was signed for,
+/// and turns away any other. False if it was turned away.
 #[cfg(feature = "ssr")]
-pub async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
-    log!("Connected!");
-    ws.on_failed_upgrade(|e| eprintln!("{:?}", e))
-        .on_upgrade(handle_socket)
+async fn hello(
+    streamer: StreamerId,
+    worker: &str,
+    protocol: u32,
+    name: String,
+    capacity: usize,
+    capabilities: Vec<Capability>,
+) -> bool {
+    let refusal = if protocol != PROTOCOL_VERSION {
+        Some(format!(
+            "The server speaks protocol version {}, not {}",
+            PROTOCOL_VERSION, protocol
+        ))
+    } else if name != worker {
+        Some(format!("The token was signed for {}, not {}", worker, name))
+    } else {
+        None
+    };
+    let reply = match &refusal {
+        Some(reason) => ServerMsg::Rejected {
+            reason: reason.clone(),
+        },
+        None => ServerMsg::Welcome {
+            protocol,
+            ice: repan_config::get().ice.settings(),
+        },
+    };
+    let kind = reply.kind();
+    match serde_json::to_string(&Envelope::new(name.as_str(), reply)) {
+        Ok(text) => match streamers::send_to(streamer, text).await {
+            Ok(()) => metrics::streamer_message("out", kind),
+            Err(e) => log!("Couldn't answer streamer {}: {:?}", streamer, e),
+        },
+        Err(e) => log!(
+            "Couldn't serialize answer to streamer {}: {:?}",
+            streamer,
+            e
+        ),
+    }
+    if let Some(reason) = refusal {
+        log!("Turned away streamer {} ({}): {}", streamer, name, reason);
+        streamers::remove(streamer).await;
+        return false;
+    }
+    streamers::introduce(streamer, name, capacity, capabilities).await;
+    offer_renegotiation().await;
+    true
 }
+
+/// The bearer token a streamer connects with.
 #[cfg(feature = "ssr")]
-async fn handle_socket(mut socket: WebSocket) {
-    use axum::extract::ws::Utf8Bytes;
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
 
-    socket.send(Message::Text(Utf8Bytes::from_static("Hello!")));
+/// Upgrades connections from streamers only. Whatever holds the socket answers listeners'
+/// offers, so anything without a token signed with `streamer.secret` is turned away.
+#[cfg(feature = "ssr")]
+pub async fn ws_handler(ws: WebSocketUpgrade, headers: HeaderMap) -> Response {
+    let secret = &repan_config::get().streamer.secret;
+    let now = chrono::Utc::now().timestamp();
+    let worker = match bearer_token(&headers) {
+        _ if secret.is_empty() => Err("no_secret"),
+        None => Err("no_token"),
+        Some(token) => verify_worker_token(secret, token, now).map_err(|e| {
+            log!("Refused a streamer token: {}", e);
+            e.as_str()
+        }),
+    };
+    let worker = match worker {
+        Ok(worker) => worker,
+        Err(reason) => {
+            log!("Refused a connection to /ws: {}", reason);
+            metrics::streamer_refused(reason);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    log!("Streamer {} connected", worker);
+    ws.on_failed_upgrade(|e| eprintln!("{:?}", e))
+        .on_upgrade(move |socket| handle_socket(socket, worker))
+}
+#[cfg(feature = "ssr")]
+async fn handle_socket(socket: WebSocket, worker: String) {
     log!("Socket being handled");
-    let (mut gst_sink, mut gst_receiver) = socket.split();
-    let client_messager = GST_MSG_SINK.clone();
-    *client_messager.lock().await = Some(gst_sink);
+    let (gst_sink, gst_receiver) = socket.split();
+    let streamer = streamers::add(gst_sink).await;
 
-    tokio::spawn(handle_streamer_messages(gst_receiver));
+    tokio::spawn(handle_streamer_messages(streamer, worker, gst_receiver));
 }
 #[cfg(feature = "ssr")]
-async fn handle_streamer_messages(mut rx: SplitStream<WebSocket>) {
+async fn handle_streamer_messages(
+    streamer: StreamerId,
+    worker: String,
+    mut rx: SplitStream<WebSocket>,
+) {
+    // Nothing but its Hello counts until the streamer was welcomed.
+    let mut welcomed = false;
     loop {
         match rx.next().await {
-            Some(Ok(msg)) => {
-                log!("From Gstreamer: {:?}", msg.to_text().unwrap());
-                let text = msg.to_text().unwrap();
-                let json: Result<ClientMessage, serde_json::Error> = serde_json::from_str(text);
-
-                match json {
-                    Ok(msg) => match msg.gst_msg {
-                        GstJsonMsg::Sdp { r#type, sdp } => {
-                            if r#type.ne("answer") {
-                                log!("Got sdp, but it was offer");
-                                continue;
-                            }
-
-                            let client_connections = CLIENT_SDP_ANSWERS.clone();
-                            let mut guard = client_connections.lock().await;
-                            let _ = guard.insert(msg.id.clone(), sdp);
+            Some(Ok(Message::Text(text))) => {
+                if !streamers::seen(streamer).await {
+                    log!("Dropping message of lost streamer {}", streamer);
+                    break;
+                }
+                log!("From Gstreamer: {:?}", text);
+                let msg = match serde_json::from_str::<Envelope<StreamerMsg>>(text.as_str()) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log!("Couldn't parse message of streamer {}: {}", streamer, e);
+                        continue;
+                    }
+                };
+                metrics::streamer_message("in", msg.msg.kind());
+                if !matches!(msg.msg, StreamerMsg::Hello { .. }) {
+                    if !welcomed {
+                        log!(
+                            "Dropping {} of streamer {}, it didn't say hello yet",
+                            msg.msg.kind(),
+                            streamer
+                        );
+                        continue;
+                    }
+                    // A streamer only speaks for the sessions and renders it was given.
+                    if !streamers::owns(streamer, &msg.id).await {
+                        log!(
+                            "Ignoring {} of streamer {} for {}, which isn't on it",
+                            msg.msg.kind(),
+                            streamer,
+                            msg.id
+                        );
+                        continue;
+                    }
+                }
+                match msg.msg {
+                    StreamerMsg::Answer { sdp } => {
+                        let mut pending = PENDING_OFFERS.lock().await;
+                        if pending.remove(&msg.id).is_none() {
+                            log!("Answer for {} came too late", msg.id);
+                            continue;
+                        }
+                        metrics::pending_offers(pending.len());
+                        drop(pending);
+
+                        let mut answered = ANSWERED_SESSIONS.lock().await;
+                        answered.insert(msg.id.clone());
+                        metrics::webrtc_sessions(answered.len());
+                        drop(answered);
+                        push_signal(&msg.id, SignalEvent::Answer { sdp }).await;
+                    }
+                    StreamerMsg::OfferFailed { reason } => {
+                        let mut pending = PENDING_OFFERS.lock().await;
+                        if pending.remove(&msg.id).is_none() {
+                            log!("Failure of {}'s offer came too late", msg.id);
+                            continue;
                         }
-                        _ => (),
-                    },
-                    _ => log!("Not json message"),
+                        metrics::pending_offers(pending.len());
+                        drop(pending);
+                        push_signal(&msg.id, SignalEvent::Failed { reason }).await;
+                    }
+                    StreamerMsg::Ice {
+                        candidate,
+                        sdp_mline_index,
+                    } => {
+                        let ice = SignalEvent::Ice {
+                            candidate,
+                            sdp_mline_index,
+                        };
+                        push_signal(&msg.id, ice).await;
+                    }
+                    StreamerMsg::RenderProgress { progress } => {
+                        update_render_job(&msg.id, RenderUpdate::Progress(progress)).await
+                    }
+                    StreamerMsg::RenderDone { .. } => {
+                        streamers::release(&msg.id).await;
+                        update_render_job(&msg.id, RenderUpdate::Done).await
+                    }
+                    StreamerMsg::RenderFailed { error } => {
+                        streamers::release(&msg.id).await;
+                        update_render_job(&msg.id, RenderUpdate::Failed(error)).await
+                    }
+                    StreamerMsg::Hello {
+                        protocol,
+                        name,
+                        capacity,
+                        capabilities,
+                    } => {
+                        if !hello(streamer, &worker, protocol, name, capacity, capabilities).await {
+                            break;
+                        }
+                        welcomed = true;
+                    }
+                    StreamerMsg::Session { state } => session_state_changed(&msg.id, state).await,
+                }
+            }
+            // Pongs and pings, they only show that the streamer is alive.
+            Some(Ok(_)) => {
+                if !streamers::seen(streamer).await {
+                    break;
                 }
             }
             Some(Err(e)) => log!("{:?}", e.to_string()),
             None => {
                 log!("Socket ended");
+                streamer_lost(streamer).await;
                 break;
             }
-            _ => println!("Server message not picked up"),
         }
     }
 }
+
+/// Drops a streamer that disconnected or stopped answering. Its renders fail, its listeners are
+/// told to renegotiate once another streamer has room.
+#[cfg(feature = "ssr")]
+pub async fn streamer_lost(streamer: StreamerId) {
+    let reason = "The streamer disconnected".to_string();
+    for (client, needs) in streamers::remove(streamer).await {
+        forget_connection(&client).await;
+        match needs {
+            // Detached sessions are told once their browser is back and restarts ICE.
+            Capability::Stream => {
+                LOST_SESSIONS.lock().await.insert(client.clone());
+                set_session_state(&client, SessionState::Negotiating).await;
+                let reason = reason.clone();
+                push_signal(&client, SignalEvent::Lost { reason }).await;
+            }
+            Capability::Render => {
+                update_render_job(&client, RenderUpdate::Failed(reason.clone())).await;
+            }
+        }
+    }
+    offer_renegotiation().await;
+}
+
+/// Asks the sessions that lost their streamer for new offers, if any streamer has room.
+#[cfg(feature = "ssr")]
+async fn offer_renegotiation() {
+    if !streamers::has_room(Capability::Stream).await {
+        return;
+    }
+    let lost: Vec<String> = LOST_SESSIONS.lock().await.drain().collect();
+    for client in lost {
+        push_signal(&client, SignalEvent::Renegotiate).await;
+    }
+}
@@ -1,4 +1,6 @@
 #[cfg(feature = "ssr")]
+use rusqlite::trace::TraceEventCodes;
+#[cfg(feature = "ssr")]
 use rusqlite::types::FromSql;
 #[cfg(feature = "ssr")]
 use rusqlite::{Connection, Error, Result, Row, ToSql};
@@ -6,14 +8,11 @@ use serde::de::value;
 #[cfg(feature = "ssr")]
 use std::fmt;
 #[cfg(feature = "ssr")]
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use leptos::logging::log;
 use serde::{Deserialize, Serialize};
 
-const DB_PATH: &str = "/home/joffy/Work/repan_stream/";
-//const DB_PATH: &str = "D:\\dev\\audio-stream\\";
-
 //#[derive(Serialize, Deserialize, Debug)]
 //pub struct Jam
 //{
@@ -27,17 +26,161 @@ pub enum QueryResult {
     Id(i64),
     Path(String),
     Date(String),
-    Tracks(Vec<String>)
-
+    Tracks(Vec<String>),
 }
 
-
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct JamQueryResult<T> {
     pub id: i64,
     pub data: T,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Marker {
+    pub id: i64,
+    pub jam_id: i64,
+    pub name: String,
+    pub position_ms: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrackMix {
+    pub track: String,
+    pub volume: f64,
+    pub pan: f64,
+    pub mute: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MixPreset {
+    pub id: i64,
+    pub jam_id: i64,
+    pub name: String,
+    pub tracks: Vec<TrackMix>,
+}
+
+/// How a user likes to hear every jam, kept between sessions. Stored as JSON, settings added
+/// later default for rows written before them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserMixSettings {
+    #[serde(default)]
+    pub transpose: repan_protocol::Transpose,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    /// Site admins create bands and hand out library roots.
+    pub admin: bool,
+}
+
+/// What a member may do with a band's jams. Ordered, every role can do what the ones below it can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Browse, listen, download and render mixdowns
+    Viewer,
+    /// Also upload, and edit markers and presets
+    Editor,
+    /// Also manage the members
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Editor, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+    pub fn parse(role: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|r| r.as_str() == role)
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl FromSql for Role {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        let role = value.as_str()?;
+        Role::parse(role).ok_or_else(|| rusqlite::types::FromSqlError::Other(role.into()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Band {
+    pub id: i64,
+    pub name: String,
+    /// Library directories whose jams belong to this band
+    pub roots: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandMember {
+    pub user_id: i64,
+    pub username: String,
+    pub role: Role,
+}
+
+/// A link that lets someone without an account listen to one jam.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: i64,
+    pub jam_id: i64,
+    pub jam_date: String,
+    /// The mix the player is locked to, unity gain for every track when `None`
+    pub preset_id: Option<i64>,
+    pub preset_name: Option<String>,
+    pub allow_download: bool,
+    pub created_by: i64,
+    /// Unix timestamps
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub revoked: bool,
+    pub views: i64,
+}
+
+/// A track of a jam resolved to the file it was recorded to.
+#[cfg(feature = "ssr")]
+#[derive(Clone, Debug)]
+pub struct StemFile {
+    pub track_id: i64,
+    pub jam_id: i64,
+    pub name: String,
+    pub date: String,
+    pub path: PathBuf,
+}
+
+#[cfg(feature = "ssr")]
+impl StemFile {
+    /// Stems are stored as `{jam path}{track}-{date}.wav`, the same layout the streamer loads.
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        let dir: String = row.get(2)?;
+        let name: String = row.get(3)?;
+        let date: String = row.get(4)?;
+        Ok(StemFile {
+            track_id: row.get(0)?,
+            jam_id: row.get(1)?,
+            path: PathBuf::from(format!("{}{}-{}.wav", dir, name, date)),
+            name,
+            date,
+        })
+    }
+    pub fn file_name(&self) -> String {
+        format!("{}-{}.wav", self.name, self.date)
+    }
+}
+
 #[derive(Debug)]
 pub enum QueryTarget {
     Date,
@@ -49,7 +192,7 @@ pub enum QueryType {
     FromDate(String),
     FromPath(String),
     FromID(i64),
-    FromStem(String)
+    FromStem(String),
 }
 
 #[derive(Debug)]
@@ -65,6 +208,7 @@ pub enum QueryAmount {
 #[cfg(feature = "ssr")]
 pub enum DatabaseError {
     AlreadyExists,
+    Sqlite(rusqlite::Error),
 }
 
 #[cfg(feature = "ssr")]
@@ -73,6 +217,7 @@ impl fmt::Display for DatabaseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             DatabaseError::AlreadyExists => write!(f, "Item already exists"),
+            DatabaseError::Sqlite(e) => write!(f, "{}", e),
         }
     }
 }
@@ -82,9 +227,9 @@ impl std::error::Error for DatabaseError {}
 
 #[cfg(feature = "ssr")]
 impl From<rusqlite::Error> for DatabaseError {
-#[cfg(feature = "ssr")]
-    fn from(_: rusqlite::Error) -> Self {
-        todo!()
+    #[cfg(feature = "ssr")]
+    fn from(e: rusqlite::Error) -> Self {
+        DatabaseError::Sqlite(e)
     }
 }
 #[cfg(feature = "ssr")]
@@ -123,6 +268,14 @@ impl FromRow for (String, String) {
     }
 }
 
+/// Ids of the jams user `?1` may see: the ones under a library root of one of their bands.
+/// Roots never nest, so a jam belongs to at most one band.
+#[cfg(feature = "ssr")]
+const VISIBLE_JAMS: &str = "SELECT jams.id FROM jams
+    JOIN band_roots ON SUBSTR(jams.path, 1, LENGTH(band_roots.root)) = band_roots.root
+    JOIN band_members ON band_members.band_id = band_roots.band_id
+    WHERE band_members.user_id = ?1";
+
 #[cfg(feature = "ssr")]
 pub struct Database {
     conn: Connection,
@@ -141,7 +294,6 @@ impl Database {
     //    let mut stmt = self.conn.prepare(&sql)?;
     //    stmt.query(params)
 
-
     //    Ok(())
     //}
     //#[cfg(feature = "ssr")]
@@ -150,76 +302,81 @@ impl Database {
     //}
 
     #[cfg(feature = "ssr")]
+    /// Only ever returns jams and tracks `user_id` has access to.
     pub fn query<T>(
         &mut self,
+        user_id: i64,
         target: QueryTarget,
         amount: QueryAmount,
     ) -> Result<Vec<JamQueryResult<T>>, rusqlite::Error>
     where
-        T: FromSql
+        T: FromSql,
     {
         let (sql, params): (String, Vec<rusqlite::types::Value>) = match (target, amount)
         {
-            (QueryTarget::Date, QueryAmount::All) => 
+            (QueryTarget::Date, QueryAmount::All) =>
             (
-                "SELECT date, id FROM jams".to_string(),
+                format!("SELECT date, id FROM jams WHERE id IN ({VISIBLE_JAMS})"),
                 vec![],
             ),
             (QueryTarget::Date, QueryAmount::One(QueryType::FromPath(path))) =>
             (
-                "SELECT date, id FROM jams WHERE path = ?1".to_string(),
+                format!("SELECT date, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND path = ?2"),
                 vec![path.into()],
             ),
             (QueryTarget::Date, QueryAmount::One(QueryType::FromID(id))) =>
             (
-                "SELECT date, id FROM jams WHERE id = ?1".to_string(),
+                format!("SELECT date, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND id = ?2"),
                 vec![id.into()],
             ),
             (QueryTarget::Date, QueryAmount::Month(yearmonth)) =>
             (
-                "SELECT date, id FROM jams WHERE SUBSTR(date, 1, 4) = ?1;".to_string(),
+                format!("SELECT date, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND SUBSTR(date, 1, 4) = ?2;"),
                 vec![yearmonth.into()],
             ),
             (QueryTarget::Date, QueryAmount::MonthDays(yearmonth)) =>
             (
-                "SELECT CAST(SUBSTR(date, 5, 2) AS INTEGER) AS day, id FROM jams WHERE SUBSTR(date, 1, 4) = ?1;".to_string(),
+                format!("SELECT CAST(SUBSTR(date, 5, 2) AS INTEGER) AS day, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND SUBSTR(date, 1, 4) = ?2;"),
                 vec![yearmonth.into()],
             ),
             (QueryTarget::Date, QueryAmount::Day(yearmonthday)) =>
             (
-                "SELECT date, id FROM jams WHERE SUBSTR(date, 1, 6) = ?1;".to_string(),
+                format!("SELECT date, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND SUBSTR(date, 1, 6) = ?2;"),
                 vec![yearmonthday.into()],
             ),
             (QueryTarget::Path, QueryAmount::All) =>
             (
-                "SELECT path, id FROM jams".to_string(),
+                format!("SELECT path, id FROM jams WHERE id IN ({VISIBLE_JAMS})"),
                 vec![],
             ),
             (QueryTarget::Path, QueryAmount::One(QueryType::FromDate(date))) =>
             (
-                "SELECT path, id FROM jams WHERE date = ?1".to_string(),
+                format!("SELECT path, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND date = ?2"),
                 vec![date.into()],
             ),
             (QueryTarget::Path, QueryAmount::One(QueryType::FromID(id))) =>
             (
-                "SELECT path, id FROM jams WHERE id = ?1".to_string(),
+                format!("SELECT path, id FROM jams WHERE id IN ({VISIBLE_JAMS}) AND id = ?2"),
                 vec![id.into()],
             ),
+            // Ordered, `SetTrack` counts tracks in the order the streamer and the browser got them.
             (QueryTarget::Track(jam_id), QueryAmount::All) =>
             (
-                "SELECT track, id FROM tracks WHERE jam_id = ?1".to_string(),
+                format!("SELECT track, id FROM tracks WHERE jam_id IN ({VISIBLE_JAMS}) AND jam_id = ?2 ORDER BY id"),
                 vec![jam_id.into()],
             ),
             (QueryTarget::Track(jam_id), QueryAmount::One(QueryType::FromStem(stem))) =>
             (
-                "SELECT track, id FROM tracks WHERE jam_id = ?1 AND track = ?2".to_string(),
+                format!("SELECT track, id FROM tracks WHERE jam_id IN ({VISIBLE_JAMS}) AND jam_id = ?2 AND track = ?3"),
                 vec![jam_id.into(), stem.into()],
             ),
             _ => unimplemented!("Not a valid query"),
         };
 
         let mut stmt = self.conn.prepare(&sql)?;
-        let params_refs: Vec<&dyn ToSql> = params.iter().map(|v| v as &dyn ToSql).collect();
+        let params_refs: Vec<&dyn ToSql> = std::iter::once(&user_id as &dyn ToSql)
+            .chain(params.iter().map(|v| v as &dyn ToSql))
+            .collect();
         let rows = stmt.query_map(&*params_refs, |row| {
             Ok(JamQueryResult {
                 data: row.get(0)?,
@@ -229,15 +386,703 @@ impl Database {
         let results: Result<Vec<_>, _> = rows.collect();
         results
     }
+
+    /// Creates the tables the web server owns. `jams` and `tracks` are created by jamdb.
+    #[cfg(feature = "ssr")]
+    pub fn create_tables(&mut self) -> Result<(), rusqlite::Error> {
+        self.conn.execute_batch(
+            "BEGIN;
+            CREATE TABLE IF NOT EXISTS markers
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              jam_id INTEGER NOT NULL,
+              name TEXT NOT NULL,
+              position_ms INTEGER NOT NULL,
+              FOREIGN KEY(jam_id) REFERENCES jams(id));
+            CREATE TABLE IF NOT EXISTS mix_presets
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              jam_id INTEGER NOT NULL,
+              name TEXT NOT NULL,
+              settings TEXT NOT NULL,
+              FOREIGN KEY(jam_id) REFERENCES jams(id));
+            CREATE TABLE IF NOT EXISTS users
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              username TEXT NOT NULL UNIQUE,
+              password_hash TEXT NOT NULL,
+              admin INTEGER NOT NULL DEFAULT 0);
+            CREATE TABLE IF NOT EXISTS user_mix_settings
+            ( user_id INTEGER PRIMARY KEY,
+              settings TEXT NOT NULL,
+              FOREIGN KEY(user_id) REFERENCES users(id));
+            CREATE TABLE IF NOT EXISTS sessions
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              token TEXT NOT NULL UNIQUE,
+              user_id INTEGER NOT NULL,
+              expires INTEGER NOT NULL,
+              FOREIGN KEY(user_id) REFERENCES users(id));
+            CREATE TABLE IF NOT EXISTS bands
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              name TEXT NOT NULL UNIQUE);
+            CREATE TABLE IF NOT EXISTS band_members
+            ( band_id INTEGER NOT NULL,
+              user_id INTEGER NOT NULL,
+              role TEXT NOT NULL,
+              PRIMARY KEY(band_id, user_id),
+              FOREIGN KEY(band_id) REFERENCES bands(id),
+              FOREIGN KEY(user_id) REFERENCES users(id));
+            CREATE TABLE IF NOT EXISTS band_roots
+            ( root TEXT PRIMARY KEY,
+              band_id INTEGER NOT NULL,
+              FOREIGN KEY(band_id) REFERENCES bands(id));
+            CREATE TABLE IF NOT EXISTS share_links
+            ( id INTEGER PRIMARY KEY AUTOINCREMENT,
+              jam_id INTEGER NOT NULL,
+              preset_id INTEGER,
+              allow_download INTEGER NOT NULL,
+              created_by INTEGER NOT NULL,
+              created_at INTEGER NOT NULL,
+              expires_at INTEGER NOT NULL,
+              revoked INTEGER NOT NULL DEFAULT 0,
+              views INTEGER NOT NULL DEFAULT 0,
+              FOREIGN KEY(jam_id) REFERENCES jams(id),
+              FOREIGN KEY(preset_id) REFERENCES mix_presets(id),
+              FOREIGN KEY(created_by) REFERENCES users(id));
+            CREATE TABLE IF NOT EXISTS secrets
+            ( name TEXT PRIMARY KEY,
+              value TEXT NOT NULL);
+            COMMIT;",
+        )
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn user_count(&mut self) -> Result<i64, rusqlite::Error> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+    }
+
+    /// Fails with a constraint violation if the username is taken.
+    #[cfg(feature = "ssr")]
+    pub fn add_user(
+        &mut self,
+        username: &str,
+        password_hash: &str,
+        admin: bool,
+    ) -> Result<User, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO users (username, password_hash, admin) VALUES (?1, ?2, ?3)",
+            rusqlite::params![username, password_hash, admin],
+        )?;
+        Ok(User {
+            id: self.conn.last_insert_rowid(),
+            username: username.to_string(),
+            admin,
+        })
+    }
+
+    /// Adds an account if `creator` may, and makes it the site admin if it is the first one.
+    /// Nobody needs to be logged in for the first account, once an admin exists only admins add
+    /// accounts. Checked and inserted in one transaction, so two people can't both become the
+    /// first admin. None when `creator` may not add accounts.
+    #[cfg(feature = "ssr")]
+    pub fn register_user(
+        &mut self,
+        username: &str,
+        password_hash: &str,
+        creator: Option<&User>,
+    ) -> Result<Option<User>, rusqlite::Error> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let (users, admins): (i64, i64) = tx.query_row(
+            "SELECT COUNT(*), COUNT(*) FILTER (WHERE admin) FROM users",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let allowed = match creator {
+            None => users == 0,
+            Some(creator) => admins == 0 || creator.admin,
+        };
+        if !allowed {
+            return Ok(None);
+        }
+        let admin = users == 0;
+        tx.execute(
+            "INSERT INTO users (username, password_hash, admin) VALUES (?1, ?2, ?3)",
+            rusqlite::params![username, password_hash, admin],
+        )?;
+        let id = tx.last_insert_rowid();
+        tx.commit()?;
+        Ok(Some(User {
+            id,
+            username: username.to_string(),
+            admin,
+        }))
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn user_by_name(&mut self, username: &str) -> Result<Option<User>, rusqlite::Error> {
+        Ok(self.user_credentials(username)?.map(|(user, _)| user))
+    }
+
+    /// The user and their password hash.
+    #[cfg(feature = "ssr")]
+    pub fn user_credentials(
+        &mut self,
+        username: &str,
+    ) -> Result<Option<(User, String)>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, username, admin, password_hash FROM users WHERE username = ?1")?;
+        let mut rows = stmt.query_map([username], |row| {
+            Ok((
+                User {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    admin: row.get(2)?,
+                },
+                row.get(3)?,
+            ))
+        })?;
+        rows.next().transpose()
+    }
+
+    /// Returns the session id, which unlike the token is safe to show around.
+    #[cfg(feature = "ssr")]
+    pub fn add_session(
+        &mut self,
+        token: &str,
+        user_id: i64,
+        expires: i64,
+    ) -> Result<i64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO sessions (token, user_id, expires) VALUES (?1, ?2, ?3)",
+            rusqlite::params![token, user_id, expires],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// The session id and user of a token that hasn't expired by `now`.
+    #[cfg(feature = "ssr")]
+    pub fn session(
+        &mut self,
+        token: &str,
+        now: i64,
+    ) -> Result<Option<(i64, User)>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT sessions.id, users.id, users.username, users.admin
+             FROM sessions JOIN users ON users.id = sessions.user_id
+             WHERE sessions.token = ?1 AND sessions.expires > ?2",
+        )?;
+        let mut rows = stmt.query_map(rusqlite::params![token, now], |row| {
+            Ok((
+                row.get(0)?,
+                User {
+                    id: row.get(1)?,
+                    username: row.get(2)?,
+                    admin: row.get(3)?,
+                },
+            ))
+        })?;
+        rows.next().transpose()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn delete_session(&mut self, token: &str) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM sessions WHERE token = ?1", [token])?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn delete_expired_sessions(&mut self, now: i64) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM sessions WHERE expires <= ?1", [now])?;
+        Ok(())
+    }
+
+    /// The role `user_id` has in the band owning the jam, `None` if they can't see it at all.
+    #[cfg(feature = "ssr")]
+    pub fn jam_role(&mut self, user_id: i64, jam_id: i64) -> Result<Option<Role>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT band_members.role FROM jams
+             JOIN band_roots ON SUBSTR(jams.path, 1, LENGTH(band_roots.root)) = band_roots.root
+             JOIN band_members ON band_members.band_id = band_roots.band_id
+             WHERE band_members.user_id = ?1 AND jams.id = ?2",
+        )?;
+        let mut rows = stmt.query_map([user_id, jam_id], |row| row.get(0))?;
+        rows.next().transpose()
+    }
+
+    /// Only looks at the jam of that date in `dir`, other bands may have recorded that day too.
+    #[cfg(feature = "ssr")]
+    pub fn track_exists(
+        &mut self,
+        date: &str,
+        dir: &str,
+        track: &str,
+    ) -> Result<bool, rusqlite::Error> {
+        jamdb::track_exists(&self.conn, date, dir, track)
+    }
+
+    /// Indexes a single stem the same way a library scan would, creating its jam if needed.
+    /// Returns the jam id.
+    #[cfg(feature = "ssr")]
+    pub fn add_stem(
+        &mut self,
+        date: &str,
+        dir: &str,
+        track: &str,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        jamdb::add_track(&mut self.conn, date, dir, track)
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn stem_files(
+        &mut self,
+        user_id: i64,
+        jam_id: i64,
+    ) -> Result<Vec<StemFile>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT tracks.id, jams.id, jams.path, tracks.track, jams.date
+             FROM tracks JOIN jams ON jams.id = tracks.jam_id
+             WHERE jams.id IN ({VISIBLE_JAMS}) AND jams.id = ?2 ORDER BY tracks.track"
+        ))?;
+        let rows = stmt.query_map([user_id, jam_id], StemFile::from_row)?;
+        rows.collect()
+    }
+
+    /// Not filtered by band, only for callers that checked a share link instead.
+    #[cfg(feature = "ssr")]
+    pub fn shared_stem_files(&mut self, jam_id: i64) -> Result<Vec<StemFile>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT tracks.id, jams.id, jams.path, tracks.track, jams.date
+             FROM tracks JOIN jams ON jams.id = tracks.jam_id
+             WHERE jams.id = ?1 ORDER BY tracks.track",
+        )?;
+        let rows = stmt.query_map([jam_id], StemFile::from_row)?;
+        rows.collect()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn stem_file(
+        &mut self,
+        user_id: i64,
+        track_id: i64,
+    ) -> Result<Option<StemFile>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT tracks.id, jams.id, jams.path, tracks.track, jams.date
+             FROM tracks JOIN jams ON jams.id = tracks.jam_id
+             WHERE jams.id IN ({VISIBLE_JAMS}) AND tracks.id = ?2"
+        ))?;
+        let mut rows = stmt.query_map([user_id, track_id], StemFile::from_row)?;
+        rows.next().transpose()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn markers(&mut self, jam_id: i64) -> Result<Vec<Marker>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, jam_id, name, position_ms FROM markers WHERE jam_id = ?1 ORDER BY position_ms",
+        )?;
+        let rows = stmt.query_map([jam_id], |row| {
+            Ok(Marker {
+                id: row.get(0)?,
+                jam_id: row.get(1)?,
+                name: row.get(2)?,
+                position_ms: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn add_marker(
+        &mut self,
+        jam_id: i64,
+        name: &str,
+        position_ms: i64,
+    ) -> Result<Marker, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO markers (jam_id, name, position_ms) VALUES (?1, ?2, ?3)",
+            rusqlite::params![jam_id, name, position_ms],
+        )?;
+        Ok(Marker {
+            id: self.conn.last_insert_rowid(),
+            jam_id,
+            name: name.to_string(),
+            position_ms,
+        })
+    }
+
+    /// Returns false if no marker with that id belonged to the jam.
+    #[cfg(feature = "ssr")]
+    pub fn delete_marker(&mut self, jam_id: i64, id: i64) -> Result<bool, rusqlite::Error> {
+        let deleted = self.conn.execute(
+            "DELETE FROM markers WHERE id = ?1 AND jam_id = ?2",
+            [id, jam_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn mix_presets(&mut self, jam_id: i64) -> Result<Vec<MixPreset>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, jam_id, name, settings FROM mix_presets WHERE jam_id = ?1")?;
+        let rows = stmt.query_map([jam_id], |row| {
+            let settings: String = row.get(3)?;
+            let tracks = serde_json::from_str(&settings).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    3,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?;
+            Ok(MixPreset {
+                id: row.get(0)?,
+                jam_id: row.get(1)?,
+                name: row.get(2)?,
+                tracks,
+            })
+        })?;
+        rows.collect()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn add_mix_preset(
+        &mut self,
+        jam_id: i64,
+        name: &str,
+        tracks: Vec<TrackMix>,
+    ) -> Result<MixPreset, rusqlite::Error> {
+        let settings = serde_json::to_string(&tracks)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.conn.execute(
+            "INSERT INTO mix_presets (jam_id, name, settings) VALUES (?1, ?2, ?3)",
+            rusqlite::params![jam_id, name, settings],
+        )?;
+        Ok(MixPreset {
+            id: self.conn.last_insert_rowid(),
+            jam_id,
+            name: name.to_string(),
+            tracks,
+        })
+    }
+
+    /// Returns false if no preset with that id belonged to the jam.
+    #[cfg(feature = "ssr")]
+    pub fn delete_mix_preset(&mut self, jam_id: i64, id: i64) -> Result<bool, rusqlite::Error> {
+        let deleted = self.conn.execute(
+            "DELETE FROM mix_presets WHERE id = ?1 AND jam_id = ?2",
+            [id, jam_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// The defaults until the user saved any.
+    #[cfg(feature = "ssr")]
+    pub fn mix_settings(&mut self, user_id: i64) -> Result<UserMixSettings, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT settings FROM user_mix_settings WHERE user_id = ?1")?;
+        let mut rows = stmt.query_map([user_id], |row| row.get::<_, String>(0))?;
+        let Some(settings) = rows.next().transpose()? else {
+            return Ok(UserMixSettings::default());
+        };
+        serde_json::from_str(&settings).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn set_mix_settings(
+        &mut self,
+        user_id: i64,
+        settings: &UserMixSettings,
+    ) -> Result<(), rusqlite::Error> {
+        let settings = serde_json::to_string(settings)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.conn.execute(
+            "INSERT INTO user_mix_settings (user_id, settings) VALUES (?1, ?2)
+             ON CONFLICT(user_id) DO UPDATE SET settings = excluded.settings",
+            rusqlite::params![user_id, settings],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn add_band(&mut self, name: &str) -> Result<Band, rusqlite::Error> {
+        self.conn
+            .execute("INSERT INTO bands (name) VALUES (?1)", [name])?;
+        Ok(Band {
+            id: self.conn.last_insert_rowid(),
+            name: name.to_string(),
+            roots: vec![],
+        })
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn band(&mut self, band_id: i64) -> Result<Option<Band>, rusqlite::Error> {
+        let name = {
+            let mut stmt = self.conn.prepare("SELECT name FROM bands WHERE id = ?1")?;
+            let mut rows = stmt.query_map([band_id], |row| row.get::<_, String>(0))?;
+            match rows.next().transpose()? {
+                Some(name) => name,
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(Band {
+            id: band_id,
+            name,
+            roots: self.band_roots(band_id)?,
+        }))
+    }
+
+    /// Every band for site admins, otherwise the bands `user` is a member of. With the user's
+    /// role, `None` for bands a site admin isn't in.
+    #[cfg(feature = "ssr")]
+    pub fn bands_of(&mut self, user: &User) -> Result<Vec<(Band, Option<Role>)>, rusqlite::Error> {
+        let rows: Vec<(i64, String, Option<Role>)> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT bands.id, bands.name, band_members.role FROM bands
+                 LEFT JOIN band_members
+                   ON band_members.band_id = bands.id AND band_members.user_id = ?1
+                 WHERE band_members.user_id IS NOT NULL OR ?2
+                 ORDER BY bands.name",
+            )?;
+            let rows = stmt.query_map(rusqlite::params![user.id, user.admin], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+            rows.collect::<Result<_, _>>()?
+        };
+        rows.into_iter()
+            .map(|(id, name, role)| {
+                Ok((
+                    Band {
+                        id,
+                        name,
+                        roots: self.band_roots(id)?,
+                    },
+                    role,
+                ))
+            })
+            .collect()
+    }
+
+    /// The role of `user_id` in a band, site admins aren't special here.
+    #[cfg(feature = "ssr")]
+    pub fn band_role(
+        &mut self,
+        user_id: i64,
+        band_id: i64,
+    ) -> Result<Option<Role>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT role FROM band_members WHERE user_id = ?1 AND band_id = ?2")?;
+        let mut rows = stmt.query_map([user_id, band_id], |row| row.get(0))?;
+        rows.next().transpose()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn band_roots(&mut self, band_id: i64) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root FROM band_roots WHERE band_id = ?1 ORDER BY root")?;
+        let rows = stmt.query_map([band_id], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn all_band_roots(&mut self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root FROM band_roots ORDER BY root")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Assigns a library root to a band. Fails with `AlreadyExists` if it overlaps with a root
+    /// that's already assigned, nested roots would make a jam belong to two bands.
+    #[cfg(feature = "ssr")]
+    pub fn add_band_root(&mut self, band_id: i64, root: &str) -> Result<(), DatabaseError> {
+        let overlapping: bool = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM band_roots
+             WHERE SUBSTR(?1, 1, LENGTH(root)) = root OR SUBSTR(root, 1, LENGTH(?1)) = ?1)",
+            [root],
+            |row| row.get(0),
+        )?;
+        if overlapping {
+            return Err(DatabaseError::AlreadyExists);
+        }
+        self.conn.execute(
+            "INSERT INTO band_roots (root, band_id) VALUES (?1, ?2)",
+            rusqlite::params![root, band_id],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn band_members(&mut self, band_id: i64) -> Result<Vec<BandMember>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT users.id, users.username, band_members.role
+             FROM band_members JOIN users ON users.id = band_members.user_id
+             WHERE band_members.band_id = ?1 ORDER BY users.username",
+        )?;
+        let rows = stmt.query_map([band_id], |row| {
+            Ok(BandMember {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                role: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Adds the member or changes their role.
+    #[cfg(feature = "ssr")]
+    pub fn set_band_member(
+        &mut self,
+        band_id: i64,
+        user_id: i64,
+        role: Role,
+    ) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO band_members (band_id, user_id, role) VALUES (?1, ?2, ?3)
+             ON CONFLICT(band_id, user_id) DO UPDATE SET role = excluded.role",
+            rusqlite::params![band_id, user_id, role],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn remove_band_member(
+        &mut self,
+        band_id: i64,
+        user_id: i64,
+    ) -> Result<bool, rusqlite::Error> {
+        let deleted = self.conn.execute(
+            "DELETE FROM band_members WHERE band_id = ?1 AND user_id = ?2",
+            [band_id, user_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// The secret stored under `name`, created with `generate` the first time it is asked for.
+    #[cfg(feature = "ssr")]
+    pub fn secret(
+        &mut self,
+        name: &str,
+        generate: impl FnOnce() -> String,
+    ) -> Result<String, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO secrets (name, value) VALUES (?1, ?2)",
+            [name, &generate()],
+        )?;
+        self.conn
+            .query_row("SELECT value FROM secrets WHERE name = ?1", [name], |row| {
+                row.get(0)
+            })
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn add_share_link(
+        &mut self,
+        jam_id: i64,
+        preset_id: Option<i64>,
+        allow_download: bool,
+        created_by: i64,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO share_links
+             (jam_id, preset_id, allow_download, created_by, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                jam_id,
+                preset_id,
+                allow_download,
+                created_by,
+                created_at,
+                expires_at
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn share_link(&mut self, id: i64) -> Result<Option<ShareLink>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{SHARE_LINK_SELECT} WHERE share_links.id = ?1"))?;
+        let mut rows = stmt.query_map([id], ShareLink::from_row)?;
+        rows.next().transpose()
+    }
+
+    /// Links `user_id` created, newest first.
+    #[cfg(feature = "ssr")]
+    pub fn share_links_by(&mut self, user_id: i64) -> Result<Vec<ShareLink>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{SHARE_LINK_SELECT} WHERE share_links.created_by = ?1 ORDER BY share_links.id DESC"
+        ))?;
+        let rows = stmt.query_map([user_id], ShareLink::from_row)?;
+        rows.collect()
+    }
+
+    /// Returns false if there was no such link.
+    #[cfg(feature = "ssr")]
+    pub fn revoke_share_link(&mut self, id: i64) -> Result<bool, rusqlite::Error> {
+        let revoked = self
+            .conn
+            .execute("UPDATE share_links SET revoked = 1 WHERE id = ?1", [id])?;
+        Ok(revoked > 0)
+    }
+
+    #[cfg(feature = "ssr")]
+    pub fn count_share_view(&mut self, id: i64) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE share_links SET views = views + 1 WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
 }
+
+#[cfg(feature = "ssr")]
+const SHARE_LINK_SELECT: &str = "SELECT share_links.id, share_links.jam_id, jams.date,
+    share_links.preset_id, mix_presets.name, share_links.allow_download, share_links.created_by,
+    share_links.created_at, share_links.expires_at, share_links.revoked, share_links.views
+    FROM share_links JOIN jams ON jams.id = share_links.jam_id
+    LEFT JOIN mix_presets ON mix_presets.id = share_links.preset_id";
+
+#[cfg(feature = "ssr")]
+impl ShareLink {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(ShareLink {
+            id: row.get(0)?,
+            jam_id: row.get(1)?,
+            jam_date: row.get(2)?,
+            preset_id: row.get(3)?,
+            preset_name: row.get(4)?,
+            allow_download: row.get(5)?,
+            created_by: row.get(6)?,
+            created_at: row.get(7)?,
+            expires_at: row.get(8)?,
+            revoked: row.get(9)?,
+            views: row.get(10)?,
+        })
+    }
+}
+
 #[cfg(feature = "ssr")]
 pub fn get_database() -> Result<Database, Error> {
-    let full_path = DB_PATH.to_string() + "jams.db";
-    let db_path = Path::new(&full_path);
+    let db_path = &repan_config::get().database.path;
 
     if !db_path.is_file() {
         return Err(rusqlite::Error::InvalidPath(db_path.to_path_buf()));
     }
-    let conn = Connection::open(&full_path)?;
+    let mut conn = Connection::open(db_path)?;
+    conn.trace_v2(
+        TraceEventCodes::SQLITE_TRACE_PROFILE,
+        Some(crate::backend::metrics::record_query),
+    );
     Ok(Database { conn })
 }
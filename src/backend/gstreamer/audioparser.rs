@@ -18,8 +18,8 @@ pub fn create_audiopipeline(tracks: &Vec<String>, user_id: &str) -> Result<gst::
     //let convert = create_and_link_elements("audioconvert", &mixer, &pipeline);
     //let resample = create_and_link_elements("audioresample", &convert, &pipeline);
     let caps = gst::Caps::builder("audio/x-raw")
-        .field("channels", &2)
-        .field("rate", &44100)
+        .field("channels", &(repan_config::get().audio.channels as i32))
+        .field("rate", &(repan_config::get().audio.sample_rate as i32))
         .build();
 
     let capsfilter = gst::ElementFactory::make("capsfilter")
@@ -64,7 +64,10 @@ pub fn create_audiopipeline(tracks: &Vec<String>, user_id: &str) -> Result<gst::
 
 fn create_audiostem(track: &str, pipeline: &gst::Pipeline, mixer: &gst::Element) -> Result<(), glib::error::BoolError>
 {
-    let track_withprefix = "/audio/".to_string() + track;
+    let track_withprefix = match repan_config::get().library.roots.first() {
+        Some(root) => root.join(track).to_string_lossy().to_string(),
+        None => track.to_string(),
+    };
 
     let sound = gst::ElementFactory::make("filesrc")
         .name(track)
@@ -77,8 +80,8 @@ fn create_audiostem(track: &str, pipeline: &gst::Pipeline, mixer: &gst::Element)
     let audioconvert = create_and_link_elements("audioconvert", &wavparser, pipeline)?;
     let audioresample = create_and_link_elements("audioresample", &audioconvert, pipeline)?;
     let caps = gst::Caps::builder("audio/x-raw")
-        .field("channels", &2)
-        .field("rate", &44100)
+        .field("channels", &(repan_config::get().audio.channels as i32))
+        .field("rate", &(repan_config::get().audio.sample_rate as i32))
         .build();
 
     let capsfilter = gst::ElementFactory::make("capsfilter")
@@ -105,7 +105,7 @@ fn load_project(audiotracks: &Vec<String>, projectname: &String, user_id: &Strin
 
     println!("Loaded project: {}", projectname);
     //Should probably not be done here
-    let mut rtmptarget = "rtmp://rtmp:1935/stream/".to_string();
+    let mut rtmptarget = repan_config::get().streamer.rtmp_url.clone();
     rtmptarget.push_str(user_id);
     println!("rtmp: {}", rtmptarget);
     let pipeline = audioparser::create_audiopipeline(audiotracks, &rtmptarget);
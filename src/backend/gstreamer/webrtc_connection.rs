@@ -5,8 +5,6 @@ use gstreamer_webrtc::{WebRTCSessionDescription, gst::Message};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, unbounded_channel};
 
-const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
-
 // JSON messages we communicate with
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -34,7 +32,9 @@ pub fn connect_user_test()
 
     let webrtcbin = pipeline.by_name("webrtcbin").unwrap();
 
-    webrtcbin.set_property_from_str("stun-server", STUN_SERVER);
+    if let Some(stun_server) = repan_config::get().ice.settings().gst_stun_server() {
+        webrtcbin.set_property_from_str("stun-server", &stun_server);
+    }
     webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
 
     let bus = pipeline.bus().unwrap();
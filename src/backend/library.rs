@@ -0,0 +1,95 @@
+//! Rescans of the library, for recordings copied in by hand instead of uploaded.
+//!
+//! A scan walks the library roots of the configuration, or every band root when none are
+//! configured, with the same rules as jamdb and adds the stems the database doesn't know yet.
+//! Its progress shows up in `/metrics`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::backend::database::get_database;
+use crate::backend::metrics;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ScanStatus {
+    pub running: bool,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub stems_added: u64,
+    pub error: Option<String>,
+}
+
+/// The running or last finished scan.
+static SCAN: Lazy<Mutex<ScanStatus>> = Lazy::new(|| Mutex::new(ScanStatus::default()));
+
+pub fn scan_status() -> ScanStatus {
+    SCAN.lock().unwrap().clone()
+}
+
+/// Starts a scan in the background. `None` if one is running already.
+pub fn start_scan() -> Option<ScanStatus> {
+    let mut scan = SCAN.lock().unwrap();
+    if scan.running {
+        return None;
+    }
+    *scan = ScanStatus {
+        running: true,
+        started_at: Some(chrono::Utc::now().timestamp()),
+        ..ScanStatus::default()
+    };
+    metrics::scan_running(true);
+
+    tokio::spawn(async {
+        // Should the scan panic after all, it still has to end rather than stay running forever.
+        let result = match tokio::task::spawn_blocking(scan_library).await {
+            Ok(result) => result,
+            Err(e) => Err(format!("Scan aborted: {}", e)),
+        };
+        let mut scan = SCAN.lock().unwrap();
+        scan.running = false;
+        scan.finished_at = Some(chrono::Utc::now().timestamp());
+        match result {
+            Ok(added) => scan.stems_added = added,
+            Err(e) => scan.error = Some(e),
+        }
+        metrics::scan_running(false);
+    });
+    Some(scan.clone())
+}
+
+fn scan_library() -> Result<u64, String> {
+    let db_error = |e: rusqlite::Error| format!("Database error: {}", e);
+    let mut db = get_database().map_err(db_error)?;
+
+    let configured = &repan_config::get().library.roots;
+    let roots: Vec<PathBuf> = if configured.is_empty() {
+        db.all_band_roots()
+            .map_err(db_error)?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
+    } else {
+        configured.clone()
+    };
+    let roots: Vec<&Path> = roots.iter().map(PathBuf::as_path).collect();
+
+    let mut added = 0;
+    for jam in jamdb::get_all_jams_from_dirs(&roots) {
+        for track in &jam.tracks {
+            if db
+                .track_exists(&jam.date, &jam.path, track)
+                .map_err(db_error)?
+            {
+                continue;
+            }
+            db.add_stem(&jam.date, &jam.path, track)
+                .map_err(|e| format!("Couldn't add {} of {}: {}", track, jam.date, e))?;
+            added += 1;
+            metrics::scan_stem_added();
+        }
+    }
+    Ok(added)
+}
@@ -0,0 +1,179 @@
+//! Prometheus metrics of the web server, scraped from `/metrics` on `server.metrics_listen`.
+//!
+//! Everything is recorded through the `metrics` facade, jamdb counts its scans the same way. The
+//! streamer exports the GStreamer side from its own endpoint.
+
+use std::{collections::HashMap, io, net::SocketAddr, sync::OnceLock};
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use rusqlite::trace::TraceEvent;
+
+use crate::backend::render::{RenderJob, RenderState};
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Database statements are fast, the buckets are mostly below a millisecond.
+const QUERY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0,
+];
+
+/// Installs the recorder. Metrics recorded before this are lost, so it runs first thing.
+pub fn install() -> Result<(), BuildError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("repan_db_query_seconds".to_string()),
+            &QUERY_BUCKETS,
+        )?
+        .install_recorder()?;
+    let _ = HANDLE.set(handle);
+
+    describe_gauge!(
+        "repan_webrtc_sessions",
+        "Sessions the streamer answered an offer for"
+    );
+    describe_gauge!(
+        "repan_sdp_offers_pending",
+        "Offers sent to the streamer that have no answer yet"
+    );
+    describe_gauge!("repan_streamers", "Connected streamer workers");
+    describe_gauge!(
+        "repan_streamer_clients",
+        "Listener sessions and render jobs assigned to each streamer"
+    );
+    describe_counter!(
+        "repan_streamer_messages_total",
+        "Messages exchanged with the streamer, by direction and kind"
+    );
+    describe_counter!(
+        "repan_streamer_refused_total",
+        "Connections to /ws turned away for a missing or bad token, by reason"
+    );
+    describe_gauge!("repan_render_jobs", "Render jobs by state");
+    describe_histogram!(
+        "repan_db_query_seconds",
+        "Time spent in database statements, by operation and table"
+    );
+    describe_gauge!("repan_scan_running", "1 while a library scan runs");
+    describe_counter!(
+        "repan_scan_directories_total",
+        "Directories visited by library scans"
+    );
+    describe_counter!("repan_scan_stems_total", "Stems found by library scans");
+    describe_counter!(
+        "repan_scan_stems_added_total",
+        "Stems library scans added to the database"
+    );
+    Ok(())
+}
+
+/// Serves `/metrics` on a listener of its own. It has no login, so it isn't on the site.
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    let app = Router::new().route("/metrics", get(metrics_handler));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Metrics on http://{}/metrics", addr);
+    axum::serve(listener, app).await
+}
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    match HANDLE.get() {
+        Some(handle) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            handle.render(),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub fn webrtc_sessions(count: usize) {
+    gauge!("repan_webrtc_sessions").set(count as f64);
+}
+
+pub fn pending_offers(count: usize) {
+    gauge!("repan_sdp_offers_pending").set(count as f64);
+}
+
+pub fn streamers(count: usize) {
+    gauge!("repan_streamers").set(count as f64);
+}
+
+pub fn streamer_clients(streamer: &str, count: usize) {
+    gauge!("repan_streamer_clients", "streamer" => streamer.to_string()).set(count as f64);
+}
+
+pub fn streamer_message(direction: &'static str, kind: &'static str) {
+    counter!("repan_streamer_messages_total", "direction" => direction, "kind" => kind)
+        .increment(1);
+}
+
+pub fn streamer_refused(reason: &'static str) {
+    counter!("repan_streamer_refused_total", "reason" => reason).increment(1);
+}
+
+pub fn render_jobs(jobs: &HashMap<String, RenderJob>) {
+    for state in [
+        RenderState::Queued,
+        RenderState::Rendering,
+        RenderState::Done,
+        RenderState::Failed,
+    ] {
+        let count = jobs
+            .values()
+            .filter(|job| job.status.state == state)
+            .count();
+        gauge!("repan_render_jobs", "state" => state.as_str()).set(count as f64);
+    }
+}
+
+pub fn scan_running(running: bool) {
+    gauge!("repan_scan_running").set(if running { 1.0 } else { 0.0 });
+}
+
+pub fn scan_stem_added() {
+    counter!("repan_scan_stems_added_total").increment(1);
+}
+
+/// Installed as rusqlite's trace callback on every connection, for profile events.
+pub fn record_query(event: TraceEvent<'_>) {
+    let TraceEvent::Profile(statement, duration) = event else {
+        return;
+    };
+    let (operation, table) = statement_label(&statement.sql());
+    histogram!("repan_db_query_seconds", "operation" => operation, "table" => table)
+        .record(duration.as_secs_f64());
+}
+
+/// `("select", "jams")` for `SELECT ... FROM jams ...`. Raw SQL would make a series per
+/// statement, and the ones built with `format!` even more.
+fn statement_label(sql: &str) -> (String, String) {
+    let words: Vec<String> = sql
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect();
+    let operation = words.first().cloned().unwrap_or_default();
+    let before_table = match operation.as_str() {
+        "select" | "delete" => "from",
+        "insert" | "replace" => "into",
+        "update" => "update",
+        "create" => "exists",
+        _ => return (operation, String::new()),
+    };
+    let table = words
+        .iter()
+        .skip_while(|word| *word != before_table)
+        .nth(1)
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric() && c != '_')
+                .to_string()
+        })
+        .unwrap_or_default();
+    (operation, table)
+}
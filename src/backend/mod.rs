@@ -1,4 +1,21 @@
 //pub mod gstreamer;
+#[cfg(feature = "ssr")]
+pub mod api;
+pub mod auth;
+pub mod bands;
 pub mod client_connections;
 pub mod database;
+#[cfg(feature = "ssr")]
+pub mod library;
+#[cfg(feature = "ssr")]
+pub mod metrics;
+pub mod render;
 pub mod serverfunctions;
+pub mod share;
+#[cfg(feature = "ssr")]
+pub mod stemserve;
+#[cfg(feature = "ssr")]
+pub mod streamers;
+pub mod upload;
+#[cfg(feature = "ssr")]
+pub mod zipstream;
@@ -0,0 +1,424 @@
+//! Offline mixdowns. The server keeps track of the jobs, the streamer does the rendering with
+//! the same mixer graph it streams with and reports progress back over the `/ws` socket.
+
+#[cfg(feature = "ssr")]
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "ssr")]
+use leptos::logging::log;
+use leptos::{prelude::ServerFnError, server};
+#[cfg(feature = "ssr")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "ssr")]
+use repan_protocol::{Capability, ServerMsg};
+pub use repan_protocol::{RenderFormat, RenderStem};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use tokio::sync::Mutex;
+
+#[cfg(feature = "ssr")]
+use crate::backend::auth::require_user;
+#[cfg(feature = "ssr")]
+use crate::backend::bands::require_jam_role;
+#[cfg(feature = "ssr")]
+use crate::backend::client_connections::send_to_streamer;
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, ShareLink, StemFile, User};
+use crate::backend::database::{Role, TrackMix};
+#[cfg(feature = "ssr")]
+use crate::backend::metrics;
+#[cfg(feature = "ssr")]
+use crate::backend::share::share_mix;
+#[cfg(feature = "ssr")]
+use crate::backend::streamers;
+
+/// Browsers play it everywhere and it's small enough to stream to listeners.
+#[cfg(feature = "ssr")]
+const SHARE_FORMAT: RenderFormat = RenderFormat::Mp3;
+#[cfg(feature = "ssr")]
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[cfg(feature = "ssr")]
+pub static RENDER_JOBS: Lazy<Arc<Mutex<HashMap<String, RenderJob>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderState {
+    Queued,
+    Rendering,
+    Done,
+    Failed,
+}
+
+impl RenderState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RenderState::Queued => "queued",
+            RenderState::Rendering => "rendering",
+            RenderState::Done => "done",
+            RenderState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RenderStatus {
+    pub id: String,
+    pub jam_id: i64,
+    pub format: RenderFormat,
+    pub state: RenderState,
+    /// 0.0 to 1.0
+    pub progress: f64,
+    pub error: Option<String>,
+    pub download_url: Option<String>,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(Clone, Debug)]
+pub struct RenderJob {
+    /// Only whoever started a render gets to see it.
+    pub user_id: i64,
+    pub status: RenderStatus,
+    pub output: PathBuf,
+    /// Where the file can be fetched once done, handed out in `status`.
+    pub download_url: String,
+    /// When the job last changed or was asked for. Jobs left alone long enough are swept,
+    /// together with their file.
+    pub touched: Instant,
+}
+
+/// Tracks missing from `mix` play at unity gain, centered.
+#[cfg(feature = "ssr")]
+pub async fn start_render_job(
+    user: &User,
+    jam_id: i64,
+    format: RenderFormat,
+    mix: Vec<TrackMix>,
+) -> Result<RenderStatus, ServerFnError> {
+    require_jam_role(user, jam_id, Role::Viewer)?;
+    let stems = get_database()?.stem_files(user.id, jam_id)?;
+    if stems.is_empty() {
+        return Err(ServerFnError::ServerError("Jam has no tracks".to_string()));
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let download_url = format!("/api/v1/renders/{}/file", id);
+    let job = new_job(id, user.id, jam_id, &stems[0].date, format, download_url).await?;
+    let status = job.status.clone();
+    RENDER_JOBS
+        .lock()
+        .await
+        .insert(status.id.clone(), job.clone());
+    dispatch_render(job, render_stems(stems, &mix)).await?;
+    Ok(status)
+}
+
+/// Mix of a share link that doesn't allow downloads, so its stems never leave the server. The
+/// render is kept under the link's id and reused by everyone opening it.
+#[cfg(feature = "ssr")]
+pub async fn start_share_render(
+    link: &ShareLink,
+    token: &str,
+) -> Result<RenderStatus, ServerFnError> {
+    let id = share_render_id(link.id);
+    let mut jobs = RENDER_JOBS.lock().await;
+    if let Some(job) = jobs
+        .get(&id)
+        .filter(|j| j.status.state != RenderState::Failed)
+    {
+        return Ok(job.status.clone());
+    }
+    let mut db = get_database()?;
+    let stems = db.shared_stem_files(link.jam_id)?;
+    if stems.is_empty() {
+        return Err(ServerFnError::ServerError("Jam has no tracks".to_string()));
+    }
+    let mix = share_mix(&mut db, link)?;
+    drop(db);
+    let download_url = format!("/api/v1/shared/{}/mix", token);
+    let job = new_job(
+        id.clone(),
+        link.created_by,
+        link.jam_id,
+        &stems[0].date,
+        SHARE_FORMAT,
+        download_url,
+    )
+    .await?;
+    let status = job.status.clone();
+    // Inserted before letting go of the lock so concurrent visitors share one render.
+    jobs.insert(id, job.clone());
+    drop(jobs);
+    dispatch_render(job, render_stems(stems, &mix)).await?;
+    Ok(status)
+}
+
+/// The render of a share link's mix, whoever started it.
+#[cfg(feature = "ssr")]
+pub async fn share_render(link_id: i64) -> Option<RenderJob> {
+    let mut jobs = RENDER_JOBS.lock().await;
+    let job = jobs.get_mut(&share_render_id(link_id))?;
+    job.touched = Instant::now();
+    Some(job.clone())
+}
+
+#[cfg(feature = "ssr")]
+fn share_render_id(link_id: i64) -> String {
+    format!("share-{}", link_id)
+}
+
+#[cfg(feature = "ssr")]
+fn render_stems(stems: Vec<StemFile>, mix: &[TrackMix]) -> Vec<RenderStem> {
+    stems
+        .into_iter()
+        .map(|stem| {
+            let settings = mix.iter().find(|m| m.track == stem.name);
+            RenderStem {
+                location: stem.path.to_string_lossy().to_string(),
+                volume: settings.map(|m| m.volume.clamp(0.0, 10.0)).unwrap_or(1.0),
+                pan: settings.map(|m| m.pan.clamp(-1.0, 1.0)).unwrap_or(0.0),
+                mute: settings.map(|m| m.mute).unwrap_or(false),
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "ssr")]
+async fn new_job(
+    id: String,
+    user_id: i64,
+    jam_id: i64,
+    date: &str,
+    format: RenderFormat,
+    download_url: String,
+) -> Result<RenderJob, ServerFnError> {
+    let render_dir = &repan_config::get().library.renders;
+    tokio::fs::create_dir_all(render_dir).await?;
+    let output = render_dir.join(format!("{}-{}.{}", date, id, format.extension()));
+    Ok(RenderJob {
+        user_id,
+        status: RenderStatus {
+            id,
+            jam_id,
+            format,
+            state: RenderState::Queued,
+            progress: 0.0,
+            error: None,
+            download_url: None,
+        },
+        output,
+        download_url,
+        touched: Instant::now(),
+    })
+}
+
+/// Hands a job that is already in `RENDER_JOBS` to a streamer.
+#[cfg(feature = "ssr")]
+async fn dispatch_render(job: RenderJob, stems: Vec<RenderStem>) -> Result<(), ServerFnError> {
+    metrics::render_jobs(&*RENDER_JOBS.lock().await);
+    let id = job.status.id;
+    let msg = ServerMsg::Render {
+        output: job.output.to_string_lossy().to_string(),
+        format: job.status.format,
+        stems,
+    };
+    let sent = async {
+        streamers::assign(&id, Capability::Render).await?;
+        send_to_streamer(id.clone(), msg).await
+    };
+    if let Err(e) = sent.await {
+        streamers::release(&id).await;
+        update_render_job(&id, RenderUpdate::Failed(e.to_string())).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ssr")]
+pub enum RenderUpdate {
+    Progress(f64),
+    Done,
+    Failed(String),
+}
+
+#[cfg(feature = "ssr")]
+pub async fn update_render_job(id: &str, update: RenderUpdate) {
+    let mut jobs = RENDER_JOBS.lock().await;
+    let Some(job) = jobs.get_mut(id) else {
+        log!("Update for unknown render job {}", id);
+        return;
+    };
+    job.touched = Instant::now();
+    let status = &mut job.status;
+    match update {
+        RenderUpdate::Progress(progress) => {
+            status.state = RenderState::Rendering;
+            status.progress = progress.clamp(0.0, 1.0);
+        }
+        RenderUpdate::Done => {
+            status.state = RenderState::Done;
+            status.progress = 1.0;
+            status.download_url = Some(job.download_url.clone());
+        }
+        RenderUpdate::Failed(error) => {
+            status.state = RenderState::Failed;
+            status.error = Some(error);
+        }
+    }
+    metrics::render_jobs(&jobs);
+}
+
+#[cfg(feature = "ssr")]
+pub async fn render_job(user_id: i64, id: &str) -> Option<RenderJob> {
+    let mut jobs = RENDER_JOBS.lock().await;
+    let job = jobs.get_mut(id).filter(|job| job.user_id == user_id)?;
+    job.touched = Instant::now();
+    Some(job.clone())
+}
+
+/// Forgets jobs nobody asked for in `expiry` and deletes their files, along with files in `dir`
+/// that old no job knows about, left over from before a restart. A share link renders its mix
+/// again when it is opened next. Returns how many files were deleted.
+#[cfg(feature = "ssr")]
+pub async fn sweep_renders(dir: &Path, expiry: Duration) -> usize {
+    let mut stale = vec![];
+    let live: HashSet<PathBuf> = {
+        let mut jobs = RENDER_JOBS.lock().await;
+        jobs.retain(|id, job| {
+            let keep = job.touched.elapsed() <= expiry;
+            if !keep {
+                stale.push((id.clone(), job.status.state, job.output.clone()));
+            }
+            keep
+        });
+        metrics::render_jobs(&jobs);
+        jobs.values().map(|job| job.output.clone()).collect()
+    };
+
+    let mut removed = 0;
+    for (id, state, output) in stale {
+        if matches!(state, RenderState::Queued | RenderState::Rendering) {
+            streamers::release(&id).await;
+        }
+        if tokio::fs::remove_file(&output).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return removed;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if live.contains(&path) {
+            continue;
+        }
+        let expired = entry
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > expiry));
+        if expired && tokio::fs::remove_file(&path).await.is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Sweeps old renders every hour for as long as the server runs.
+#[cfg(feature = "ssr")]
+pub fn spawn_render_sweeper() {
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let config = repan_config::get();
+            let removed =
+                sweep_renders(&config.library.renders, config.limits.render_expiry()).await;
+            if removed > 0 {
+                log!("Removed {} old renders", removed);
+            }
+        }
+    });
+}
+
+#[server(StartRender)]
+pub async fn start_render(
+    jam_id: i64,
+    format: RenderFormat,
+    mix: Vec<TrackMix>,
+) -> Result<RenderStatus, ServerFnError> {
+    let user = require_user().await?;
+    start_render_job(&user, jam_id, format, mix).await
+}
+
+#[server(GetRenderStatus)]
+pub async fn get_render_status(id: String) -> Result<RenderStatus, ServerFnError> {
+    let user = require_user().await?;
+    match render_job(user.id, &id).await {
+        Some(job) => Ok(job.status),
+        None => Err(ServerFnError::ServerError("No such render".to_string())),
+    }
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    fn job(id: &str, output: PathBuf, age: Duration) -> RenderJob {
+        RenderJob {
+            user_id: 1,
+            status: RenderStatus {
+                id: id.to_string(),
+                jam_id: 1,
+                format: RenderFormat::Wav,
+                state: RenderState::Done,
+                progress: 1.0,
+                error: None,
+                download_url: None,
+            },
+            output,
+            download_url: String::new(),
+            touched: Instant::now() - age,
+        }
+    }
+
+    #[tokio::test]
+    async fn old_renders_are_swept() {
+        let dir = std::env::temp_dir().join(format!("repan-renders-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let [old, fresh, orphan, new_file] =
+            ["old", "fresh", "orphan", "new"].map(|name| dir.join(format!("{}.wav", name)));
+        for path in [&old, &fresh, &orphan, &new_file] {
+            std::fs::write(path, b"RIFF").unwrap();
+        }
+        std::fs::File::options()
+            .write(true)
+            .open(&orphan)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - 2 * hour)
+            .unwrap();
+        {
+            let mut jobs = RENDER_JOBS.lock().await;
+            jobs.insert("old".to_string(), job("old", old.clone(), 2 * hour));
+            jobs.insert(
+                "fresh".to_string(),
+                job("fresh", fresh.clone(), Duration::ZERO),
+            );
+        }
+
+        assert_eq!(sweep_renders(&dir, hour).await, 2);
+        assert!(!old.exists() && !orphan.exists());
+        // No job knows the new file, it may be from one that is only starting.
+        assert!(fresh.exists() && new_file.exists());
+        assert!(render_job(1, "old").await.is_none());
+        assert!(render_job(1, "fresh").await.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
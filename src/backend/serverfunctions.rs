@@ -5,9 +5,15 @@ use serde::Deserialize;
 use serde::Serialize;
 
 #[cfg(feature = "ssr")]
-use crate::backend::database::get_database;
+use crate::backend::auth::require_user;
+#[cfg(feature = "ssr")]
+use crate::backend::bands::require_jam_role;
 use crate::backend::database::QueryType;
-use crate::backend::database::{JamQueryResult, QueryAmount, QueryTarget};
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, Role};
+use crate::backend::database::{
+    JamQueryResult, Marker, MixPreset, QueryAmount, QueryTarget, UserMixSettings,
+};
 
 //pub type MultiQuery = Vec<Vec<JamQueryResult<String>>>;
 
@@ -21,13 +27,19 @@ pub struct MultiQuery {
 
 #[server(GetTracksAndPath)]
 pub async fn get_tracks_and_path(id: i64) -> Result<MultiQuery, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let tracks = db.query(QueryTarget::Track(id), QueryAmount::All)?;
+    let tracks = db.query(user.id, QueryTarget::Track(id), QueryAmount::All)?;
+    // Jams of other bands look exactly like missing ones.
     let path = db
-        .query(QueryTarget::Path, QueryAmount::One(QueryType::FromID(id)))?
+        .query(
+            user.id,
+            QueryTarget::Path,
+            QueryAmount::One(QueryType::FromID(id)),
+        )?
         .first()
-        .unwrap()
+        .ok_or_else(|| ServerFnError::ServerError("Jam not found".to_string()))?
         .to_owned();
 
     Ok(MultiQuery {
@@ -40,27 +52,34 @@ pub async fn get_tracks_and_path(id: i64) -> Result<MultiQuery, ServerFnError> {
 
 #[server(GetAllJams)]
 pub async fn get_all_jams() -> Result<Vec<JamQueryResult<String>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Date, QueryAmount::All)?;
+    let res = db.query(user.id, QueryTarget::Date, QueryAmount::All)?;
     Ok(res)
 }
 #[server(GetAllJamsFromMonth)]
 pub async fn get_all_jams_month(
     yearmonth: String,
 ) -> Result<Vec<JamQueryResult<String>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Date, QueryAmount::Month(yearmonth))?;
+    let res = db.query(user.id, QueryTarget::Date, QueryAmount::Month(yearmonth))?;
     Ok(res)
 }
 #[server(GetAllJamsFromMonthAsDays)]
 pub async fn get_all_days_with_jams(
     yearmonth: String,
 ) -> Result<Vec<JamQueryResult<u32>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Date, QueryAmount::MonthDays(yearmonth))?;
+    let res = db.query(
+        user.id,
+        QueryTarget::Date,
+        QueryAmount::MonthDays(yearmonth),
+    )?;
     Ok(res)
 }
 
@@ -68,18 +87,28 @@ pub async fn get_all_days_with_jams(
 pub async fn get_all_jams_from_day(
     ymd: String,
 ) -> Result<Vec<JamQueryResult<String>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Date, QueryAmount::Day(ymd))?;
+    let res = db.query(user.id, QueryTarget::Date, QueryAmount::Day(ymd))?;
     Ok(res)
 }
 #[server(GetJam)]
 pub async fn get_jam(id: i64) -> Result<MultiQuery, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let tracks = db.query(QueryTarget::Track(id), QueryAmount::All)?;
-    let path = db.query(QueryTarget::Path, QueryAmount::One(QueryType::FromID(id)))?;
-    let date = db.query(QueryTarget::Date, QueryAmount::One(QueryType::FromID(id)))?;
+    let tracks = db.query(user.id, QueryTarget::Track(id), QueryAmount::All)?;
+    let path = db.query(
+        user.id,
+        QueryTarget::Path,
+        QueryAmount::One(QueryType::FromID(id)),
+    )?;
+    let date = db.query(
+        user.id,
+        QueryTarget::Date,
+        QueryAmount::One(QueryType::FromID(id)),
+    )?;
 
     if path.is_empty() || date.is_empty() {
         return Err(ServerFnError::ServerError("No path or empty".to_string()));
@@ -97,28 +126,61 @@ pub async fn get_jam(id: i64) -> Result<MultiQuery, ServerFnError> {
 
 #[server(GetJamPath)]
 pub async fn get_jam_path(jam_date: String) -> Result<JamQueryResult<String>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
     let res = db.query(
+        user.id,
         QueryTarget::Path,
         QueryAmount::One(QueryType::FromDate(jam_date)),
     )?;
-    Ok(res.first().expect("At least one jam to exist").clone())
+    res.first()
+        .cloned()
+        .ok_or_else(|| ServerFnError::ServerError("Jam not found".to_string()))
 }
 #[server(GetTracks)]
 pub async fn get_tracks(id: i64) -> Result<Vec<JamQueryResult<String>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Track(id), QueryAmount::All)?;
+    let res = db.query(user.id, QueryTarget::Track(id), QueryAmount::All)?;
     Ok(res)
 }
 #[server(GetTrackList)]
 pub async fn get_track_list(jam_id: i64) -> Result<Vec<JamQueryResult<String>>, ServerFnError> {
+    let user = require_user().await?;
     let mut db = get_database()?;
 
-    let res = db.query(QueryTarget::Track(jam_id), QueryAmount::All)?;
+    let res = db.query(user.id, QueryTarget::Track(jam_id), QueryAmount::All)?;
     Ok(res)
 }
+#[server(GetMixPresets)]
+pub async fn get_mix_presets(jam_id: i64) -> Result<Vec<MixPreset>, ServerFnError> {
+    let user = require_user().await?;
+    require_jam_role(&user, jam_id, Role::Viewer)?;
+    Ok(get_database()?.mix_presets(jam_id)?)
+}
+#[server(GetMarkers)]
+pub async fn get_markers(jam_id: i64) -> Result<Vec<Marker>, ServerFnError> {
+    let user = require_user().await?;
+    require_jam_role(&user, jam_id, Role::Viewer)?;
+    Ok(get_database()?.markers(jam_id)?)
+}
+#[server(GetMixSettings)]
+pub async fn get_mix_settings() -> Result<UserMixSettings, ServerFnError> {
+    let user = require_user().await?;
+    Ok(get_database()?.mix_settings(user.id)?)
+}
+#[server(SaveMixSettings)]
+pub async fn save_mix_settings(settings: UserMixSettings) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    settings
+        .transpose
+        .validate()
+        .map_err(ServerFnError::ServerError)?;
+    get_database()?.set_mix_settings(user.id, &settings)?;
+    Ok(())
+}
 //#[server(GetFullJam)]
 //async fn get_full_jam() -> Result<Vec<JamQueryResult>, ServerFnError>
 //{
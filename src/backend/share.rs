@@ -0,0 +1,303 @@
+//! Links that let someone without an account listen to a single jam, optionally locked to one
+//! of its mix presets.
+//!
+//! A token is `{id}.{expires}.{signature}`, signed with a secret kept in the database, so links
+//! can't be guessed from their id and their expiry can't be pushed back. The row in
+//! `share_links` still has the last word, that is where links are revoked and views counted.
+
+use leptos::{prelude::ServerFnError, server};
+#[cfg(feature = "ssr")]
+use repan_protocol::auth::{hex, unhex};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ssr")]
+use crate::backend::auth::require_user;
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, Database, Role, User};
+use crate::backend::database::{ShareLink, TrackMix};
+#[cfg(feature = "ssr")]
+use crate::backend::render::start_share_render;
+use crate::backend::render::RenderStatus;
+
+pub const MAX_SHARE_DAYS: u32 = 90;
+#[cfg(feature = "ssr")]
+const SECRET_NAME: &str = "share_links";
+/// Bytes of the HMAC kept in the token, plenty against guessing and keeps links short.
+#[cfg(feature = "ssr")]
+const SIGNATURE_LEN: usize = 16;
+
+/// A link as its creator sees it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OwnShare {
+    pub link: ShareLink,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedTrack {
+    pub id: i64,
+    pub name: String,
+}
+
+/// Everything the share page gets to know about a jam.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedJam {
+    pub date: String,
+    pub tracks: Vec<SharedTrack>,
+    pub preset_name: Option<String>,
+    /// Tracks left out play at unity gain, centered.
+    pub mix: Vec<TrackMix>,
+    pub allow_download: bool,
+    pub expires_at: i64,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(Debug)]
+pub enum ShareError {
+    NotFound(&'static str),
+    Expired,
+    Revoked,
+    Forbidden(String),
+    Invalid(String),
+    Database(rusqlite::Error),
+}
+
+#[cfg(feature = "ssr")]
+impl std::fmt::Display for ShareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareError::NotFound(what) => write!(f, "{} not found", what),
+            ShareError::Expired => write!(f, "This link has expired"),
+            ShareError::Revoked => write!(f, "This link was revoked"),
+            ShareError::Forbidden(reason) | ShareError::Invalid(reason) => write!(f, "{}", reason),
+            ShareError::Database(_) => write!(f, "Database error"),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<rusqlite::Error> for ShareError {
+    fn from(e: rusqlite::Error) -> Self {
+        ShareError::Database(e)
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<ShareError> for ServerFnError {
+    fn from(e: ShareError) -> Self {
+        if let ShareError::Database(e) = &e {
+            leptos::logging::log!("Share link failed: {:?}", e);
+        }
+        ServerFnError::ServerError(e.to_string())
+    }
+}
+
+#[cfg(feature = "ssr")]
+fn signer(id: i64, expires_at: i64) -> Result<hmac::Hmac<sha2::Sha256>, rusqlite::Error> {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+    use hmac::Mac;
+
+    let secret = get_database()?.secret(SECRET_NAME, || {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        hex(&bytes)
+    })?;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", id, expires_at).as_bytes());
+    Ok(mac)
+}
+
+#[cfg(feature = "ssr")]
+pub fn share_token(link: &ShareLink) -> Result<String, rusqlite::Error> {
+    use hmac::Mac;
+
+    let signature = signer(link.id, link.expires_at)?.finalize().into_bytes();
+    Ok(format!(
+        "{}.{}.{}",
+        link.id,
+        link.expires_at,
+        hex(&signature[..SIGNATURE_LEN])
+    ))
+}
+
+/// The link behind `token`, if it is genuine and still open.
+#[cfg(feature = "ssr")]
+pub fn resolve_share(token: &str) -> Result<ShareLink, ShareError> {
+    use hmac::Mac;
+
+    let not_found = || ShareError::NotFound("Share link");
+    let mut parts = token.split('.');
+    let (Some(id), Some(expires_at), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(not_found());
+    };
+    let id: i64 = id.parse().map_err(|_| not_found())?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| not_found())?;
+    let signature = unhex(signature)
+        .filter(|s| s.len() == SIGNATURE_LEN)
+        .ok_or_else(not_found)?;
+    signer(id, expires_at)?
+        .verify_truncated_left(&signature)
+        .map_err(|_| not_found())?;
+
+    if chrono::Utc::now().timestamp() >= expires_at {
+        return Err(ShareError::Expired);
+    }
+    let link = get_database()?
+        .share_link(id)?
+        .filter(|link| link.expires_at == expires_at)
+        .ok_or_else(not_found)?;
+    if link.revoked {
+        return Err(ShareError::Revoked);
+    }
+    Ok(link)
+}
+
+/// Resolves `token` for someone opening the link, which counts as a view.
+#[cfg(feature = "ssr")]
+pub fn open_share(token: &str) -> Result<SharedJam, ShareError> {
+    let link = resolve_share(token)?;
+    let mut db = get_database()?;
+    db.count_share_view(link.id)?;
+
+    let tracks = db
+        .shared_stem_files(link.jam_id)?
+        .into_iter()
+        .map(|stem| SharedTrack {
+            id: stem.track_id,
+            name: stem.name,
+        })
+        .collect();
+    let mix = share_mix(&mut db, &link)?;
+    Ok(SharedJam {
+        date: link.jam_date,
+        tracks,
+        preset_name: link.preset_name,
+        mix,
+        allow_download: link.allow_download,
+        expires_at: link.expires_at,
+    })
+}
+
+/// The preset a link is locked to, empty when it plays the jam as recorded.
+#[cfg(feature = "ssr")]
+pub fn share_mix(db: &mut Database, link: &ShareLink) -> Result<Vec<TrackMix>, rusqlite::Error> {
+    let Some(preset_id) = link.preset_id else {
+        return Ok(vec![]);
+    };
+    Ok(db
+        .mix_presets(link.jam_id)?
+        .into_iter()
+        .find(|preset| preset.id == preset_id)
+        .map(|preset| preset.tracks)
+        .unwrap_or_default())
+}
+
+#[cfg(feature = "ssr")]
+pub fn create_share_link(
+    user: &User,
+    jam_id: i64,
+    preset_id: Option<i64>,
+    allow_download: bool,
+    expires_in_days: u32,
+) -> Result<OwnShare, ShareError> {
+    let mut db = get_database()?;
+    match db.jam_role(user.id, jam_id)? {
+        Some(role) if role >= Role::Editor => (),
+        Some(_) => {
+            return Err(ShareError::Forbidden(
+                "Sharing needs the editor role in the band".to_string(),
+            ))
+        }
+        None => return Err(ShareError::NotFound("Jam")),
+    }
+    if !(1..=MAX_SHARE_DAYS).contains(&expires_in_days) {
+        return Err(ShareError::Invalid(format!(
+            "Links expire after 1 to {} days",
+            MAX_SHARE_DAYS
+        )));
+    }
+    if let Some(preset_id) = preset_id {
+        if !db.mix_presets(jam_id)?.iter().any(|p| p.id == preset_id) {
+            return Err(ShareError::NotFound("Preset"));
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let expires_at = now + i64::from(expires_in_days) * 24 * 60 * 60;
+    let id = db.add_share_link(jam_id, preset_id, allow_download, user.id, now, expires_at)?;
+    let link = db
+        .share_link(id)?
+        .ok_or(ShareError::NotFound("Share link"))?;
+    let token = share_token(&link)?;
+    Ok(OwnShare { link, token })
+}
+
+#[cfg(feature = "ssr")]
+pub fn own_shares(user: &User) -> Result<Vec<OwnShare>, ShareError> {
+    get_database()?
+        .share_links_by(user.id)?
+        .into_iter()
+        .map(|link| {
+            let token = share_token(&link)?;
+            Ok(OwnShare { link, token })
+        })
+        .collect()
+}
+
+/// Only whoever made the link, or a site admin, may revoke it.
+#[cfg(feature = "ssr")]
+pub fn revoke_share(user: &User, id: i64) -> Result<(), ShareError> {
+    let mut db = get_database()?;
+    match db.share_link(id)? {
+        Some(link) if link.created_by == user.id || user.admin => {
+            db.revoke_share_link(id)?;
+            Ok(())
+        }
+        _ => Err(ShareError::NotFound("Share link")),
+    }
+}
+
+#[server(CreateShare)]
+pub async fn create_share(
+    jam_id: i64,
+    preset_id: Option<i64>,
+    allow_download: bool,
+    expires_in_days: u32,
+) -> Result<OwnShare, ServerFnError> {
+    let user = require_user().await?;
+    Ok(create_share_link(
+        &user,
+        jam_id,
+        preset_id,
+        allow_download,
+        expires_in_days,
+    )?)
+}
+
+#[server(GetShares)]
+pub async fn get_shares() -> Result<Vec<OwnShare>, ServerFnError> {
+    let user = require_user().await?;
+    Ok(own_shares(&user)?)
+}
+
+#[server(RevokeShare)]
+pub async fn revoke_share_link(id: i64) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    Ok(revoke_share(&user, id)?)
+}
+
+/// Needs no account, the token is the permission.
+#[server(GetSharedJam)]
+pub async fn get_shared_jam(token: String) -> Result<SharedJam, ServerFnError> {
+    Ok(open_share(&token)?)
+}
+
+/// Starts the server side mix of a link that doesn't allow downloads, or reports how far it got.
+#[server(PrepareSharedMix)]
+pub async fn prepare_shared_mix(token: String) -> Result<RenderStatus, ServerFnError> {
+    let link = resolve_share(&token)?;
+    start_share_render(&link, &token).await
+}
@@ -0,0 +1,308 @@
+//! Serves single stems with `Range`, `ETag` and conditional request support, so players and
+//! DAWs can seek in a track without downloading all of it.
+//!
+//! Files are only ever found through the `tracks` table, never through a client supplied path.
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use leptos::logging::log;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::backend::api::ApiError;
+use crate::backend::database::get_database;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// Inclusive on both ends, like the header itself.
+    Satisfiable {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes.
+///
+/// Returns `None` when the whole file should be sent: no header, a unit other than bytes, a
+/// malformed header or several ranges. Multipart responses aren't worth it for audio.
+pub fn parse_range(header: &str, len: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = match (start.is_empty(), end.is_empty()) {
+        // bytes=-500, the last 500 bytes
+        (true, false) => {
+            let suffix: u64 = end.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            ByteRange::Satisfiable {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }
+        }
+        // bytes=500-
+        (false, true) => {
+            let start: u64 = start.parse().ok()?;
+            if start >= len {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            ByteRange::Satisfiable {
+                start,
+                end: len - 1,
+            }
+        }
+        (false, false) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            if start >= len {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            ByteRange::Satisfiable {
+                start,
+                end: end.min(len - 1),
+            }
+        }
+        (true, true) => return None,
+    };
+    Some(range)
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("wav") => "audio/wav",
+        Some("flac") => "audio/flac",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `If-None-Match` may list several tags or `*`. Weak comparison, as RFC 9110 asks for here.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+    })
+}
+
+pub async fn serve_stem(
+    user_id: i64,
+    track_id: i64,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let mut db = get_database()?;
+    let Some(stem) = db.stem_file(user_id, track_id)? else {
+        return Err(ApiError::not_found("Track"));
+    };
+    serve_file(&stem.path, &stem.file_name(), method, headers).await
+}
+
+pub async fn serve_file(
+    path: &Path,
+    download_name: &str,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => {
+            log!("File in database missing on disk: {:?}", path);
+            return Err(ApiError::not_found("File"));
+        }
+    };
+    let len = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let etag = format!("\"{:x}-{:x}\"", len, modified);
+    let last_modified = DateTime::<Utc>::from_timestamp(modified as i64, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(path)),
+    );
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        response_headers.insert(header::ETAG, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&last_modified) {
+        response_headers.insert(header::LAST_MODIFIED, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!(
+        "inline; filename*=UTF-8''{}",
+        percent_encode(download_name)
+    )) {
+        response_headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+
+    let header_str = |name: header::HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if header_str(header::IF_NONE_MATCH).is_some_and(|h| etag_matches(h, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+
+    // A stale If-Range means the client's partial copy is outdated, so it gets the full file.
+    let range_allowed = match header_str(header::IF_RANGE) {
+        Some(if_range) => if_range == etag || if_range == last_modified,
+        None => true,
+    };
+    let range = header_str(header::RANGE)
+        .filter(|_| range_allowed)
+        .and_then(|h| parse_range(h, len));
+
+    let (status, start, end) = match range {
+        Some(ByteRange::Unsatisfiable) => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response());
+        }
+        Some(ByteRange::Satisfiable { start, end }) => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)).unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        None if len == 0 => (StatusCode::OK, 0, 0),
+        None => (StatusCode::OK, 0, len - 1),
+    };
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+
+    if method == Method::HEAD || body_len == 0 {
+        return Ok((status, response_headers).into_response());
+    }
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|_| ApiError::not_found("File"))?;
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Seek failed"))?;
+
+    let body = futures::stream::unfold((file, body_len), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0u8; remaining.min(CHUNK_SIZE) as usize];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buf.truncate(read);
+                Some((Ok(Bytes::from(buf)), (file, remaining - read as u64)))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    });
+
+    Ok((status, response_headers, Body::from_stream(body)).into_response())
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let range = |start, end| Some(ByteRange::Satisfiable { start, end });
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse_range("bytes=900-2000", 1000), range(900, 999));
+        // Open ended and suffix ranges.
+        assert_eq!(parse_range("bytes=500-", 1000), range(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), range(0, 999));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=2000-3000", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=-0", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(parse_range("bytes=-10", 0), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn whole_file_for_what_isnt_a_single_range() {
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("bytes=9-0", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+
+    #[test]
+    fn etags() {
+        let etag = "\"3e8-5f5e100\"";
+        assert!(etag_matches(etag, etag));
+        assert!(etag_matches("W/\"3e8-5f5e100\"", etag));
+        assert!(etag_matches("\"other\", \"3e8-5f5e100\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"other\"", etag));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_is_416() {
+        let path = std::env::temp_dir().join(format!("repan-range-{}.wav", std::process::id()));
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=100-"));
+
+        let response = serve_file(&path, "stem.wav", Method::GET, headers)
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */100");
+    }
+}
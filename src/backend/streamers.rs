@@ -0,0 +1,314 @@
+//! Registry of the streamer workers connected to `/ws`.
+//!
+//! A streamer introduces itself with its name, how many clients it takes and whether it streams,
+//! renders or both. A listener session or render job is assigned to the healthy streamer with the
+//! most room left among those that can take it, and everything for that client goes to the same
+//! streamer afterwards. Streamers are pinged, one that stops
+//! answering is dropped like one that disconnected.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+use futures::stream::SplitSink;
+use futures::SinkExt;
+use leptos::logging::log;
+use leptos::prelude::ServerFnError;
+use once_cell::sync::Lazy;
+use repan_protocol::Capability;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::backend::client_connections::streamer_lost;
+use crate::backend::metrics;
+
+pub type StreamerId = u64;
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// Without any message for this long a streamer counts as unhealthy.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Locked on its own, sending can take a while and must not hold up the registry.
+type Sink = Arc<Mutex<SplitSink<WebSocket, Message>>>;
+
+struct Streamer {
+    sink: Sink,
+    /// Known once the streamer introduced itself, it takes no clients before.
+    name: Option<String>,
+    capacity: usize,
+    capabilities: Vec<Capability>,
+    connected_at: i64,
+    last_seen: Instant,
+    /// Listener sessions and render jobs running on this streamer, with what each needed it for.
+    clients: HashMap<String, Capability>,
+}
+
+impl Streamer {
+    fn healthy(&self) -> bool {
+        self.name.is_some() && self.last_seen.elapsed() < STALE_AFTER
+    }
+
+    fn has_room(&self, needs: Capability) -> bool {
+        self.healthy() && self.capabilities.contains(&needs) && self.clients.len() < self.capacity
+    }
+
+    fn load(&self) -> f64 {
+        self.clients.len() as f64 / self.capacity as f64
+    }
+
+    fn report_clients(&self) {
+        if let Some(name) = &self.name {
+            metrics::streamer_clients(name, self.clients.len());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    streamers: HashMap<StreamerId, Streamer>,
+    /// The streamer each client runs on.
+    owners: HashMap<String, StreamerId>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamerInfo {
+    pub id: StreamerId,
+    pub name: Option<String>,
+    pub capacity: usize,
+    pub capabilities: Vec<Capability>,
+    pub clients: usize,
+    pub healthy: bool,
+    pub connected_at: i64,
+    /// Seconds since the last message, pongs included.
+    pub last_seen: u64,
+}
+
+/// Registers a newly connected streamer and starts pinging it.
+pub async fn add(sink: SplitSink<WebSocket, Message>) -> StreamerId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut registry = REGISTRY.lock().await;
+    registry.streamers.insert(
+        id,
+        Streamer {
+            sink: Arc::new(Mutex::new(sink)),
+            name: None,
+            capacity: 0,
+            capabilities: vec![],
+            connected_at: chrono::Utc::now().timestamp(),
+            last_seen: Instant::now(),
+            clients: HashMap::new(),
+        },
+    );
+    metrics::streamers(registry.streamers.len());
+    drop(registry);
+
+    tokio::spawn(keep_alive(id));
+    id
+}
+
+pub async fn introduce(
+    id: StreamerId,
+    name: String,
+    capacity: usize,
+    capabilities: Vec<Capability>,
+) {
+    let mut registry = REGISTRY.lock().await;
+    if let Some(streamer) = registry.streamers.get_mut(&id) {
+        log!(
+            "Streamer {} is {}, takes {} clients, {:?}",
+            id,
+            name,
+            capacity,
+            capabilities
+        );
+        streamer.name = Some(name);
+        streamer.capacity = capacity;
+        streamer.capabilities = capabilities;
+        streamer.report_clients();
+    }
+}
+
+/// Any message from the streamer shows it is alive. False once the streamer was dropped.
+pub async fn seen(id: StreamerId) -> bool {
+    match REGISTRY.lock().await.streamers.get_mut(&id) {
+        Some(streamer) => {
+            streamer.last_seen = Instant::now();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether any streamer would take another client that `needs` it.
+pub async fn has_room(needs: Capability) -> bool {
+    REGISTRY
+        .lock()
+        .await
+        .streamers
+        .values()
+        .any(|streamer| streamer.has_room(needs))
+}
+
+/// Forgets a disconnected streamer. Returns the clients that were running on it, with the
+/// capability each was assigned for.
+pub async fn remove(id: StreamerId) -> Vec<(String, Capability)> {
+    let mut registry = REGISTRY.lock().await;
+    let Some(streamer) = registry.streamers.remove(&id) else {
+        return vec![];
+    };
+    for client in streamer.clients.keys() {
+        registry.owners.remove(client);
+    }
+    if let Some(name) = &streamer.name {
+        metrics::streamer_clients(name, 0);
+    }
+    metrics::streamers(registry.streamers.len());
+    streamer.clients.into_iter().collect()
+}
+
+/// Makes sure `client` has a streamer. It keeps the one it has, otherwise it gets the healthy
+/// streamer with the lowest load among those that can do what it `needs`.
+pub async fn assign(client: &str, needs: Capability) -> Result<(), ServerFnError> {
+    let mut registry = REGISTRY.lock().await;
+    if registry.owners.contains_key(client) {
+        return Ok(());
+    }
+    if !registry
+        .streamers
+        .values()
+        .any(|streamer| streamer.capabilities.contains(&needs))
+    {
+        return Err(ServerFnError::ServerError(format!(
+            "No streamer connected that can {}",
+            needs.as_str()
+        )));
+    }
+    let Some((&id, streamer)) = registry
+        .streamers
+        .iter_mut()
+        .filter(|(_, streamer)| streamer.has_room(needs))
+        .min_by(|(_, a), (_, b)| a.load().total_cmp(&b.load()))
+    else {
+        return Err(ServerFnError::ServerError(
+            "Every streamer is busy, try again later".to_string(),
+        ));
+    };
+    streamer.clients.insert(client.to_string(), needs);
+    streamer.report_clients();
+    registry.owners.insert(client.to_string(), id);
+    Ok(())
+}
+
+/// Frees the client's place on its streamer.
+pub async fn release(client: &str) {
+    let mut registry = REGISTRY.lock().await;
+    let Some(id) = registry.owners.remove(client) else {
+        return;
+    };
+    if let Some(streamer) = registry.streamers.get_mut(&id) {
+        streamer.clients.remove(client);
+        streamer.report_clients();
+    }
+}
+
+/// Whether `client` was assigned to streamer `id`.
+pub async fn owns(id: StreamerId, client: &str) -> bool {
+    REGISTRY.lock().await.owners.get(client) == Some(&id)
+}
+
+/// Name of the streamer `client` runs on.
+pub async fn name_of_owner(client: &str) -> Option<String> {
+    let registry = REGISTRY.lock().await;
+    let id = registry.owners.get(client)?;
+    registry.streamers.get(id)?.name.clone()
+}
+
+/// Sends `text` to the streamer `client` was assigned to.
+pub async fn send(client: &str, text: String) -> Result<(), ServerFnError> {
+    let sink = {
+        let registry = REGISTRY.lock().await;
+        registry
+            .owners
+            .get(client)
+            .and_then(|id| registry.streamers.get(id))
+            .map(|streamer| streamer.sink.clone())
+            .ok_or_else(|| ServerFnError::ServerError("No streamer assigned".to_string()))?
+    };
+    send_text(&sink, text).await
+}
+
+/// Sends `text` to streamer `id`, for messages about the streamer itself.
+pub async fn send_to(id: StreamerId, text: String) -> Result<(), ServerFnError> {
+    let sink = REGISTRY
+        .lock()
+        .await
+        .streamers
+        .get(&id)
+        .map(|streamer| streamer.sink.clone())
+        .ok_or_else(|| ServerFnError::ServerError("Unknown streamer".to_string()))?;
+    send_text(&sink, text).await
+}
+
+async fn send_text(sink: &Sink, text: String) -> Result<(), ServerFnError> {
+    sink.lock()
+        .await
+        .send(Message::Text(Utf8Bytes::from(text)))
+        .await?;
+    Ok(())
+}
+
+pub async fn list() -> Vec<StreamerInfo> {
+    let registry = REGISTRY.lock().await;
+    let mut streamers: Vec<StreamerInfo> = registry
+        .streamers
+        .iter()
+        .map(|(&id, streamer)| StreamerInfo {
+            id,
+            name: streamer.name.clone(),
+            capacity: streamer.capacity,
+            capabilities: streamer.capabilities.clone(),
+            clients: streamer.clients.len(),
+            healthy: streamer.healthy(),
+            connected_at: streamer.connected_at,
+            last_seen: streamer.last_seen.elapsed().as_secs(),
+        })
+        .collect();
+    streamers.sort_by_key(|streamer| streamer.id);
+    streamers
+}
+
+/// Pings the streamer until it is removed. The pongs keep `last_seen` current, a streamer that
+/// stays quiet too long is dropped as if it had disconnected. Its socket may never close when
+/// the network goes away.
+async fn keep_alive(id: StreamerId) {
+    let mut ticker = tokio::time::interval(PING_INTERVAL);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let registry = REGISTRY.lock().await;
+        let Some(streamer) = registry.streamers.get(&id) else {
+            return;
+        };
+        if streamer.last_seen.elapsed() > STALE_AFTER {
+            drop(registry);
+            log!("Streamer {} stopped answering", id);
+            streamer_lost(id).await;
+            return;
+        }
+        let sink = streamer.sink.clone();
+        drop(registry);
+        let pinged = sink
+            .lock()
+            .await
+            .send(Message::Ping(Default::default()))
+            .await;
+        if let Err(e) = pinged {
+            log!("Couldn't ping streamer {}: {:?}", id, e);
+        }
+    }
+}
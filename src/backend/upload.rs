@@ -0,0 +1,528 @@
+//! Resumable uploads of new stems into the library.
+//!
+//! An upload is created with its final file name and size, then filled with chunks at explicit
+//! offsets. A dropped connection loses nothing, the client asks for the current offset and
+//! continues from there. Both the partial file and its metadata live on disk, so this survives a
+//! server restart too. Completing an upload validates it, moves it under the library root of the
+//! band it was uploaded for and indexes it with jamdb's rules.
+
+#[cfg(feature = "ssr")]
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "ssr")]
+use leptos::logging::log;
+use leptos::{prelude::ServerFnError, server};
+#[cfg(feature = "ssr")]
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[cfg(feature = "ssr")]
+use crate::backend::auth::require_user;
+#[cfg(feature = "ssr")]
+use crate::backend::database::{get_database, Role, User};
+
+/// What the upload page sends per request, `limits.max_chunk_size` can't go below it.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+#[cfg(feature = "ssr")]
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub id: String,
+    pub band_id: i64,
+    pub file_name: String,
+    pub size: u64,
+    /// Bytes received so far, where the next chunk has to start.
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IngestResult {
+    pub jam_id: i64,
+    pub date: String,
+    pub track: String,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadMeta {
+    user_id: i64,
+    band_id: i64,
+    file_name: String,
+    size: u64,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(Debug)]
+pub enum UploadError {
+    Invalid(String),
+    NotFound,
+    Forbidden(String),
+    /// Holds the offset the server actually is at
+    OffsetMismatch(u64),
+    Duplicate(String),
+    Io(io::Error),
+    Database(String),
+}
+
+#[cfg(feature = "ssr")]
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::Invalid(reason) => write!(f, "Invalid upload: {}", reason),
+            UploadError::NotFound => write!(f, "No such upload"),
+            UploadError::Forbidden(reason) => write!(f, "{}", reason),
+            UploadError::OffsetMismatch(offset) => {
+                write!(f, "Upload is at offset {}", offset)
+            }
+            UploadError::Duplicate(name) => write!(f, "{} is already in the library", name),
+            UploadError::Io(e) => write!(f, "Upload failed: {}", e),
+            UploadError::Database(e) => write!(f, "Indexing failed: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl std::error::Error for UploadError {}
+
+#[cfg(feature = "ssr")]
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<rusqlite::Error> for UploadError {
+    fn from(e: rusqlite::Error) -> Self {
+        UploadError::Database(e.to_string())
+    }
+}
+
+/// One lock per upload in progress. Without it two requests for the same offset would both pass
+/// the offset check and append their chunk.
+#[cfg(feature = "ssr")]
+static UPLOAD_LOCKS: Lazy<Mutex<HashMap<uuid::Uuid, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(feature = "ssr")]
+fn upload_lock(id: &str) -> Result<Arc<tokio::sync::Mutex<()>>, UploadError> {
+    let id = uuid::Uuid::parse_str(id).map_err(|_| UploadError::NotFound)?;
+    Ok(UPLOAD_LOCKS.lock().unwrap().entry(id).or_default().clone())
+}
+
+/// Once the upload is gone, so nobody can be waiting for it anymore.
+#[cfg(feature = "ssr")]
+fn forget_upload_lock(id: &str) {
+    if let Ok(id) = uuid::Uuid::parse_str(id) {
+        UPLOAD_LOCKS.lock().unwrap().remove(&id);
+    }
+}
+
+#[cfg(feature = "ssr")]
+fn uploads_dir() -> PathBuf {
+    repan_config::get().library.uploads.clone()
+}
+
+/// Upload ids end up in file names, so only ever accept real uuids.
+#[cfg(feature = "ssr")]
+fn upload_paths(id: &str) -> Result<(PathBuf, PathBuf), UploadError> {
+    let id = uuid::Uuid::parse_str(id).map_err(|_| UploadError::NotFound)?;
+    let dir = uploads_dir();
+    Ok((
+        dir.join(format!("{}.json", id)),
+        dir.join(format!("{}.part", id)),
+    ))
+}
+
+/// Checks a stem name against the same rules jamdb indexes with. The stem is later opened as
+/// `{track}-{date}.wav`, so names that don't come out the same that way are refused, like
+/// `x01-KaggeN-210319_1833.wav` or `01-KaggeN-210319_1833-take2.wav`.
+#[cfg(feature = "ssr")]
+fn parse_stem_name(file_name: &str) -> Result<(String, String), UploadError> {
+    let path = Path::new(file_name);
+    if path.file_name().and_then(|n| n.to_str()) != Some(file_name) {
+        return Err(UploadError::Invalid(
+            "File name must not contain a path".to_string(),
+        ));
+    }
+    jamdb::StemRules::new()
+        .parse(path)
+        .filter(|(date, track)| format!("{}-{}.wav", track, date) == file_name)
+        .ok_or_else(|| {
+            UploadError::Invalid(format!(
+                "{} doesn't follow the naming scheme, e.g. 01-KaggeN-210319_1833.wav",
+                file_name
+            ))
+        })
+}
+
+/// Where an upload ends up, `{band root}/{jam date}/{file name}`, and how it gets indexed.
+#[cfg(feature = "ssr")]
+struct Destination {
+    jam_dir: PathBuf,
+    path: PathBuf,
+    date: String,
+    /// The jam's directory as jamdb stores it.
+    dir: String,
+    track: String,
+}
+
+#[cfg(feature = "ssr")]
+fn stem_destination(root: &Path, file_name: &str) -> Result<Destination, UploadError> {
+    let (date, track) = parse_stem_name(file_name)?;
+    let jam_dir = root.join(&date);
+    let path = jam_dir.join(file_name);
+    // Index with the final path, exactly as a scan of the library would see the file. A root
+    // that looks like part of a stem name would make that differ from the name alone.
+    let invalid = || {
+        UploadError::Invalid(format!(
+            "{} can't be stored under {}",
+            file_name,
+            root.display()
+        ))
+    };
+    if jamdb::StemRules::new().parse(&path) != Some((date.clone(), track.clone())) {
+        return Err(invalid());
+    }
+    let dir = jamdb::jam_dir(&path).ok_or_else(invalid)?;
+    Ok(Destination {
+        jam_dir,
+        path,
+        date,
+        dir,
+        track,
+    })
+}
+
+/// The root new recordings of the band go to. Needs at least the editor role.
+#[cfg(feature = "ssr")]
+fn band_upload_root(user: &User, band_id: i64) -> Result<PathBuf, UploadError> {
+    let mut db = get_database()?;
+    let role = db.band_role(user.id, band_id)?;
+    if !role.is_some_and(|role| role >= Role::Editor) {
+        return Err(UploadError::Forbidden(
+            "Uploading needs the editor role in the band".to_string(),
+        ));
+    }
+    db.band_roots(band_id)?
+        .into_iter()
+        .next()
+        .map(PathBuf::from)
+        .ok_or_else(|| UploadError::Invalid("The band has no library root yet".to_string()))
+}
+
+#[cfg(feature = "ssr")]
+pub async fn create_upload_file(
+    user: &User,
+    band_id: i64,
+    file_name: String,
+    size: u64,
+) -> Result<UploadStatus, UploadError> {
+    let root = band_upload_root(user, band_id)?;
+    let Destination {
+        date, dir, track, ..
+    } = stem_destination(&root, &file_name)?;
+    let max_size = repan_config::get().limits.max_upload_size;
+    if size == 0 || size > max_size {
+        return Err(UploadError::Invalid(format!(
+            "Size must be between 1 and {} bytes",
+            max_size
+        )));
+    }
+    if get_database()?.track_exists(&date, &dir, &track)? {
+        return Err(UploadError::Duplicate(file_name));
+    }
+
+    tokio::fs::create_dir_all(uploads_dir()).await?;
+    let id = uuid::Uuid::new_v4().to_string();
+    let (meta_path, part_path) = upload_paths(&id)?;
+    let meta = UploadMeta {
+        user_id: user.id,
+        band_id,
+        file_name,
+        size,
+    };
+    let meta_json = serde_json::to_vec(&meta).map_err(|e| UploadError::Io(io::Error::other(e)))?;
+    tokio::fs::write(&meta_path, meta_json).await?;
+    tokio::fs::File::create(&part_path).await?;
+
+    Ok(UploadStatus {
+        id,
+        band_id,
+        file_name: meta.file_name,
+        size: meta.size,
+        offset: 0,
+    })
+}
+
+/// Uploads of other users are reported as missing.
+#[cfg(feature = "ssr")]
+pub async fn upload_status(user: &User, id: &str) -> Result<UploadStatus, UploadError> {
+    let (meta_path, part_path) = upload_paths(id)?;
+    let meta = match tokio::fs::read(&meta_path).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(UploadError::NotFound),
+        Err(e) => return Err(e.into()),
+    };
+    let meta: UploadMeta =
+        serde_json::from_slice(&meta).map_err(|e| UploadError::Io(io::Error::other(e)))?;
+    if meta.user_id != user.id {
+        return Err(UploadError::NotFound);
+    }
+    let offset = tokio::fs::metadata(&part_path).await?.len();
+
+    Ok(UploadStatus {
+        id: id.to_string(),
+        band_id: meta.band_id,
+        file_name: meta.file_name,
+        size: meta.size,
+        offset,
+    })
+}
+
+#[cfg(feature = "ssr")]
+pub async fn append_chunk(
+    user: &User,
+    id: &str,
+    offset: u64,
+    chunk: &[u8],
+) -> Result<UploadStatus, UploadError> {
+    let lock = upload_lock(id)?;
+    let _writing = lock.lock().await;
+    let mut status = upload_status(user, id).await?;
+    if offset != status.offset {
+        return Err(UploadError::OffsetMismatch(status.offset));
+    }
+    if status.offset + chunk.len() as u64 > status.size {
+        return Err(UploadError::Invalid(
+            "Chunk goes past the announced size".to_string(),
+        ));
+    }
+
+    let (_, part_path) = upload_paths(id)?;
+    let mut part = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&part_path)
+        .await?;
+    part.write_all(chunk).await?;
+    part.flush().await?;
+
+    status.offset += chunk.len() as u64;
+    Ok(status)
+}
+
+/// Moves a finished upload to `{band root}/{jam date}/` and adds it to the database.
+#[cfg(feature = "ssr")]
+pub async fn complete_upload_file(user: &User, id: &str) -> Result<IngestResult, UploadError> {
+    let lock = upload_lock(id)?;
+    let _writing = lock.lock().await;
+    let status = upload_status(user, id).await?;
+    let root = band_upload_root(user, status.band_id)?;
+    if status.offset != status.size {
+        return Err(UploadError::Invalid(format!(
+            "Only {} of {} bytes received",
+            status.offset, status.size
+        )));
+    }
+    let (meta_path, part_path) = upload_paths(id)?;
+    check_wav_header(&part_path).await?;
+
+    let Destination {
+        jam_dir,
+        path: destination,
+        date,
+        dir,
+        track,
+    } = stem_destination(&root, &status.file_name)?;
+
+    let mut db = get_database()?;
+    if db.track_exists(&date, &dir, &track)? || tokio::fs::try_exists(&destination).await? {
+        return Err(UploadError::Duplicate(status.file_name));
+    }
+
+    tokio::fs::create_dir_all(&jam_dir).await?;
+    move_file(&part_path, &destination).await?;
+    let jam_id = db
+        .add_stem(&date, &dir, &track)
+        .map_err(|e| UploadError::Database(e.to_string()))?;
+    let _ = tokio::fs::remove_file(&meta_path).await;
+    forget_upload_lock(id);
+
+    Ok(IngestResult {
+        jam_id,
+        date,
+        track,
+    })
+}
+
+/// Renames, or copies when the uploads directory is on another filesystem than the library.
+#[cfg(feature = "ssr")]
+async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(e) = tokio::fs::copy(from, to).await {
+                let _ = tokio::fs::remove_file(to).await;
+                return Err(e);
+            }
+            tokio::fs::remove_file(from).await
+        }
+        result => result,
+    }
+}
+
+/// Deletes uploads nothing was added to for `limits.upload_expiry_hours`. Returns how many.
+#[cfg(feature = "ssr")]
+pub async fn sweep_uploads() -> Result<usize, UploadError> {
+    let expiry = repan_config::get().limits.upload_expiry();
+    let mut entries = match tokio::fs::read_dir(uploads_dir()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut ids = std::collections::HashSet::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("part" | "json")
+        ) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| uuid::Uuid::parse_str(s).ok())
+        {
+            ids.insert(id.to_string());
+        }
+    }
+
+    let mut removed = 0;
+    for id in ids {
+        let lock = upload_lock(&id)?;
+        let _writing = lock.lock().await;
+        let (meta_path, part_path) = upload_paths(&id)?;
+        // Every chunk touches the partial file, the metadata only tells when it was created.
+        let metadata = match tokio::fs::metadata(&part_path).await {
+            Ok(metadata) => metadata,
+            Err(_) => tokio::fs::metadata(&meta_path).await?,
+        };
+        let expired = metadata.modified()?.elapsed().is_ok_and(|age| age > expiry);
+        if expired {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            let _ = tokio::fs::remove_file(&meta_path).await;
+            forget_upload_lock(&id);
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Sweeps abandoned uploads every hour for as long as the server runs.
+#[cfg(feature = "ssr")]
+pub fn spawn_upload_sweeper() {
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match sweep_uploads().await {
+                Ok(0) => (),
+                Ok(removed) => log!("Removed {} abandoned uploads", removed),
+                Err(e) => log!("Sweeping uploads failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Only PCM WAV is streamable, so reject anything without a RIFF/RF64 WAVE header.
+#[cfg(feature = "ssr")]
+async fn check_wav_header(path: &Path) -> Result<(), UploadError> {
+    let mut header = [0u8; 12];
+    let mut file = tokio::fs::File::open(path).await?;
+    if file.read_exact(&mut header).await.is_err() {
+        return Err(UploadError::Invalid(
+            "File is too short to be a WAV".to_string(),
+        ));
+    }
+    let riff = &header[0..4] == b"RIFF" || &header[0..4] == b"RF64";
+    if !riff || &header[8..12] != b"WAVE" {
+        return Err(UploadError::Invalid("File is not a WAV".to_string()));
+    }
+    Ok(())
+}
+
+#[server(CreateUpload)]
+pub async fn create_upload(
+    band_id: i64,
+    file_name: String,
+    size: u64,
+) -> Result<UploadStatus, ServerFnError> {
+    let user = require_user().await?;
+    Ok(create_upload_file(&user, band_id, file_name, size).await?)
+}
+
+#[server(GetUpload)]
+pub async fn get_upload(id: String) -> Result<UploadStatus, ServerFnError> {
+    let user = require_user().await?;
+    Ok(upload_status(&user, &id).await?)
+}
+
+#[server(CompleteUpload)]
+pub async fn complete_upload(id: String) -> Result<IngestResult, ServerFnError> {
+    let user = require_user().await?;
+    Ok(complete_upload_file(&user, &id).await?)
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stem_names_round_trip() {
+        assert_eq!(
+            parse_stem_name("01-KaggeN-210319_1833.wav").unwrap(),
+            ("210319_1833".to_string(), "01-KaggeN".to_string())
+        );
+        assert_eq!(
+            parse_stem_name("03-Bass-Left-210319_1833.wav").unwrap().1,
+            "03-Bass-Left"
+        );
+        for name in [
+            "x01-KaggeN-210319_1833.wav",
+            "01-KaggeN-210319_1833-take2.wav",
+            "01-210101_1200-210319_1833.wav",
+            "01-KaggeN-210319_1833.flac",
+            "KaggeN-210319_1833.wav",
+            "../01-KaggeN-210319_1833.wav",
+            "",
+        ] {
+            assert!(parse_stem_name(name).is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn destination_is_under_the_jam_date() {
+        let destination =
+            stem_destination(Path::new("/bands/ours"), "01-KaggeN-210319_1833.wav").unwrap();
+        assert_eq!(
+            destination.path,
+            Path::new("/bands/ours/210319_1833/01-KaggeN-210319_1833.wav")
+        );
+        assert_eq!(destination.dir, "/bands/ours/210319_1833/");
+        assert_eq!(destination.track, "01-KaggeN");
+    }
+
+    #[test]
+    fn root_must_not_look_like_a_stem() {
+        let root = Path::new("/bands/01-ours-");
+        assert!(stem_destination(root, "01-KaggeN-210319_1833.wav").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Writes a ZIP archive straight into a response body without a temp file.
+//!
+//! Entries are stored uncompressed, WAV doesn't shrink enough to be worth the CPU, and each
+//! entry is followed by a data descriptor so the CRC can be computed while streaming. Records
+//! are always written in ZIP64 form, a long multitrack jam easily passes 4 GiB.
+
+use std::io;
+use std::path::Path;
+
+use axum::body::Bytes;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use futures::channel::mpsc::Sender;
+use futures::SinkExt;
+use tokio::io::AsyncReadExt;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_OF_CENTRAL_SIG: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_SIG: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x07064b50;
+
+/// Bit 3: sizes and CRC follow the data. Bit 11: names are UTF-8, track names often aren't ASCII.
+const FLAGS: u16 = 0x0008 | 0x0800;
+const VERSION_ZIP64: u16 = 45;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type ZipChunk = Result<Bytes, io::Error>;
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+pub struct ZipStream {
+    tx: Sender<ZipChunk>,
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl ZipStream {
+    /// `modified` is stamped on every entry.
+    pub fn new(tx: Sender<ZipChunk>, modified: NaiveDateTime) -> Self {
+        let (dos_time, dos_date) = to_dos(modified);
+        ZipStream {
+            tx,
+            offset: 0,
+            entries: Vec::new(),
+            dos_time,
+            dos_date,
+        }
+    }
+
+    pub async fn add_bytes(&mut self, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        let header_offset = self.offset;
+        self.local_header(name).await?;
+
+        let crc = crc32fast::hash(data);
+        self.write(Bytes::copy_from_slice(data)).await?;
+        self.data_descriptor(crc, data.len() as u64).await?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            crc,
+            size: data.len() as u64,
+            offset: header_offset,
+        });
+        Ok(())
+    }
+
+    pub async fn add_file(&mut self, name: &str, path: &Path) -> Result<(), io::Error> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let header_offset = self.offset;
+        self.local_header(name).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        loop {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            buf.truncate(read);
+            hasher.update(&buf);
+            size += read as u64;
+            self.write(Bytes::from(buf)).await?;
+        }
+        let crc = hasher.finalize();
+        self.data_descriptor(crc, size).await?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset: header_offset,
+        });
+        Ok(())
+    }
+
+    /// Writes the central directory. The archive is unreadable without it.
+    pub async fn finish(mut self) -> Result<(), io::Error> {
+        let central_offset = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+
+        for entry in &entries {
+            let mut buf = Vec::with_capacity(46 + entry.name.len() + 28);
+            // Every entry gets a ZIP64 extra field, it keeps the header layout independent of
+            // whether this particular entry needed it.
+            let extra = zip64_extra(&[entry.size, entry.size, entry.offset]);
+            put_u32(&mut buf, CENTRAL_HEADER_SIG);
+            put_u16(&mut buf, VERSION_ZIP64);
+            put_u16(&mut buf, VERSION_ZIP64);
+            put_u16(&mut buf, FLAGS);
+            put_u16(&mut buf, 0);
+            put_u16(&mut buf, self.dos_time);
+            put_u16(&mut buf, self.dos_date);
+            put_u32(&mut buf, entry.crc);
+            put_u32(&mut buf, u32::MAX);
+            put_u32(&mut buf, u32::MAX);
+            put_u16(&mut buf, entry.name.len() as u16);
+            put_u16(&mut buf, extra.len() as u16);
+            put_u16(&mut buf, 0);
+            put_u16(&mut buf, 0);
+            put_u16(&mut buf, 0);
+            put_u32(&mut buf, 0);
+            put_u32(&mut buf, u32::MAX);
+            buf.extend_from_slice(entry.name.as_bytes());
+            buf.extend_from_slice(&extra);
+            self.write(Bytes::from(buf)).await?;
+        }
+
+        let central_size = self.offset - central_offset;
+        let zip64_end_offset = self.offset;
+        let count = entries.len() as u64;
+
+        let mut buf = Vec::with_capacity(56 + 20 + 22);
+        put_u32(&mut buf, ZIP64_END_OF_CENTRAL_SIG);
+        put_u64(&mut buf, 44);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 0);
+        put_u64(&mut buf, count);
+        put_u64(&mut buf, count);
+        put_u64(&mut buf, central_size);
+        put_u64(&mut buf, central_offset);
+
+        put_u32(&mut buf, ZIP64_LOCATOR_SIG);
+        put_u32(&mut buf, 0);
+        put_u64(&mut buf, zip64_end_offset);
+        put_u32(&mut buf, 1);
+
+        put_u32(&mut buf, END_OF_CENTRAL_SIG);
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, count.min(u16::MAX as u64) as u16);
+        put_u16(&mut buf, count.min(u16::MAX as u64) as u16);
+        put_u32(&mut buf, central_size.min(u32::MAX as u64) as u32);
+        put_u32(&mut buf, central_offset.min(u32::MAX as u64) as u32);
+        put_u16(&mut buf, 0);
+        self.write(Bytes::from(buf)).await?;
+
+        self.tx.close_channel();
+        Ok(())
+    }
+
+    /// Fails the response body so the client sees an error instead of a truncated archive.
+    pub async fn abort(mut self, error: io::Error) {
+        let _ = self.tx.send(Err(error)).await;
+        self.tx.close_channel();
+    }
+
+    async fn local_header(&mut self, name: &str) -> Result<(), io::Error> {
+        let extra = zip64_extra(&[0, 0]);
+        let mut buf = Vec::with_capacity(30 + name.len() + extra.len());
+        put_u32(&mut buf, LOCAL_HEADER_SIG);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u16(&mut buf, FLAGS);
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, self.dos_time);
+        put_u16(&mut buf, self.dos_date);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, u32::MAX);
+        put_u32(&mut buf, u32::MAX);
+        put_u16(&mut buf, name.len() as u16);
+        put_u16(&mut buf, extra.len() as u16);
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&extra);
+        self.write(Bytes::from(buf)).await
+    }
+
+    /// ZIP64 form, since the local header announced a ZIP64 extra field.
+    async fn data_descriptor(&mut self, crc: u32, size: u64) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(24);
+        put_u32(&mut buf, DATA_DESCRIPTOR_SIG);
+        put_u32(&mut buf, crc);
+        put_u64(&mut buf, size);
+        put_u64(&mut buf, size);
+        self.write(Bytes::from(buf)).await
+    }
+
+    async fn write(&mut self, bytes: Bytes) -> Result<(), io::Error> {
+        self.offset += bytes.len() as u64;
+        self.tx
+            .send(Ok(bytes))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away"))
+    }
+}
+
+fn zip64_extra(values: &[u64]) -> Vec<u8> {
+    let mut extra = Vec::with_capacity(4 + values.len() * 8);
+    put_u16(&mut extra, 0x0001);
+    put_u16(&mut extra, (values.len() * 8) as u16);
+    for value in values {
+        put_u64(&mut extra, *value);
+    }
+    extra
+}
+
+/// MS-DOS timestamps start in 1980, anything earlier is clamped.
+fn to_dos(time: NaiveDateTime) -> (u16, u16) {
+    let year = time.year().clamp(1980, 2107) as u16;
+    let dos_time =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    let dos_date = ((year - 1980) << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    (dos_time, dos_date)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek};
+
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// The archive as it went out. Runs of zeros are only counted, so entries past 4 GiB
+    /// don't need that much memory.
+    #[derive(Default)]
+    struct Streamed {
+        parts: Vec<(u64, Option<Bytes>)>,
+        len: u64,
+        pos: u64,
+    }
+
+    impl Streamed {
+        async fn collect(mut rx: mpsc::Receiver<ZipChunk>) -> Streamed {
+            let mut streamed = Streamed::default();
+            while let Some(chunk) = rx.next().await {
+                let chunk = chunk.unwrap();
+                let len = chunk.len() as u64;
+                let zeros = chunk.len() == CHUNK_SIZE && chunk.iter().all(|b| *b == 0);
+                streamed
+                    .parts
+                    .push((streamed.len, (!zeros).then_some(chunk)));
+                streamed.len += len;
+            }
+            streamed
+        }
+    }
+
+    impl Read for Streamed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos >= self.len {
+                return Ok(0);
+            }
+            let index = self.parts.partition_point(|(start, _)| *start <= self.pos) - 1;
+            let (start, part) = &self.parts[index];
+            let end = self
+                .parts
+                .get(index + 1)
+                .map_or(self.len, |(next, _)| *next);
+            let skip = (self.pos - start) as usize;
+            let read = buf.len().min((end - self.pos) as usize);
+            match part {
+                Some(bytes) => buf[..read].copy_from_slice(&bytes[skip..skip + read]),
+                None => buf[..read].fill(0),
+            }
+            self.pos += read as u64;
+            Ok(read)
+        }
+    }
+
+    impl Seek for Streamed {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                io::SeekFrom::Start(pos) => pos,
+                io::SeekFrom::End(delta) => self.len.checked_add_signed(delta).unwrap(),
+                io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta).unwrap(),
+            };
+            Ok(self.pos)
+        }
+    }
+
+    fn recorded() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("210319_1833", "%y%m%d_%H%M").unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_back() {
+        let path = std::env::temp_dir().join(format!("repan-zip-{}.wav", std::process::id()));
+        let stem: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &stem).unwrap();
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut zip = ZipStream::new(tx, recorded());
+        let writer = async {
+            zip.add_bytes("manifest.json", b"{}").await?;
+            zip.add_file("01-KaggeN-210319_1833.wav", &path).await?;
+            zip.finish().await
+        };
+        let (written, streamed) = tokio::join!(writer, Streamed::collect(rx));
+        let _ = std::fs::remove_file(&path);
+        written.unwrap();
+
+        let mut archive = zip::ZipArchive::new(streamed).unwrap();
+        assert_eq!(archive.len(), 2);
+        let expected: [(&str, &[u8]); 2] = [
+            ("manifest.json", b"{}"),
+            ("01-KaggeN-210319_1833.wav", &stem),
+        ];
+        for (index, (name, data)) in expected.into_iter().enumerate() {
+            let mut entry = archive.by_index(index).unwrap();
+            assert_eq!(entry.name(), name);
+            assert_eq!(entry.crc32(), crc32fast::hash(data));
+            let mut read = vec![];
+            entry.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "streams more than 4 GiB, run with --ignored"]
+    async fn entries_past_4_gib() {
+        let path = std::env::temp_dir().join(format!("repan-zip64-{}.wav", std::process::id()));
+        let size = u32::MAX as u64 + CHUNK_SIZE as u64;
+        std::fs::File::create(&path).unwrap().set_len(size).unwrap();
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut zip = ZipStream::new(tx, recorded());
+        let writer = async {
+            zip.add_file("01-KaggeN-210319_1833.wav", &path).await?;
+            zip.add_bytes("after.txt", b"past the 4 GiB mark").await?;
+            zip.finish().await
+        };
+        let (written, streamed) = tokio::join!(writer, Streamed::collect(rx));
+        let _ = std::fs::remove_file(&path);
+        written.unwrap();
+
+        let mut archive = zip::ZipArchive::new(streamed).unwrap();
+        let mut big = archive.by_index(0).unwrap();
+        assert_eq!(big.name(), "01-KaggeN-210319_1833.wav");
+        assert_eq!(big.size(), size);
+        // Reading to the end checks the CRC.
+        assert_eq!(io::copy(&mut big, &mut io::sink()).unwrap(), size);
+        drop(big);
+        let mut after = archive.by_index(1).unwrap();
+        assert_eq!(after.name(), "after.txt");
+        assert!(after.header_start() > u32::MAX as u64);
+        assert_eq!(after.crc32(), crc32fast::hash(b"past the 4 GiB mark"));
+        let mut read = String::new();
+        after.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "past the 4 GiB mark");
+    }
+}
@@ -0,0 +1,145 @@
+use leptos::prelude::*;
+
+use crate::backend::auth::current_user;
+use crate::backend::bands::{
+    get_band_members, get_bands, AddBandRoot, CreateBand, MyBand, RemoveBandMember, SetBandMember,
+};
+use crate::backend::database::Role;
+use crate::frontend::login::ActionError;
+
+#[component]
+pub fn BandsPage() -> impl IntoView {
+    let user = Resource::new(|| (), |_| current_user());
+    let create_band = ServerAction::<CreateBand>::new();
+    let add_root = ServerAction::<AddBandRoot>::new();
+    let bands = Resource::new(
+        move || (create_band.version().get(), add_root.version().get()),
+        |_| get_bands(),
+    );
+    let site_admin =
+        Signal::derive(move || matches!(user.get(), Some(Ok(Some(user))) if user.admin));
+
+    view! {
+        <h1>"Bands"</h1>
+        <nav>
+            <a href="/">"Home"</a>
+        </nav>
+        <Show when=move || site_admin.get()>
+            <h2>"New band"</h2>
+            <ActionForm action=create_band>
+                <input type="text" name="name" placeholder="Name" required />
+                <input type="submit" value="Create" />
+            </ActionForm>
+            <ActionError value=create_band.value() />
+        </Show>
+        <Suspense fallback=|| view! { <p>"Loading..."</p> }>
+            {move || Suspend::new(async move {
+                match bands.await {
+                    Ok(bands) if bands.is_empty() => {
+                        view! { <p>"You aren't in any band yet."</p> }.into_any()
+                    }
+                    Ok(bands) => {
+                        bands
+                            .into_iter()
+                            .map(|band| view! { <BandCard band add_root site_admin /> })
+                            .collect_view()
+                            .into_any()
+                    }
+                    Err(e) => view! { <p class="error">{e.to_string()}</p> }.into_any(),
+                }
+            })}
+        </Suspense>
+        <ActionError value=add_root.value() />
+    }
+}
+
+#[component]
+fn BandCard(
+    band: MyBand,
+    add_root: ServerAction<AddBandRoot>,
+    site_admin: Signal<bool>,
+) -> impl IntoView {
+    let MyBand { band, role } = band;
+    let band_id = band.id;
+
+    view! {
+        <section class="band">
+            <h2>{band.name}</h2>
+            <p>"Your role: "{role.map_or("not a member", |role| role.as_str())}</p>
+            <ul class="roots">
+                {band
+                    .roots
+                    .into_iter()
+                    .map(|root| view! { <li>{root}</li> })
+                    .collect_view()}
+            </ul>
+            <Show when=move || site_admin.get()>
+                <ActionForm action=add_root>
+                    <input type="hidden" name="band_id" value=band_id />
+                    <input type="text" name="root" placeholder="/library/band/" required />
+                    <input type="submit" value="Add library folder" />
+                </ActionForm>
+            </Show>
+            <Show when=move || role == Some(Role::Admin) || site_admin.get()>
+                <Members band_id />
+            </Show>
+        </section>
+    }
+}
+
+#[component]
+fn Members(band_id: i64) -> impl IntoView {
+    let set_member = ServerAction::<SetBandMember>::new();
+    let remove_member = ServerAction::<RemoveBandMember>::new();
+    let members = Resource::new(
+        move || (set_member.version().get(), remove_member.version().get()),
+        move |_| get_band_members(band_id),
+    );
+
+    view! {
+        <h3>"Members"</h3>
+        <Suspense fallback=|| view! { <p>"Loading..."</p> }>
+            {move || Suspend::new(async move {
+                match members.await {
+                    Ok(members) => {
+                        view! {
+                            <ul class="members">
+                                {members
+                                    .into_iter()
+                                    .map(|member| {
+                                        view! {
+                                            <li>
+                                                {member.username}" ("{member.role.as_str()}")"
+                                                <ActionForm action=remove_member>
+                                                    <input type="hidden" name="band_id" value=band_id />
+                                                    <input type="hidden" name="user_id" value=member.user_id />
+                                                    <input type="submit" value="Remove" />
+                                                </ActionForm>
+                                            </li>
+                                        }
+                                    })
+                                    .collect_view()}
+                            </ul>
+                        }
+                            .into_any()
+                    }
+                    Err(e) => view! { <p class="error">{e.to_string()}</p> }.into_any(),
+                }
+            })}
+        </Suspense>
+        <p>"Adding someone who is already a member changes their role."</p>
+        <ActionForm action=set_member>
+            <input type="hidden" name="band_id" value=band_id />
+            <input type="text" name="username" placeholder="Username" required />
+            <select name="role">
+                {Role::ALL
+                    .into_iter()
+                    .map(|role| view! { <option value=role.as_str()>{role.as_str()}</option> })
+                    .collect_view()}
+            </select>
+            <input type="submit" value="Add or change" />
+        </ActionForm>
+        <ActionError value=set_member.value() />
+        <ActionError value=remove_member.value() />
+    }
+}
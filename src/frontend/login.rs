@@ -0,0 +1,65 @@
+use leptos::prelude::*;
+
+use crate::backend::auth::{Login, Logout, Register};
+
+#[component]
+pub fn LoginPage() -> impl IntoView {
+    let login = ServerAction::<Login>::new();
+    let register = ServerAction::<Register>::new();
+
+    view! {
+        <h1>"Log in to Repan"</h1>
+        <ActionForm action=login>
+            <input type="text" name="username" placeholder="Username" autocomplete="username" required />
+            <input
+                type="password"
+                name="password"
+                placeholder="Password"
+                autocomplete="current-password"
+                required
+            />
+            <input type="submit" value="Log in" />
+        </ActionForm>
+        <ActionError value=login.value() />
+
+        <h2>"New here?"</h2>
+        <p>"The first account can be created right here, after that ask an admin to add you."</p>
+        <ActionForm action=register>
+            <input type="text" name="username" placeholder="Username" autocomplete="username" required />
+            <input
+                type="password"
+                name="password"
+                placeholder="Password"
+                autocomplete="new-password"
+                minlength="8"
+                required
+            />
+            <input type="submit" value="Create account" />
+        </ActionForm>
+        <ActionError value=register.value() />
+    }
+}
+
+#[component]
+pub fn LogoutButton() -> impl IntoView {
+    let logout = ServerAction::<Logout>::new();
+
+    view! {
+        <ActionForm action=logout>
+            <input type="submit" value="Log out" />
+        </ActionForm>
+    }
+}
+
+#[component]
+pub fn ActionError<T>(value: RwSignal<Option<Result<T, ServerFnError>>>) -> impl IntoView
+where
+    T: Send + Sync + 'static,
+{
+    move || {
+        value.with(|value| match value {
+            Some(Err(e)) => Some(view! { <p class="error">{e.to_string()}</p> }),
+            _ => None,
+        })
+    }
+}
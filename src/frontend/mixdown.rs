@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use leptos::{logging::log, prelude::*, task::spawn_local};
+
+use crate::backend::render::{
+    get_render_status, start_render, RenderFormat, RenderState, RenderStatus,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[component]
+pub fn Mixdown(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
+    let (format, set_format) = signal(RenderFormat::Wav);
+    let (status, set_status) = signal::<Option<RenderStatus>>(None);
+    let (error, set_error) = signal::<Option<String>>(None);
+
+    let poll = move |id: String| {
+        let handle = StoredValue::new(None::<IntervalHandle>);
+        let interval = set_interval_with_handle(
+            move || {
+                let id = id.clone();
+                spawn_local(async move {
+                    match get_render_status(id).await {
+                        Ok(current) => {
+                            let finished = matches!(
+                                current.state,
+                                RenderState::Done | RenderState::Failed
+                            );
+                            set_status.set(Some(current));
+                            if finished {
+                                if let Some(handle) = handle.get_value() {
+                                    handle.clear();
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log!("Render status failed: {:?}", e);
+                            set_error.set(Some(e.to_string()));
+                            if let Some(handle) = handle.get_value() {
+                                handle.clear();
+                            }
+                        }
+                    }
+                });
+            },
+            POLL_INTERVAL,
+        );
+        handle.set_value(interval.ok());
+    };
+
+    let export = move |_| {
+        set_error.set(None);
+        let jam_id = selected_jam_id.get();
+        let format = format.get();
+        spawn_local(async move {
+            match start_render(jam_id, format, vec![]).await {
+                Ok(started) => {
+                    let id = started.id.clone();
+                    set_status.set(Some(started));
+                    poll(id);
+                }
+                Err(e) => set_error.set(Some(e.to_string())),
+            }
+        });
+    };
+
+    let rendering = move || {
+        status.with(|s| {
+            s.as_ref()
+                .is_some_and(|s| matches!(s.state, RenderState::Queued | RenderState::Rendering))
+        })
+    };
+
+    view! {
+        <div class="mixdown">
+            <select on:change=move |ev| {
+                let value = event_target_value(&ev);
+                if let Some(f) = RenderFormat::ALL.into_iter().find(|f| f.extension() == value) {
+                    set_format.set(f);
+                }
+            }>
+                {RenderFormat::ALL
+                    .into_iter()
+                    .map(|f| view! { <option value=f.extension()>{f.extension().to_uppercase()}</option> })
+                    .collect::<Vec<_>>()}
+            </select>
+            <button on:click=export disabled=rendering>"Export mix"</button>
+            {move || {
+                status
+                    .get()
+                    .map(|s| match (s.state, s.download_url) {
+                        (RenderState::Done, Some(url)) => {
+                            view! { <a href=url download>"Download mix"</a> }.into_any()
+                        }
+                        (RenderState::Failed, _) => {
+                            view! { <p>{format!("Export failed: {}", s.error.unwrap_or_default())}</p> }
+                                .into_any()
+                        }
+                        _ => view! { <progress max="1" value=s.progress.to_string()></progress> }.into_any(),
+                    })
+            }}
+            {move || error.get().map(|e| view! { <p>{e}</p> })}
+        </div>
+    }
+}
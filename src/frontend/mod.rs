@@ -1,5 +1,12 @@
+pub mod bands;
 pub mod jamselector;
+pub mod login;
+pub mod mixdown;
+pub mod sessions;
+pub mod share;
 pub mod sidebar;
 pub mod track_list;
+pub mod transport;
+pub mod upload;
 pub mod calendar;
 pub mod webrtc;
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use leptos::prelude::*;
+
+use crate::backend::client_connections::get_sessions;
+use crate::frontend::share::format_time;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Live listening sessions of every user, for site admins.
+#[component]
+pub fn SessionsPage() -> impl IntoView {
+    let (tick, set_tick) = signal(0u32);
+    let sessions = Resource::new(move || tick.get(), |_| get_sessions());
+    Effect::new(move |_| {
+        let interval =
+            set_interval_with_handle(move || set_tick.update(|tick| *tick += 1), REFRESH_INTERVAL);
+        if let Ok(interval) = interval {
+            on_cleanup(move || interval.clear());
+        }
+    });
+
+    view! {
+        <h1>"Sessions"</h1>
+        <nav>
+            <a href="/">"Home"</a>
+        </nav>
+        <Transition fallback=|| view! { <p>"Loading..."</p> }>
+            {move || Suspend::new(async move {
+                match sessions.await {
+                    Ok(sessions) if sessions.is_empty() => {
+                        view! { <p>"Nobody is listening."</p> }.into_any()
+                    }
+                    Ok(sessions) => {
+                        view! {
+                            <table class="sessions">
+                                <tr>
+                                    <th>"User"</th>
+                                    <th>"State"</th>
+                                    <th>"Streamer"</th>
+                                    <th>"Opened"</th>
+                                    <th>"Since"</th>
+                                </tr>
+                                {sessions
+                                    .into_iter()
+                                    .map(|session| {
+                                        view! {
+                                            <tr>
+                                                <td>{session.user}</td>
+                                                <td>{session.state.as_str()}</td>
+                                                <td>{session.streamer.unwrap_or_default()}</td>
+                                                <td>{format_time(session.opened_at)}</td>
+                                                <td>{format_time(session.changed_at)}</td>
+                                            </tr>
+                                        }
+                                    })
+                                    .collect_view()}
+                            </table>
+                        }
+                            .into_any()
+                    }
+                    Err(e) => view! { <p class="error">{e.to_string()}</p> }.into_any(),
+                }
+            })}
+        </Transition>
+    }
+}
@@ -0,0 +1,404 @@
+use std::time::Duration;
+
+use leptos::{html, logging::log, prelude::*, task::spawn_local};
+use leptos_router::hooks::use_params_map;
+use wasm_bindgen::JsCast;
+use web_sys::AudioContext;
+
+use crate::backend::database::{MixPreset, TrackMix};
+use crate::backend::render::{RenderState, RenderStatus};
+use crate::backend::serverfunctions::get_mix_presets;
+use crate::backend::share::{
+    create_share, get_shared_jam, get_shares, prepare_shared_mix, revoke_share_link, SharedJam,
+    SharedTrack, MAX_SHARE_DAYS,
+};
+
+const DEFAULT_SHARE_DAYS: u32 = 7;
+const MIX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Lets members hand out links to the selected jam, and keep an eye on the ones they made.
+#[component]
+pub fn ShareJam(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
+    let presets = Resource::new(move || selected_jam_id.get(), get_mix_presets);
+    let (preset, set_preset) = signal(None::<i64>);
+    let (allow_download, set_allow_download) = signal(false);
+    let (days, set_days) = signal(DEFAULT_SHARE_DAYS);
+
+    let create = Action::new(|args: &(i64, Option<i64>, bool, u32)| {
+        let (jam_id, preset, allow_download, days) = *args;
+        create_share(jam_id, preset, allow_download, days)
+    });
+    let revoke = Action::new(|id: &i64| revoke_share_link(*id));
+    let shares = Resource::new(
+        move || (create.version().get(), revoke.version().get()),
+        |_| get_shares(),
+    );
+
+    view! {
+        <div class="share">
+            <h3>"Share this jam"</h3>
+            <select on:change=move |ev| set_preset.set(event_target_value(&ev).parse().ok())>
+                <option value="">"Every track as recorded"</option>
+                <Suspense>
+                    {move || Suspend::new(async move {
+                        presets
+                            .await
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|p: MixPreset| view! { <option value=p.id>{p.name}</option> })
+                            .collect_view()
+                    })}
+                </Suspense>
+            </select>
+            <label>
+                <input
+                    type="checkbox"
+                    on:change=move |ev| set_allow_download.set(event_target_checked(&ev))
+                />
+                "Allow downloading the stems"
+            </label>
+            <label>
+                "Expires after "
+                <input
+                    type="number"
+                    min="1"
+                    max=MAX_SHARE_DAYS
+                    prop:value=move || days.get()
+                    on:change=move |ev| {
+                        if let Ok(d) = event_target_value(&ev).parse() {
+                            set_days.set(d);
+                        }
+                    }
+                />
+                " days"
+            </label>
+            <button on:click=move |_| {
+                create
+                    .dispatch((
+                        selected_jam_id.get(),
+                        preset.get(),
+                        allow_download.get(),
+                        days.get(),
+                    ));
+            }>"Create link"</button>
+            {move || {
+                create
+                    .value()
+                    .get()
+                    .and_then(|r| r.err())
+                    .map(|e| view! { <p class="error">{e.to_string()}</p> })
+            }}
+            <h3>"Your links"</h3>
+            <Suspense fallback=|| view! { <p>"Loading..."</p> }>
+                {move || Suspend::new(async move {
+                    match shares.await {
+                        Ok(shares) => {
+                            view! {
+                                <table class="shares">
+                                    <tr>
+                                        <th>"Jam"</th>
+                                        <th>"Mix"</th>
+                                        <th>"Views"</th>
+                                        <th>"Expires"</th>
+                                        <th></th>
+                                    </tr>
+                                    {shares
+                                        .into_iter()
+                                        .map(|share| {
+                                            let link = share.link;
+                                            let url = format!("/share/{}", share.token);
+                                            let id = link.id;
+                                            let mut mix = link
+                                                .preset_name
+                                                .unwrap_or_else(|| "As recorded".to_string());
+                                            if link.allow_download {
+                                                mix.push_str(", downloadable");
+                                            }
+                                            view! {
+                                                <tr>
+                                                    <td>
+                                                        <a href=url>{link.jam_date}</a>
+                                                    </td>
+                                                    <td>{mix}</td>
+                                                    <td>{link.views}</td>
+                                                    <td>{format_time(link.expires_at)}</td>
+                                                    <td>
+                                                        {if link.revoked {
+                                                            view! { "Revoked" }.into_any()
+                                                        } else {
+                                                            view! {
+                                                                <button on:click=move |_| {
+                                                                    revoke.dispatch(id);
+                                                                }>"Revoke"</button>
+                                                            }
+                                                                .into_any()
+                                                        }}
+                                                    </td>
+                                                </tr>
+                                            }
+                                        })
+                                        .collect_view()}
+                                </table>
+                            }
+                                .into_any()
+                        }
+                        Err(e) => view! { <p class="error">{e.to_string()}</p> }.into_any(),
+                    }
+                })}
+            </Suspense>
+        </div>
+    }
+}
+
+/// The page a share link opens, no account needed.
+#[component]
+pub fn SharePage() -> impl IntoView {
+    let params = use_params_map();
+    let token = move || params.read().get("token").unwrap_or_default();
+    let shared = Resource::new(token, get_shared_jam);
+
+    view! {
+        <Suspense fallback=|| view! { <p>"Loading..."</p> }>
+            {move || Suspend::new(async move {
+                match shared.await {
+                    Ok(jam) => {
+                        view! {
+                            <h1>{format!("Jam {}", jam.date)}</h1>
+                            {jam.preset_name.clone().map(|name| view! { <p>"Mix: "{name}</p> })}
+                            <p>"Link valid until "{format_time(jam.expires_at)}</p>
+                            {if jam.allow_download {
+                                view! { <SharedPlayer token=token() jam /> }.into_any()
+                            } else {
+                                view! { <SharedMix token=token() /> }.into_any()
+                            }}
+                        }
+                            .into_any()
+                    }
+                    Err(e) => {
+                        view! {
+                            <h1>"Repan"</h1>
+                            <p class="error">{e.to_string()}</p>
+                        }
+                            .into_any()
+                    }
+                }
+            })}
+        </Suspense>
+    }
+}
+
+/// Links that don't allow downloads only get the mix the server rendered with the preset.
+#[component]
+fn SharedMix(token: String) -> impl IntoView {
+    let (status, set_status) = signal::<Option<RenderStatus>>(None);
+    let (error, set_error) = signal::<Option<String>>(None);
+
+    // Asking again is how progress is polled, the server keeps one render per link.
+    let handle = StoredValue::new(None::<IntervalHandle>);
+    let stop = move || {
+        if let Some(handle) = handle.get_value() {
+            handle.clear();
+        }
+    };
+    let check = move |token: String| {
+        spawn_local(async move {
+            match prepare_shared_mix(token).await {
+                Ok(current) => {
+                    if matches!(current.state, RenderState::Done | RenderState::Failed) {
+                        stop();
+                    }
+                    set_status.set(Some(current));
+                }
+                Err(e) => {
+                    log!("Shared mix failed: {:?}", e);
+                    set_error.set(Some(e.to_string()));
+                    stop();
+                }
+            }
+        });
+    };
+    // Effects only run in the browser, the server has nothing to poll.
+    Effect::new(move |_| {
+        check(token.clone());
+        let token = token.clone();
+        let interval = set_interval_with_handle(move || check(token.clone()), MIX_POLL_INTERVAL);
+        handle.set_value(interval.ok());
+    });
+    on_cleanup(stop);
+
+    view! {
+        {move || {
+            status
+                .get()
+                .map(|s| match (s.state, s.download_url) {
+                    (RenderState::Done, Some(url)) => {
+                        view! { <audio controls preload="auto" src=url></audio> }.into_any()
+                    }
+                    (RenderState::Failed, _) => {
+                        view! {
+                            <p class="error">
+                                {format!("Mixing failed: {}", s.error.unwrap_or_default())}
+                            </p>
+                        }
+                            .into_any()
+                    }
+                    _ => {
+                        view! {
+                            <p>"Mixing..."</p>
+                            <progress max="1" value=s.progress.to_string()></progress>
+                        }
+                            .into_any()
+                    }
+                })
+        }}
+        {move || error.get().map(|e| view! { <p class="error">{e}</p> })}
+    }
+}
+
+/// Plays every stem of the jam at once, mixed in the browser the way the preset says. Only for
+/// links that allow downloads, the stems are served as they are.
+#[component]
+fn SharedPlayer(token: String, jam: SharedJam) -> impl IntoView {
+    let players: Vec<(SharedTrack, NodeRef<html::Audio>)> = jam
+        .tracks
+        .iter()
+        .map(|track| (track.clone(), NodeRef::new()))
+        .collect();
+    let mix = jam.mix.clone();
+    let elements = StoredValue::new(players.iter().map(|(_, node)| *node).collect::<Vec<_>>());
+    // Created on the first click, browsers don't allow audio before a user gesture.
+    let context = StoredValue::new_local(None::<AudioContext>);
+    let (playing, set_playing) = signal(false);
+    let (position, set_position) = signal(0.0);
+    let (duration, set_duration) = signal(0.0);
+
+    let each_element = move |f: &dyn Fn(&web_sys::HtmlAudioElement)| {
+        for node in elements.get_value() {
+            if let Some(element) = node.get_untracked() {
+                f(&element);
+            }
+        }
+    };
+
+    let toggle = {
+        let players = players.clone();
+        move |_| {
+            if context.with_value(|c| c.is_none()) {
+                match connect_mix(&players, &mix) {
+                    Ok(ctx) => context.set_value(Some(ctx)),
+                    Err(e) => {
+                        log!("Couldn't set up the mix: {:?}", e);
+                        return;
+                    }
+                }
+            }
+            if playing.get_untracked() {
+                each_element(&|element| {
+                    let _ = element.pause();
+                });
+                set_playing.set(false);
+            } else {
+                let at = position.get_untracked();
+                each_element(&|element| {
+                    element.set_current_time(at);
+                    let _ = element.play();
+                });
+                set_playing.set(true);
+            }
+        }
+    };
+    let seek = move |ev| {
+        if let Ok(at) = event_target_value(&ev).parse::<f64>() {
+            set_position.set(at);
+            each_element(&|element| element.set_current_time(at));
+        }
+    };
+
+    let download = jam
+        .allow_download
+        .then(|| format!("/api/v1/shared/{}/stems.zip", token));
+
+    view! {
+        <button on:click=toggle>{move || if playing.get() { "Pause" } else { "Play" }}</button>
+        <input
+            type="range"
+            min="0"
+            step="0.1"
+            max=move || duration.get()
+            prop:value=move || position.get()
+            on:change=seek
+        />
+        <span>{move || format!("{:.0}s / {:.0}s", position.get(), duration.get())}</span>
+        <ul class="tracks">
+            {players
+                .into_iter()
+                .enumerate()
+                .map(|(index, (track, node))| {
+                    let src = format!("/api/v1/shared/{}/tracks/{}/audio", token, track.id);
+                    // The stems are equally long, the first one keeps time for all of them.
+                    let is_first = index == 0;
+                    view! {
+                        <li>
+                            {track.name}
+                            <audio
+                                node_ref=node
+                                src=src
+                                preload="auto"
+                                on:timeupdate=move |ev| {
+                                    if is_first {
+                                        let element: web_sys::HtmlMediaElement = event_target(&ev);
+                                        set_position.set(element.current_time());
+                                    }
+                                }
+                                on:loadedmetadata=move |ev| {
+                                    let element: web_sys::HtmlMediaElement = event_target(&ev);
+                                    set_duration.update(|d| *d = d.max(element.duration()));
+                                }
+                                on:ended=move |_| {
+                                    if is_first {
+                                        set_playing.set(false);
+                                    }
+                                }
+                            ></audio>
+                        </li>
+                    }
+                })
+                .collect_view()}
+        </ul>
+        {download.map(|url| view! { <a href=url download>"Download stems"</a> })}
+    }
+}
+
+/// Routes every track through its own gain and panner, set once from the locked preset.
+fn connect_mix(
+    players: &[(SharedTrack, NodeRef<html::Audio>)],
+    mix: &[TrackMix],
+) -> Result<AudioContext, wasm_bindgen::JsValue> {
+    let ctx = AudioContext::new()?;
+    for (track, node) in players {
+        let Some(element) = node.get_untracked() else {
+            continue;
+        };
+        let (volume, pan) = match mix.iter().find(|m| m.track == track.name) {
+            Some(m) if m.mute => (0.0, m.pan),
+            Some(m) => (m.volume, m.pan),
+            None => (1.0, 0.0),
+        };
+        let source = ctx.create_media_element_source(element.unchecked_ref())?;
+        let gain = ctx.create_gain()?;
+        gain.gain().set_value(volume as f32);
+        let panner = ctx.create_stereo_panner()?;
+        panner.pan().set_value(pan as f32);
+        source
+            .connect_with_audio_node(&gain)?
+            .connect_with_audio_node(&panner)?
+            .connect_with_audio_node(&ctx.destination())?;
+    }
+    Ok(ctx)
+}
@@ -1,32 +1,166 @@
+use std::collections::HashMap;
+
 use crate::backend::{
-    client_connections::GstJsonMsg,
+    client_connections::change_jam,
     database::JamQueryResult,
     serverfunctions::{get_jam, get_tracks_and_path, MultiQuery},
 };
-use leptos::{logging::log, prelude::*};
+use crate::frontend::webrtc::send_to_streamer;
+use leptos::{logging::log, prelude::*, reactive::spawn_local};
+use repan_protocol::{DataChannelMsg, TrackChange, TrackState};
 use web_sys::RtcDataChannel;
 
+/// The live mix as the streamer last confirmed it, by track index. The sliders only move once
+/// the streamer applied a change.
+#[derive(Clone, Copy)]
+pub struct LiveMix {
+    pub tracks: RwSignal<HashMap<usize, TrackState>>,
+    /// Why the last change was refused.
+    pub refused: RwSignal<Option<String>>,
+}
+
+impl Default for LiveMix {
+    fn default() -> Self {
+        LiveMix {
+            tracks: RwSignal::new(HashMap::new()),
+            refused: RwSignal::new(None),
+        }
+    }
+}
+
+impl LiveMix {
+    /// Takes in the streamer's answer to a `SetTrack`.
+    pub fn confirmed(&self, msg: DataChannelMsg) {
+        match msg {
+            DataChannelMsg::TrackSet { index, state } => {
+                self.tracks.update(|tracks| {
+                    tracks.insert(index, state);
+                });
+                self.refused.set(None);
+            }
+            DataChannelMsg::TrackRefused { index, reason } => {
+                log!("Track {} unchanged: {}", index, reason);
+                self.refused.set(Some(reason));
+            }
+            _ => (),
+        }
+    }
+
+    /// Every track as recorded, the way the streamer starts a jam.
+    pub fn reset(&self) {
+        self.tracks.set(HashMap::new());
+        self.refused.set(None);
+    }
+
+    fn track(&self, index: usize) -> TrackState {
+        self.tracks
+            .with(|tracks| tracks.get(&index).copied())
+            .unwrap_or_default()
+    }
+}
+
+#[component]
+fn TrackControls(index: usize, name: String) -> impl IntoView {
+    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
+        .expect("to have found the data channel");
+    let mix = use_context::<LiveMix>().expect("to have found the live mix");
+    let state = move || mix.track(index);
+    let change = move |change: TrackChange| {
+        send_to_streamer(
+            dc.get_untracked(),
+            &DataChannelMsg::SetTrack { index, change },
+        )
+    };
+
+    view! {
+        <div class="track">
+            <span class="track-name">{name}</span>
+            <label>
+                "Volume"
+                <input
+                    type="range"
+                    min="0"
+                    max="2"
+                    step="0.01"
+                    prop:value=move || state().volume
+                    on:input=move |ev| {
+                        if let Ok(volume) = event_target_value(&ev).parse() {
+                            change(TrackChange {
+                                volume: Some(volume),
+                                ..TrackChange::default()
+                            });
+                        }
+                    }
+                />
+            </label>
+            <label>
+                "Pan"
+                <input
+                    type="range"
+                    min="-1"
+                    max="1"
+                    step="0.01"
+                    prop:value=move || state().pan
+                    on:input=move |ev| {
+                        if let Ok(pan) = event_target_value(&ev).parse() {
+                            change(TrackChange {
+                                pan: Some(pan),
+                                ..TrackChange::default()
+                            });
+                        }
+                    }
+                />
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || state().mute
+                    on:change=move |ev| {
+                        change(TrackChange {
+                            mute: Some(event_target_checked(&ev)),
+                            ..TrackChange::default()
+                        })
+                    }
+                />
+                "Mute"
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || state().solo
+                    on:change=move |ev| {
+                        change(TrackChange {
+                            solo: Some(event_target_checked(&ev)),
+                            ..TrackChange::default()
+                        })
+                    }
+                />
+                "Solo"
+            </label>
+        </div>
+    }
+}
+
 #[component]
 pub fn TrackList(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
+    let mix = use_context::<LiveMix>().expect("to have found the live mix");
     let multi_query = Resource::new(
         move || selected_jam_id.get(),
         move |_| async move { get_jam(selected_jam_id.get()).await },
     );
-    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
-        .expect("to have found getter");
     Effect::new(move |_| {
+        // The server resolves the jam and tells the streamer, only once we know it exists.
         if let Some(Ok(mq)) = multi_query.get() {
-            let path = mq.path.unwrap().data;
-            let date = mq.date.unwrap().data;
-            let tracks = mq
-                .tracks
-                .unwrap()
-                .iter()
-                .map(|v| v.data.clone())
-                .collect::<Vec<String>>();
-            let json = GstJsonMsg::ChangeJam { path, date, tracks };
-            let dc = dc.get().unwrap();
-            dc.send_with_str(serde_json::to_string(&json).unwrap().as_str());
+            let Some(jam_id) = mq.id else {
+                return;
+            };
+            spawn_local(async move {
+                match change_jam(jam_id).await {
+                    // The streamer starts the new jam's tracks as recorded.
+                    Ok(()) => mix.reset(),
+                    Err(e) => log!("Couldn't change jam: {:?}", e),
+                }
+            });
         }
 
         //if let Some(Ok(res)) = tracks_res.get() {
@@ -71,10 +205,29 @@ pub fn TrackList(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
                     }
                 };
 
+                // The streamer knows the tracks by their position in this list.
+                let tracks: Vec<(usize, JamQueryResult<String>)> = tracks
+                    .into_iter()
+                    .enumerate()
+                    .collect();
                 view! {
-                    <For each=move || tracks.clone() key=|state| state.id.clone() let(child)>
-                        <div>{child.data.clone()}</div>
+                    <For
+                        each=move || tracks.clone()
+                        key=|(_, track)| track.id
+                        let(entry)
+                    >
+                        <TrackControls index=entry.0 name=entry.1.data.clone() />
                     </For>
+                    {move || {
+                        mix.refused.get().map(|reason| view! { <p class="error">{reason}</p> })
+                    }}
+                    <a
+                        class="stem-download"
+                        href=move || format!("/api/v1/jams/{}/stems.zip", selected_jam_id.get())
+                        download
+                    >
+                        "Download stems"
+                    </a>
                 }
             })}
         </Suspense>
@@ -0,0 +1,403 @@
+use leptos::{logging::log, prelude::*, task::spawn_local};
+use repan_protocol::{DataChannelMsg, LoopRegion, Transpose, MAX_PLAYBACK_RATE, MIN_PLAYBACK_RATE};
+use web_sys::RtcDataChannel;
+
+use crate::backend::database::{Marker, UserMixSettings};
+use crate::backend::serverfunctions::{get_markers, get_mix_settings, save_mix_settings};
+use crate::frontend::webrtc::send_to_streamer;
+
+/// How far the skip buttons move the playhead.
+const SKIP_MS: i64 = 10_000;
+
+/// Where the streamer last said playback is.
+#[derive(Clone, Copy)]
+pub struct Playhead {
+    pub position_ms: RwSignal<u64>,
+    /// Unknown until the stems are loaded.
+    pub duration_ms: RwSignal<Option<u64>>,
+    pub playing: RwSignal<bool>,
+    /// The loop as the streamer last confirmed it.
+    pub looping: RwSignal<Option<LoopRegion>>,
+    /// Why the last loop wasn't taken.
+    pub loop_refused: RwSignal<Option<String>>,
+    /// Speed of playback, 1.0 as recorded.
+    pub rate: RwSignal<f64>,
+    /// Why the last speed wasn't taken.
+    pub rate_refused: RwSignal<Option<String>>,
+    /// How far the streamer shifts the mix's pitch.
+    pub transpose: RwSignal<Transpose>,
+    /// Why the last transposition wasn't taken.
+    pub transpose_refused: RwSignal<Option<String>>,
+}
+
+impl Default for Playhead {
+    fn default() -> Self {
+        Playhead {
+            position_ms: RwSignal::new(0),
+            duration_ms: RwSignal::new(None),
+            playing: RwSignal::new(false),
+            looping: RwSignal::new(None),
+            loop_refused: RwSignal::new(None),
+            rate: RwSignal::new(1.0),
+            rate_refused: RwSignal::new(None),
+            transpose: RwSignal::new(Transpose::default()),
+            transpose_refused: RwSignal::new(None),
+        }
+    }
+}
+
+impl Playhead {
+    /// Takes in the streamer's `Position` reports and its answers about the loop, speed and key.
+    pub fn reported(&self, msg: DataChannelMsg) {
+        match msg {
+            DataChannelMsg::Position {
+                position_ms,
+                duration_ms,
+                playing,
+            } => {
+                self.position_ms.set(position_ms);
+                self.duration_ms.set(duration_ms);
+                self.playing.set(playing);
+            }
+            DataChannelMsg::LoopSet { region } => {
+                self.looping.set(region);
+                self.loop_refused.set(None);
+            }
+            DataChannelMsg::LoopRefused { reason } => self.loop_refused.set(Some(reason)),
+            DataChannelMsg::RateSet { rate } => {
+                self.rate.set(rate);
+                self.rate_refused.set(None);
+            }
+            DataChannelMsg::RateRefused { reason } => self.rate_refused.set(Some(reason)),
+            // Saved once the streamer took it, refused and invalid keys never are.
+            DataChannelMsg::TransposeSet { transpose } => {
+                if transpose != self.transpose.get_untracked() {
+                    spawn_local(async move {
+                        if let Err(e) = save_mix_settings(UserMixSettings { transpose }).await {
+                            log!("Couldn't save the mix settings: {:?}", e);
+                        }
+                    });
+                }
+                self.transpose.set(transpose);
+                self.transpose_refused.set(None);
+            }
+            DataChannelMsg::TransposeRefused { reason } => self.transpose_refused.set(Some(reason)),
+            _ => (),
+        }
+    }
+
+    /// Back to how a new pipeline starts, nothing loaded at normal speed in the original key.
+    pub fn reset(&self) {
+        self.position_ms.set(0);
+        self.duration_ms.set(None);
+        self.playing.set(false);
+        self.looping.set(None);
+        self.loop_refused.set(None);
+        self.rate.set(1.0);
+        self.rate_refused.set(None);
+        self.transpose.set(Transpose::default());
+        self.transpose_refused.set(None);
+    }
+}
+
+/// Sends the user's saved settings over a newly opened data channel.
+pub async fn restore_mix_settings(dc: RtcDataChannel) {
+    match get_mix_settings().await {
+        Ok(settings) if settings.transpose != Transpose::default() => send_to_streamer(
+            Some(dc),
+            &DataChannelMsg::SetTranspose {
+                transpose: settings.transpose,
+            },
+        ),
+        Ok(_) => (),
+        Err(e) => log!("Couldn't load the mix settings: {:?}", e),
+    }
+}
+
+/// `m:ss`, the jams are rarely longer than an hour.
+fn format_time(ms: u64) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Picks one end of the loop from the jam's markers.
+#[component]
+fn MarkerSelect(
+    label: &'static str,
+    markers: Vec<Marker>,
+    on_pick: impl Fn(u64) + 'static,
+) -> impl IntoView {
+    view! {
+        <select on:change=move |ev| {
+            if let Ok(position_ms) = event_target_value(&ev).parse() {
+                on_pick(position_ms);
+            }
+        }>
+            <option value="">{label}</option>
+            {markers
+                .into_iter()
+                .map(|marker| {
+                    let position_ms = marker.position_ms.max(0) as u64;
+                    view! {
+                        <option value=position_ms.to_string()>
+                            {format!("{} ({})", marker.name, format_time(position_ms))}
+                        </option>
+                    }
+                })
+                .collect::<Vec<_>>()}
+        </select>
+    }
+}
+
+/// Sets a loop from A to B and turns it on and off. Both ends are taken from the playhead or a
+/// marker, moving one while looping moves the loop right away.
+#[component]
+fn LoopControls(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
+    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
+        .expect("to have found the data channel");
+    let playhead = use_context::<Playhead>().expect("to have found the playhead");
+    let markers = Resource::new(move || selected_jam_id.get(), get_markers);
+    let start = RwSignal::new(None::<u64>);
+    let end = RwSignal::new(None::<u64>);
+    // The streamer clears the loop with the jam it belongs to.
+    Effect::new(move |_| {
+        selected_jam_id.track();
+        start.set(None);
+        end.set(None);
+    });
+
+    let send_loop = move || {
+        let (Some(start_ms), Some(end_ms)) = (start.get_untracked(), end.get_untracked()) else {
+            playhead
+                .loop_refused
+                .set(Some("Set both ends of the loop first".to_string()));
+            return;
+        };
+        match LoopRegion::new(start_ms, end_ms) {
+            Ok(region) => send_to_streamer(dc.get_untracked(), &DataChannelMsg::SetLoop { region }),
+            Err(reason) => playhead.loop_refused.set(Some(reason)),
+        }
+    };
+    let moved = move || {
+        if playhead.looping.get_untracked().is_some() {
+            send_loop();
+        }
+    };
+    let set_start = move |position_ms: u64| {
+        start.set(Some(position_ms));
+        moved();
+    };
+    let set_end = move |position_ms: u64| {
+        end.set(Some(position_ms));
+        moved();
+    };
+    let shown = |point: Option<u64>| point.map(format_time).unwrap_or_else(|| "-".to_string());
+
+    view! {
+        <div class="loop">
+            <button on:click=move |_| set_start(playhead.position_ms.get_untracked())>"A"</button>
+            <button on:click=move |_| set_end(playhead.position_ms.get_untracked())>"B"</button>
+            <Transition fallback=|| ()>
+                {move || Suspend::new(async move {
+                    let markers = markers.await.unwrap_or_default();
+                    view! {
+                        <MarkerSelect label="A at marker" markers=markers.clone() on_pick=set_start />
+                        <MarkerSelect label="B at marker" markers=markers on_pick=set_end />
+                    }
+                })}
+            </Transition>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || playhead.looping.get().is_some()
+                    on:change=move |ev| {
+                        if event_target_checked(&ev) {
+                            send_loop();
+                        } else {
+                            send_to_streamer(dc.get_untracked(), &DataChannelMsg::ClearLoop);
+                        }
+                    }
+                />
+                "Loop"
+            </label>
+            <span class="loop-region">
+                {move || format!("{} - {}", shown(start.get()), shown(end.get()))}
+            </span>
+            {move || {
+                playhead.loop_refused.get().map(|reason| view! { <p class="error">{reason}</p> })
+            }}
+        </div>
+    }
+}
+
+/// Slows the jam down for practising, or speeds it up, without changing its pitch.
+#[component]
+fn SpeedControl() -> impl IntoView {
+    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
+        .expect("to have found the data channel");
+    let playhead = use_context::<Playhead>().expect("to have found the playhead");
+    let set_rate =
+        move |rate: f64| send_to_streamer(dc.get_untracked(), &DataChannelMsg::SetRate { rate });
+
+    view! {
+        <div class="speed">
+            <label>
+                "Speed"
+                // Sent once let go, every change restarts playback at the new speed.
+                <input
+                    type="range"
+                    min=MIN_PLAYBACK_RATE
+                    max=MAX_PLAYBACK_RATE
+                    step="0.05"
+                    prop:value=move || playhead.rate.get()
+                    on:change=move |ev| {
+                        if let Ok(rate) = event_target_value(&ev).parse() {
+                            set_rate(rate);
+                        }
+                    }
+                />
+            </label>
+            <span class="speed-value">
+                {move || format!("{}%", (playhead.rate.get() * 100.0).round())}
+            </span>
+            <button on:click=move |_| set_rate(1.0) disabled=move || playhead.rate.get() == 1.0>
+                "Normal speed"
+            </button>
+            {move || {
+                playhead.rate_refused.get().map(|reason| view! { <p class="error">{reason}</p> })
+            }}
+        </div>
+    }
+}
+
+/// Shifts the key of the whole mix in semitones and cents. Kept for the user, the next session
+/// starts in the same key.
+#[component]
+fn TransposeControl() -> impl IntoView {
+    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
+        .expect("to have found the data channel");
+    let playhead = use_context::<Playhead>().expect("to have found the playhead");
+    let transpose = move |transpose: Transpose| {
+        if let Err(reason) = transpose.validate() {
+            playhead.transpose_refused.set(Some(reason));
+            return;
+        }
+        send_to_streamer(
+            dc.get_untracked(),
+            &DataChannelMsg::SetTranspose { transpose },
+        );
+    };
+
+    view! {
+        <div class="transpose">
+            <label>
+                "Semitones"
+                <input
+                    type="number"
+                    min={-Transpose::MAX_SEMITONES}
+                    max=Transpose::MAX_SEMITONES
+                    prop:value=move || playhead.transpose.get().semitones
+                    on:change=move |ev| {
+                        if let Ok(semitones) = event_target_value(&ev).parse() {
+                            transpose(Transpose {
+                                semitones,
+                                ..playhead.transpose.get_untracked()
+                            });
+                        }
+                    }
+                />
+            </label>
+            <label>
+                "Cents"
+                <input
+                    type="number"
+                    min="-99"
+                    max="99"
+                    prop:value=move || playhead.transpose.get().cents
+                    on:change=move |ev| {
+                        if let Ok(cents) = event_target_value(&ev).parse() {
+                            transpose(Transpose {
+                                cents,
+                                ..playhead.transpose.get_untracked()
+                            });
+                        }
+                    }
+                />
+            </label>
+            <button
+                on:click=move |_| transpose(Transpose::default())
+                disabled=move || playhead.transpose.get() == Transpose::default()
+            >
+                "Original key"
+            </button>
+            {move || {
+                playhead
+                    .transpose_refused
+                    .get()
+                    .map(|reason| view! { <p class="error">{reason}</p> })
+            }}
+        </div>
+    }
+}
+
+#[component]
+pub fn Transport(selected_jam_id: ReadSignal<i64>) -> impl IntoView {
+    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
+        .expect("to have found the data channel");
+    let playhead = use_context::<Playhead>().expect("to have found the playhead");
+    let send = move |msg: DataChannelMsg| send_to_streamer(dc.get_untracked(), &msg);
+    // While the slider is dragged it shows where it's held, not the streamer's reports.
+    let scrubbing = RwSignal::new(None::<u64>);
+    let shown = move || {
+        scrubbing
+            .get()
+            .unwrap_or_else(|| playhead.position_ms.get())
+    };
+
+    view! {
+        <div class="transport">
+            <button on:click=move |_| send(DataChannelMsg::SeekBy { offset_ms: -SKIP_MS })>
+                "-10s"
+            </button>
+            <Show
+                when=move || playhead.playing.get()
+                fallback=move || {
+                    view! { <button on:click=move |_| send(DataChannelMsg::Play)>"Play"</button> }
+                }
+            >
+                <button on:click=move |_| send(DataChannelMsg::Pause)>"Pause"</button>
+            </Show>
+            <button on:click=move |_| send(DataChannelMsg::Stop)>"Stop"</button>
+            <button on:click=move |_| send(DataChannelMsg::SeekBy { offset_ms: SKIP_MS })>
+                "+10s"
+            </button>
+            <input
+                type="range"
+                min="0"
+                max=move || playhead.duration_ms.get().unwrap_or(0)
+                step="100"
+                prop:value=shown
+                disabled=move || playhead.duration_ms.get().is_none()
+                on:input=move |ev| {
+                    if let Ok(position) = event_target_value(&ev).parse() {
+                        scrubbing.set(Some(position));
+                    }
+                }
+                on:change=move |ev| {
+                    if let Ok(position_ms) = event_target_value(&ev).parse() {
+                        send(DataChannelMsg::Seek { position_ms });
+                        playhead.position_ms.set(position_ms);
+                    }
+                    scrubbing.set(None);
+                }
+            />
+            <span class="transport-time">
+                {move || format_time(shown())} " / "
+                {move || playhead.duration_ms.get().map(format_time).unwrap_or_default()}
+            </span>
+        </div>
+        <SpeedControl />
+        <TransposeControl />
+        <LoopControls selected_jam_id=selected_jam_id />
+    }
+}
@@ -0,0 +1,213 @@
+use leptos::{html, logging::log, prelude::*, task::spawn_local};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{File, Headers, RequestInit, Response};
+
+use crate::backend::bands::get_bands;
+use crate::backend::database::Role;
+use crate::backend::upload::{complete_upload, create_upload, get_upload, CHUNK_SIZE};
+
+/// How often a failing chunk is retried before the upload is given up.
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+struct UploadRow {
+    file_name: String,
+    size: u64,
+    sent: u64,
+    message: Option<String>,
+}
+
+#[component]
+pub fn UploadPage() -> impl IntoView {
+    let input = NodeRef::<html::Input>::new();
+    let rows = RwSignal::new(Vec::<UploadRow>::new());
+    let (busy, set_busy) = signal(false);
+    let band = RwSignal::new(None::<i64>);
+    // Only bands we may upload to, and only those with somewhere to put the files.
+    let bands = Resource::new(
+        || (),
+        |_| async move {
+            get_bands().await.map(|bands| {
+                bands
+                    .into_iter()
+                    .filter(|b| b.role >= Some(Role::Editor) && !b.band.roots.is_empty())
+                    .map(|b| b.band)
+                    .collect::<Vec<_>>()
+            })
+        },
+    );
+
+    let start = move |_| {
+        let Some(band_id) = band.get_untracked() else {
+            return;
+        };
+        let Some(files) = input.get().and_then(|input| input.files()) else {
+            return;
+        };
+        let files: Vec<File> = (0..files.length()).filter_map(|i| files.get(i)).collect();
+        if files.is_empty() {
+            return;
+        }
+        rows.set(
+            files
+                .iter()
+                .map(|file| UploadRow {
+                    file_name: file.name(),
+                    size: file.size() as u64,
+                    sent: 0,
+                    message: None,
+                })
+                .collect(),
+        );
+        set_busy.set(true);
+
+        spawn_local(async move {
+            // One at a time, the disk on the other end is the bottleneck anyway.
+            for (index, file) in files.into_iter().enumerate() {
+                let message = match upload_file(band_id, &file, index, rows).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log!("Upload of {} failed: {}", file.name(), e);
+                        e
+                    }
+                };
+                rows.update(|rows| rows[index].message = Some(message));
+            }
+            set_busy.set(false);
+        });
+    };
+
+    view! {
+        <h1>"Upload recordings"</h1>
+        <nav>
+            <a href="/">"Home"</a>
+        </nav>
+        <p>"Stems are named like 01-KaggeN-210319_1833.wav and land in the jam of their date."</p>
+        <Suspense fallback=|| view! { <p>"Loading bands..."</p> }>
+            {move || Suspend::new(async move {
+                match bands.await {
+                    Ok(bands) if bands.is_empty() => {
+                        view! { <p>"You aren't an editor of any band with a library folder."</p> }
+                            .into_any()
+                    }
+                    Ok(bands) => {
+                        band.set(bands.first().map(|b| b.id));
+                        view! {
+                            <select on:change=move |ev| {
+                                band.set(event_target_value(&ev).parse().ok())
+                            }>
+                                {bands
+                                    .into_iter()
+                                    .map(|b| view! { <option value=b.id>{b.name}</option> })
+                                    .collect_view()}
+                            </select>
+                        }
+                            .into_any()
+                    }
+                    Err(e) => view! { <p class="error">{e.to_string()}</p> }.into_any(),
+                }
+            })}
+        </Suspense>
+        <input type="file" accept=".wav" multiple node_ref=input />
+        <button on:click=start disabled=move || busy.get() || band.get().is_none()>"Upload"</button>
+        <ul class="uploads">
+            <For
+                each=move || rows.get().into_iter().enumerate()
+                key=|(index, row)| (*index, row.sent, row.message.clone())
+                children=move |(_, row)| {
+                    view! {
+                        <li>
+                            {row.file_name}
+                            {match row.message {
+                                Some(message) => view! { <span>{message}</span> }.into_any(),
+                                None => {
+                                    view! {
+                                        <progress
+                                            max=row.size.to_string()
+                                            value=row.sent.to_string()
+                                        ></progress>
+                                    }
+                                        .into_any()
+                                }
+                            }}
+                        </li>
+                    }
+                }
+            />
+        </ul>
+    }
+}
+
+/// Sends `file` in chunks, picking up from the server's offset whenever a chunk fails.
+async fn upload_file(
+    band_id: i64,
+    file: &File,
+    index: usize,
+    rows: RwSignal<Vec<UploadRow>>,
+) -> Result<String, String> {
+    let size = file.size() as u64;
+    let upload = create_upload(band_id, file.name(), size)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut offset = upload.offset;
+    let mut retries = 0;
+
+    while offset < size {
+        let end = (offset + CHUNK_SIZE as u64).min(size);
+        match send_chunk(&upload.id, file, offset, end).await {
+            Ok(()) => {
+                offset = end;
+                retries = 0;
+            }
+            Err(e) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    return Err(e);
+                }
+                log!("Chunk at {} failed, resuming: {}", offset, e);
+                offset = get_upload(upload.id.clone())
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .offset;
+            }
+        }
+        rows.update(|rows| rows[index].sent = offset);
+    }
+
+    let result = complete_upload(upload.id)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(format!("Added {} to jam {}", result.track, result.date))
+}
+
+async fn send_chunk(id: &str, file: &File, start: u64, end: u64) -> Result<(), String> {
+    let js_err = |e: JsValue| format!("{:?}", e);
+    let chunk = file
+        .slice_with_f64_and_f64(start as f64, end as f64)
+        .map_err(js_err)?;
+
+    let headers = Headers::new().map_err(js_err)?;
+    headers
+        .set("Upload-Offset", &start.to_string())
+        .map_err(js_err)?;
+    headers
+        .set("Content-Type", "application/offset+octet-stream")
+        .map_err(js_err)?;
+
+    let init = RequestInit::new();
+    init.set_method("PATCH");
+    init.set_headers(&headers);
+    init.set_body(&chunk);
+
+    let window = web_sys::window().ok_or("No window")?;
+    let url = format!("/api/v1/uploads/{}", id);
+    let response = JsFuture::from(window.fetch_with_str_and_init(&url, &init))
+        .await
+        .map_err(js_err)?;
+    let response: Response = response.dyn_into().map_err(js_err)?;
+    if !response.ok() {
+        return Err(format!("Server answered {}", response.status()));
+    }
+    Ok(())
+}
@@ -1,7 +1,12 @@
 #![allow(warnings)]
 #![warn(unused_variables)]
 
-use futures::{channel::mpsc, stream::SplitSink, SinkExt, StreamExt};
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    stream::SplitSink,
+    SinkExt, StreamExt,
+};
 use leptos::{
     logging::{log, warn},
     prelude::*,
@@ -24,149 +29,454 @@ use web_sys::{
     js_sys::{self, JsString, Reflect},
     MediaStream, RtcRtpTransceiver,
 };
+use web_sys::{RtcConfiguration, RtcIceServer, RtcOfferOptions};
 use web_sys::{RtcDataChannel, RtcRtpTransceiverInit};
 use web_sys::{RtcDataChannelEvent, RtcPeerConnectionIceEvent};
 use web_sys::{RtcPeerConnection, RtcTrackEvent};
 
-use crate::backend::client_connections::{
-    get_gst_sdp_answer, post_client_sdp_offer, post_ice_candidate_to_server, ClientMessage,
-};
+use web_sys::{MessageEvent, RtcIceCandidateInit, RtcPeerConnectionState};
+
+use repan_protocol::{DataChannelMsg, IceSettings, SignalEvent, SignalRequest};
+
+use crate::backend::client_connections::{get_ice_servers, open_signalling};
+use crate::frontend::track_list::LiveMix;
+use crate::frontend::transport::{restore_mix_settings, Playhead};
+
+/// The streamer counts a session as idle after a few missed heartbeats.
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long a connection may stay disconnected before ICE is restarted. Short drops often
+/// recover by themselves.
+const DISCONNECT_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Where requests for the signalling channel go, replaced when the channel is reopened.
+type Requests =
+    StoredValue<mpsc::UnboundedSender<Result<SignalRequest, ServerFnError>>, LocalStorage>;
+
+fn send_request(requests: Requests, request: SignalRequest) {
+    requests.with_value(|requests| {
+        let _ = requests.unbounded_send(Ok(request));
+    });
+}
+
+/// Sends `msg` to the streamer over the data channel, if there is one yet.
+pub fn send_to_streamer(dc: Option<RtcDataChannel>, msg: &DataChannelMsg) {
+    let Some(dc) = dc else {
+        log!("Not connected to the streamer, {:?} isn't sent", msg);
+        return;
+    };
+    let msg = serde_json::to_string(msg).unwrap();
+    if let Err(e) = dc.send_with_str(&msg) {
+        log!("Couldn't send to the streamer: {:?}", e);
+    }
+}
+
+/// Opens the session's signalling channel, requests are sent over it from then on.
+async fn connect_signalling(
+    requests: Requests,
+) -> Result<BoxedStream<SignalEvent, ServerFnError>, ServerFnError> {
+    let (tx, rx) = mpsc::unbounded();
+    requests.set_value(tx);
+    open_signalling(rx.into()).await
+}
+
+/// How far connecting to the streamer got, shown under the player.
+#[derive(Clone, Debug, PartialEq)]
+enum StreamStatus {
+    Connecting,
+    /// Getting the stream back after the network dropped or the session's streamer went away.
+    Reconnecting(String),
+    Connected,
+    Failed(String),
+}
+
+/// A peer connection gathering candidates with the STUN and TURN servers of the server's
+/// configuration.
+fn peer_connection(ice: &IceSettings) -> Result<RtcPeerConnection, JsValue> {
+    let servers = js_sys::Array::new();
+    for ice_server in &ice.servers {
+        let urls = js_sys::Array::new();
+        for url in &ice_server.urls {
+            urls.push(&JsValue::from_str(url));
+        }
+        let server = RtcIceServer::new();
+        server.set_urls(&urls);
+        if let Some(username) = &ice_server.username {
+            server.set_username(username);
+        }
+        if let Some(credential) = &ice_server.credential {
+            server.set_credential(credential);
+        }
+        servers.push(&server);
+    }
+    let config = RtcConfiguration::new();
+    config.set_ice_servers(&servers);
+    RtcPeerConnection::new_with_configuration(&config)
+}
+async fn add_ice_candidate(pc: &RtcPeerConnection, candidate: RtcIceCandidateInit) {
+    let added = pc.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&candidate));
+    if let Err(e) = JsFuture::from(added).await {
+        warn!("Couldn't add the streamer's candidate: {:?}", e);
+    }
+}
+
+/// Whether the connection dropped and ICE has to be restarted to get it back.
+fn needs_restart(pc: &RtcPeerConnection) -> bool {
+    matches!(
+        pc.connection_state(),
+        RtcPeerConnectionState::Disconnected | RtcPeerConnectionState::Failed
+    )
+}
+
+/// Offers new ICE credentials on the running connection. The streamer keeps its pipeline, so
+/// playback carries on once the new candidates connect.
+async fn restart_ice(pc: &RtcPeerConnection, requests: Requests) -> Result<(), JsValue> {
+    let options = RtcOfferOptions::new();
+    options.set_ice_restart(true);
+    let offer = JsFuture::from(pc.create_offer_with_rtc_offer_options(&options)).await?;
+    let sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))?
+        .as_string()
+        .unwrap_or_default();
+
+    send_request(requests, SignalRequest::IceRestart { sdp: sdp.clone() });
+    let local = web_sys::RtcSessionDescriptionInit::new(web_sys::RtcSdpType::Offer);
+    local.set_sdp(sdp.as_str());
+    JsFuture::from(pc.set_local_description(&local)).await?;
+    Ok(())
+}
+
+/// Sets up a new peer connection and sends its offer over the signalling channel. Run again with
+/// a fresh connection when the session moves to another streamer. `restarts` is told when the
+/// connection needs an ICE restart, `on_message` gets what the streamer sends on the data
+/// channel.
+async fn negotiate(
+    ice: &IceSettings,
+    requests: Requests,
+    restarts: &mpsc::UnboundedSender<()>,
+    on_message: Callback<DataChannelMsg>,
+    audio: NodeRef<leptos::html::Audio>,
+    status: RwSignal<StreamStatus>,
+    set_pc: WriteSignal<Option<RtcPeerConnection>, LocalStorage>,
+    set_dc: WriteSignal<Option<RtcDataChannel>, LocalStorage>,
+) -> RtcPeerConnection {
+    use wasm_bindgen::{prelude::Closure, JsCast};
+
+    let pc_clone = peer_connection(ice).unwrap();
+    *set_pc.write() = Some(pc_clone.clone());
+    let dc_clone = pc_clone.create_data_channel("channel");
+    *set_dc.write() = Some(dc_clone.clone());
+
+    let mut tr_init = RtcRtpTransceiverInit::new();
+    tr_init.set_direction(web_sys::RtcRtpTransceiverDirection::Recvonly);
+    pc_clone
+        .clone()
+        .add_transceiver_with_str_and_init("audio", &tr_init);
+
+    let track_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcTrackEvent| {
+        let audio_el = audio.get().unwrap();
+        let streams = ev.streams();
+        let stream: MediaStream = streams.get(0).unchecked_into::<MediaStream>();
+        let _ = js_sys::Reflect::set(audio_el.as_ref(), &"srcObject".into(), stream.as_ref());
+        let _ = audio_el.play();
+
+        log!("On track callback called!");
+    });
+
+    pc_clone.set_ontrack(Some(track_callback.as_ref().unchecked_ref()));
+    track_callback.forget();
+
+    let dc_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcDataChannelEvent| {
+        log!("DC CALLBACK");
+    });
+    pc_clone.set_ondatachannel(Some(dc_callback.as_ref().unchecked_ref()));
+    dc_callback.forget();
+    let pc_state = pc_clone.clone();
+    let restarts = restarts.clone();
+    let conn_callback = Closure::<dyn FnMut()>::new(move || {
+        log!("Conn CALLBACK");
+        match pc_state.connection_state() {
+            RtcPeerConnectionState::Connected => status.set(StreamStatus::Connected),
+            RtcPeerConnectionState::Disconnected => {
+                status.set(StreamStatus::Reconnecting(
+                    "The connection dropped".to_string(),
+                ));
+                let pc = pc_state.clone();
+                let restarts = restarts.clone();
+                set_timeout(
+                    move || {
+                        if pc.connection_state() == RtcPeerConnectionState::Disconnected {
+                            let _ = restarts.unbounded_send(());
+                        }
+                    },
+                    DISCONNECT_GRACE,
+                );
+            }
+            RtcPeerConnectionState::Failed => {
+                status.set(StreamStatus::Reconnecting(
+                    "The connection to the streamer failed".to_string(),
+                ));
+                let _ = restarts.unbounded_send(());
+            }
+            _ => (),
+        }
+    });
+    pc_clone.set_onconnectionstatechange(Some(conn_callback.as_ref().unchecked_ref()));
+    conn_callback.forget();
+    let ice_settings = ice.clone();
+    let ice_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
+        // No candidate means gathering finished, the streamer gets that as an empty one.
+        let (candidate, mline) = match ev.candidate() {
+            Some(candidate) => (
+                candidate.candidate(),
+                candidate.sdp_m_line_index().unwrap_or(0),
+            ),
+            None => (String::new(), 0),
+        };
+        log!("{:?} {:?}", candidate, mline);
+        if !ice_settings.allows(&candidate) {
+            return;
+        }
+        send_request(
+            requests,
+            SignalRequest::Ice {
+                candidate,
+                sdp_mline_index: mline as u32,
+            },
+        );
+    });
+    pc_clone.set_onicecandidate(Some(ice_callback.as_ref().unchecked_ref()));
+    ice_callback.forget();
+
+    // The streamer tears down sessions whose heartbeats stop. Sending fails once the channel
+    // closed, with its peer connection or otherwise, which ends the interval.
+    let heartbeat = StoredValue::new_local(None::<IntervalHandle>);
+    let heartbeat_msg = serde_json::to_string(&DataChannelMsg::Heartbeat).unwrap();
+    let dc_clone2 = dc_clone.clone();
+    let func = Closure::<dyn FnMut()>::new(move || {
+        let dc = dc_clone2.clone();
+        // Every new pipeline starts out untransposed.
+        spawn_local(restore_mix_settings(dc.clone()));
+        let heartbeat_msg = heartbeat_msg.clone();
+        let interval = set_interval_with_handle(
+            move || {
+                if dc.send_with_str(&heartbeat_msg).is_err() {
+                    if let Some(interval) = heartbeat.get_value() {
+                        interval.clear();
+                    }
+                }
+            },
+            HEARTBEAT_INTERVAL,
+        );
+        heartbeat.set_value(interval.ok());
+    });
+    dc_clone
+        .clone()
+        .set_onopen(Some(func.as_ref().unchecked_ref()));
+    func.forget();
+    let msg_callback = Closure::<dyn FnMut(_)>::new(move |ev: MessageEvent| {
+        let Some(msg) = ev.data().as_string() else {
+            return;
+        };
+        match serde_json::from_str::<DataChannelMsg>(&msg) {
+            Ok(msg) => on_message.run(msg),
+            Err(e) => warn!("Unknown message from the streamer: {}", e),
+        }
+    });
+    dc_clone
+        .clone()
+        .set_onmessage(Some(msg_callback.as_ref().unchecked_ref()));
+    msg_callback.forget();
+
+    let offer = JsFuture::from(pc_clone.create_offer()).await.unwrap();
+    let sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
+        .ok()
+        .and_then(|sdp| sdp.as_string())
+        .unwrap_or_default();
+
+    // Sent before gathering starts, so the streamer has a pipeline for the candidates that
+    // follow.
+    log!("Attempting to post client sdp");
+    send_request(requests, SignalRequest::Offer { sdp: sdp.clone() });
+    log!("Posted client sdp");
+    let local = web_sys::RtcSessionDescriptionInit::new(web_sys::RtcSdpType::Offer);
+    local.set_sdp(sdp.as_str());
+    let local_promise = pc_clone.set_local_description(&local);
+    let _ = JsFuture::from(local_promise).await.unwrap();
+    pc_clone
+}
 
-const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
 #[component]
-pub fn OfferComp(user_id: ReadSignal<String>) -> impl IntoView {
+pub fn OfferComp() -> impl IntoView {
     let set_pc = use_context::<WriteSignal<Option<RtcPeerConnection>, LocalStorage>>()
         .expect("to have found setter");
-    let pc = use_context::<ReadSignal<Option<RtcPeerConnection>, LocalStorage>>()
-        .expect("to have found getter");
     let set_dc = use_context::<WriteSignal<Option<RtcDataChannel>, LocalStorage>>()
         .expect("to have found setter");
-    let dc = use_context::<ReadSignal<Option<RtcDataChannel>, LocalStorage>>()
-        .expect("to have found getter");
+    let mix = use_context::<LiveMix>().expect("to have found the live mix");
+    let playhead = use_context::<Playhead>().expect("to have found the playhead");
+    let on_message = Callback::new(move |msg: DataChannelMsg| match msg {
+        DataChannelMsg::Position { .. }
+        | DataChannelMsg::LoopSet { .. }
+        | DataChannelMsg::LoopRefused { .. }
+        | DataChannelMsg::RateSet { .. }
+        | DataChannelMsg::RateRefused { .. }
+        | DataChannelMsg::TransposeSet { .. }
+        | DataChannelMsg::TransposeRefused { .. } => playhead.reported(msg),
+        msg => mix.confirmed(msg),
+    });
     let audio: NodeRef<leptos::html::Audio> = NodeRef::new();
+    let status = RwSignal::new(StreamStatus::Connecting);
 
     if cfg!(feature = "hydrate") {
         mount_to_body(move || {
             spawn_local(async move {
-                let mut tr: Option<Rc<RtcRtpTransceiver>> = None;
-                *set_pc.write() = Some(RtcPeerConnection::new().unwrap());
-                let pc_clone = pc.get().clone().unwrap();
-                *set_dc.write() = Some(pc_clone.create_data_channel("channel"));
-                let dc_clone = dc.get().clone().unwrap();
-
-                let mut tr_init = RtcRtpTransceiverInit::new();
-                tr_init.set_direction(web_sys::RtcRtpTransceiverDirection::Recvonly);
-
-                tr = Some(Rc::new(
-                    pc_clone
-                        .clone()
-                        .add_transceiver_with_str_and_init("audio", &tr_init),
-                ));
-                let audio_elem = document().create_element("audio").unwrap();
-                let id = user_id.get();
-                use wasm_bindgen::{prelude::Closure, JsCast};
-
-                let track_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcTrackEvent| {
-                    let audio_el = audio.get().unwrap();
-                    let streams = ev.streams();
-                    let stream: MediaStream = streams.get(0).unchecked_into::<MediaStream>();
-                    let _ = js_sys::Reflect::set(
-                        audio_el.as_ref(),
-                        &"srcObject".into(),
-                        stream.as_ref(),
-                    );
-                    let _ = audio_el.play();
-
-                    log!("On track callback called!");
-                });
-
-                pc_clone.set_ontrack(Some(track_callback.as_ref().unchecked_ref()));
-                track_callback.forget();
-
-                use crate::backend::client_connections::GstJsonMsg;
-                let dc_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcDataChannelEvent| {
-                    log!("DC CALLBACK");
-                });
-                pc_clone.set_ondatachannel(Some(dc_callback.as_ref().unchecked_ref()));
-                dc_callback.forget();
-                let conn_callback = Closure::<dyn FnMut()>::new(move || {
-                    log!("Conn CALLBACK");
-                });
-                pc_clone.set_onconnectionstatechange(Some(conn_callback.as_ref().unchecked_ref()));
-                conn_callback.forget();
-                let id_clone = id.clone();
-                let ice_callback =
-                    Closure::<dyn FnMut(_)>::new(move |ev: RtcPeerConnectionIceEvent| {
-                        if let Some(candidate) = ev.candidate() {
-                            log!("{:?}", candidate.candidate());
-                            let mline = candidate.sdp_m_line_index().unwrap();
-                            log!("{:?}", mline);
-                            let id_clone = id_clone.clone();
-                            spawn_local(async move {
-                                log!("Trying to post ice candidate");
-                                let _ = post_ice_candidate_to_server(
-                                    id_clone.clone(),
-                                    candidate.candidate(),
-                                    mline as u32,
-                                )
-                                .await
-                                .unwrap();
-                            });
+                let ice = get_ice_servers().await.unwrap_or_default();
+                let requests: Requests = StoredValue::new_local(mpsc::unbounded().0);
+                let (restarts, mut restart_rx) = mpsc::unbounded::<()>();
+                let mut events = match connect_signalling(requests).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        status.set(StreamStatus::Failed(e.to_string()));
+                        return;
+                    }
+                };
+                let mut pc_clone = negotiate(
+                    &ice, requests, &restarts, on_message, audio, status, set_pc, set_dc,
+                )
+                .await;
+
+                // The streamer's candidates can overtake its answer, they are only added once the
+                // answer is set.
+                let mut early_candidates = vec![];
+                let mut answered = false;
+                // The channel is reopened once when it drops, the server keeps the session
+                // around for a while.
+                let mut reopened = false;
+                let mut restarting = false;
+                loop {
+                    let event = match future::select(events.next(), restart_rx.next()).await {
+                        Either::Left((Some(event), _)) => event,
+                        Either::Left((None, _)) if !reopened => {
+                            reopened = true;
+                            log!("Signalling channel closed, reopening it");
+                            match connect_signalling(requests).await {
+                                Ok(reopened_events) => events = reopened_events,
+                                Err(e) => {
+                                    warn!("Couldn't reopen the signalling channel: {:?}", e);
+                                    break;
+                                }
+                            }
+                            // Whatever the old channel had in flight is lost.
+                            restarting = false;
+                            if needs_restart(&pc_clone) {
+                                let _ = restarts.unbounded_send(());
+                            }
+                            continue;
+                        }
+                        Either::Left((None, _)) => break,
+                        // Once per drop, the connection stays disconnected or failed until the
+                        // restart's answer is in.
+                        Either::Right(_) => {
+                            if !restarting && needs_restart(&pc_clone) {
+                                restarting = true;
+                                early_candidates.clear();
+                                answered = false;
+                                if let Err(e) = restart_ice(&pc_clone, requests).await {
+                                    warn!("Couldn't restart ICE: {:?}", e);
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    reopened = false;
+                    match event {
+                        Ok(SignalEvent::Answer { sdp: answer }) => {
+                            log!("Got sdp from gstreamer");
+                            let remote = web_sys::RtcSessionDescriptionInit::new(
+                                web_sys::RtcSdpType::Answer,
+                            );
+                            remote.set_sdp(answer.as_str());
+                            let remote_promise = pc_clone.set_remote_description(&remote);
+                            if let Err(e) = JsFuture::from(remote_promise).await {
+                                status.set(StreamStatus::Failed(format!(
+                                    "The streamer's answer was rejected: {:?}",
+                                    e
+                                )));
+                                continue;
+                            }
+                            answered = true;
+                            restarting = false;
+                            for candidate in early_candidates.drain(..) {
+                                add_ice_candidate(&pc_clone, candidate).await;
+                            }
+                        }
+                        // In LAN mode only host candidates are used.
+                        Ok(SignalEvent::Ice { candidate, .. }) if !ice.allows(&candidate) => {
+                            log!("Ignored candidate {}", candidate)
                         }
-                    });
-                pc_clone.set_onicecandidate(Some(ice_callback.as_ref().unchecked_ref()));
-                ice_callback.forget();
-
-                let dc_clone2 = dc_clone.clone();
-                let func = Closure::<dyn FnMut()>::new(move || {
-                    log!("Sent from client");
-                    dc_clone2
-                        .send_with_str("Message from datachannel!")
-                        .unwrap();
-                });
-                dc_clone
-                    .clone()
-                    .set_onopen(Some(func.as_ref().unchecked_ref()));
-                func.forget();
-                let msg_callback = Closure::<dyn FnMut(_)>::new(move |ev: RtcDataChannelEvent| {
-                    log!("from server: {:?}", ev);
-                });
-                dc_clone
-                    .clone()
-                    .set_onmessage(Some(msg_callback.as_ref().unchecked_ref()));
-                msg_callback.forget();
-
-                let offer = JsFuture::from(pc_clone.create_offer()).await.unwrap();
-                let sdp_offer = js_sys::JSON::stringify(&offer)
-                    .unwrap()
-                    .as_string()
-                    .unwrap();
-
-                let json: GstJsonMsg = serde_json::from_str(sdp_offer.as_str()).unwrap();
-
-                if let GstJsonMsg::Sdp { sdp, r#type } = json {
-                    log!("Parsed sdp in client to local desc");
-                    let local = web_sys::RtcSessionDescriptionInit::new(web_sys::RtcSdpType::Offer);
-                    local.set_sdp(sdp.as_str());
-                    let local_promise = pc_clone.set_local_description(&local);
-                    let _ = JsFuture::from(local_promise).await.unwrap();
-                    log!("Attempting to post client sdp");
-
-                    let _ = post_client_sdp_offer(id.clone(), sdp_offer).await.unwrap();
-                    log!("Posted client sdp");
-                    let answer = get_gst_sdp_answer(id).await.unwrap();
-                    log!("Got sdp from gstreamer");
-                    let remote =
-                        web_sys::RtcSessionDescriptionInit::new(web_sys::RtcSdpType::Answer);
-                    remote.set_sdp(answer.as_str());
-                    log!("Created remote description");
-                    let remote_promise = pc_clone.set_remote_description(&remote);
-                    let _ = JsFuture::from(remote_promise).await.unwrap();
+                        // An empty candidate is the end of candidates, addIceCandidate takes it
+                        // as such.
+                        Ok(SignalEvent::Ice {
+                            candidate,
+                            sdp_mline_index,
+                        }) => {
+                            let init = RtcIceCandidateInit::new(&candidate);
+                            init.set_sdp_m_line_index(Some(sdp_mline_index as u16));
+                            if answered {
+                                add_ice_candidate(&pc_clone, init).await;
+                            } else {
+                                early_candidates.push(init);
+                            }
+                        }
+                        Ok(SignalEvent::Failed { reason }) => {
+                            status.set(StreamStatus::Failed(reason))
+                        }
+                        // The pipeline is gone with the streamer, the server says when another
+                        // one can take over.
+                        Ok(SignalEvent::Lost { reason }) => {
+                            pc_clone.close();
+                            status.set(StreamStatus::Reconnecting(reason));
+                        }
+                        Ok(SignalEvent::Closed { reason }) => {
+                            pc_clone.close();
+                            status.set(StreamStatus::Failed(reason));
+                        }
+                        // The next streamer's pipeline starts from scratch, so does the UI.
+                        Ok(SignalEvent::Renegotiate) => {
+                            pc_clone.close();
+                            playhead.reset();
+                            mix.reset();
+                            early_candidates.clear();
+                            answered = false;
+                            restarting = false;
+                            status.set(StreamStatus::Connecting);
+                            pc_clone = negotiate(
+                                &ice, requests, &restarts, on_message, audio, status, set_pc,
+                                set_dc,
+                            )
+                            .await;
+                        }
+                        Err(e) => status.set(StreamStatus::Failed(e.to_string())),
+                    }
+                }
+                if matches!(
+                    status.get_untracked(),
+                    StreamStatus::Connecting | StreamStatus::Reconnecting(_)
+                ) {
+                    status.set(StreamStatus::Failed(
+                        "Lost the connection to the server".to_string(),
+                    ));
                 }
             });
         });
     }
     view! {
         <audio node_ref=audio controls autoplay></audio>
+        {move || match status.get() {
+            StreamStatus::Connecting => view! { <p>"Connecting to the streamer..."</p> }.into_any(),
+            StreamStatus::Reconnecting(reason) => {
+                view! { <p>{reason}", reconnecting..."</p> }.into_any()
+            }
+            StreamStatus::Connected => ().into_any(),
+            StreamStatus::Failed(error) => view! { <p class="error">{error}</p> }.into_any(),
+        }}
     }
 }
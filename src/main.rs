@@ -14,7 +14,7 @@ use futures::{
 use leptos::{config::LeptosOptions, prelude::guards};
 use leptos::{logging::log, prelude::provide_context};
 use repan_stream::backend::{
-    client_connections::{self, ws_handler, CLIENT_SDP_ANSWERS},
+    client_connections::{self, ws_handler},
     database::Database,
 };
 use tokio::{
@@ -27,30 +27,65 @@ use tokio::{
 async fn main() {
     use std::collections::HashMap;
 
-    use axum::routing::{any, get};
+    use axum::routing::any;
     use axum::Router;
     use leptos::prelude::*;
     use leptos_axum::{generate_route_list, LeptosRoutes};
 
+    use repan_stream::backend::api::api_router;
     use repan_stream::backend::database::get_database;
     use repan_stream::{app::*, backend};
 
-    match get_database() {
+    let config = match repan_config::init() {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("{:?}", e);
-            return;
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if config.streamer.secret.is_empty() {
+        eprintln!("streamer.secret is not set, no streamer can connect");
+    }
+    if !config.server.secure_cookie {
+        eprintln!("server.secure_cookie is off, session cookies are also sent over plain http");
+    }
+
+    if let Err(e) = backend::metrics::install() {
+        eprintln!("Couldn't set up metrics: {}", e);
+        std::process::exit(1);
+    }
+
+    match get_database().and_then(|mut db| db.create_tables()) {
+        Err(e) => {
+            eprintln!(
+                "Can't open the database at {}: {:?}",
+                config.database.path.display(),
+                e
+            );
+            std::process::exit(1);
         }
         _ => println!("Database loaded"),
     }
+    backend::upload::spawn_upload_sweeper();
+    backend::render::spawn_render_sweeper();
+
+    let metrics_listen = config.server.metrics_listen;
+    tokio::spawn(async move {
+        if let Err(e) = backend::metrics::serve(metrics_listen).await {
+            eprintln!("Couldn't serve metrics on {}: {}", metrics_listen, e);
+        }
+    });
 
     let conf = get_configuration(None).unwrap();
-    let addr = conf.leptos_options.site_addr;
+    let addr = config.server.listen;
     let leptos_options = conf.leptos_options;
     // Generate the list of routes in your Leptos App
     let routes = generate_route_list(App);
 
     let app = Router::new()
         .route("/ws", any(ws_handler))
+        .nest("/api/v1", api_router())
         .leptos_routes(&leptos_options, routes, {
             let leptos_options = leptos_options.clone();
             move || shell(leptos_options.clone())
@@ -0,0 +1,87 @@
+//! Share links through the API router, the way someone without an account opens them.
+//! Needs the server side, run with `cargo test --features ssr`.
+#![cfg(feature = "ssr")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use repan_stream::backend::api::api_router;
+use repan_stream::backend::database::get_database;
+use repan_stream::backend::share::share_token;
+use tower::ServiceExt;
+
+const DATE: &str = "210319_1833";
+const TRACK: &str = "01-KaggeN";
+
+/// A jam with a single stem on disk, returns its jam id and track id.
+fn library() -> (i64, i64) {
+    static LIBRARY: OnceLock<(i64, i64)> = OnceLock::new();
+    *LIBRARY.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("repan-share-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let db = root.join("repan.db");
+        std::env::set_var("REPAN_DATABASE_PATH", &db);
+        std::env::set_var("REPAN_LIBRARY_ROOTS", "");
+        repan_config::init().expect("test configuration");
+
+        let mut conn = rusqlite::Connection::open(&db).unwrap();
+        jamdb::create_jam_table(&mut conn);
+        get_database().unwrap().create_tables().unwrap();
+        let dir = format!("{}/", root.display());
+        std::fs::write(format!("{}{}-{}.wav", dir, TRACK, DATE), b"RIFF....WAVE").unwrap();
+        let jam_id = jamdb::add_track(&mut conn, DATE, &dir, TRACK).unwrap();
+
+        let track_id = get_database().unwrap().shared_stem_files(jam_id).unwrap()[0].track_id;
+        (jam_id, track_id)
+    })
+}
+
+fn share(allow_download: bool) -> String {
+    static USERS: AtomicUsize = AtomicUsize::new(0);
+    let (jam_id, _) = library();
+    let mut db = get_database().unwrap();
+    let name = format!("sharer-{}", USERS.fetch_add(1, Ordering::Relaxed));
+    let user = db.add_user(&name, "", false).unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let id = db
+        .add_share_link(jam_id, None, allow_download, user.id, now, now + 3600)
+        .unwrap();
+    share_token(&db.share_link(id).unwrap().unwrap()).unwrap()
+}
+
+async fn get(uri: String) -> StatusCode {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    api_router::<()>().oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn stems_need_a_link_that_allows_downloads() {
+    let (_, track_id) = library();
+
+    let locked = share(false);
+    let uri = format!("/shared/{}/tracks/{}/audio", locked, track_id);
+    assert_eq!(get(uri).await, StatusCode::FORBIDDEN);
+    let uri = format!("/shared/{}/stems.zip", locked);
+    assert_eq!(get(uri).await, StatusCode::FORBIDDEN);
+
+    let open = share(true);
+    let uri = format!("/shared/{}/tracks/{}/audio", open, track_id);
+    assert_eq!(get(uri).await, StatusCode::OK);
+    let uri = format!(
+        "/shared/{}/stems.zip?tracks={},{}",
+        open, track_id, track_id
+    );
+    assert_eq!(get(uri).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn mix_is_missing_until_rendered() {
+    let token = share(false);
+    assert_eq!(
+        get(format!("/shared/{}/mix", token)).await,
+        StatusCode::NOT_FOUND
+    );
+}
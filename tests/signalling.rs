@@ -0,0 +1,399 @@
+//! A browser's signalling channel end to end, with the mock streamer in place of webrtctest.
+//! Needs the server side, run with `cargo test --features ssr`.
+#![cfg(feature = "ssr")]
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use axum::http::{header, Request};
+use axum::routing::any;
+use axum::Router;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::StreamExt;
+use leptos::prelude::*;
+use leptos::server_fn::BoxedStream;
+use mock_streamer::{
+    MockConfig, MockStreamer, Rejected, CANNED_ANSWER, CANNED_CANDIDATE, WAIT_TIMEOUT,
+};
+use repan_protocol::{
+    ServerMsg, SessionState, SignalEvent, SignalRequest, StreamerMsg, PROTOCOL_VERSION,
+};
+use repan_stream::backend::auth::SESSION_COOKIE;
+use repan_stream::backend::client_connections::{open_signalling, ws_handler};
+use repan_stream::backend::database::get_database;
+use repan_stream::backend::streamers;
+
+const SECRET: &str = "signalling-test-secret";
+
+/// The streamers are global to the server, tests taking turns keeps them apart.
+static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The server's `/ws`. It runs on a runtime of its own for the whole test binary, streamer
+/// sockets outlive the test that opened them just like they outlive requests.
+fn server() -> &'static str {
+    static URL: OnceLock<String> = OnceLock::new();
+    URL.get_or_init(|| {
+        let db = std::env::temp_dir().join(format!("repan-signalling-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        std::env::set_var("REPAN_DATABASE_PATH", &db);
+        std::env::set_var("REPAN_LIBRARY_ROOTS", "");
+        std::env::set_var("REPAN_STREAMER_SECRET", SECRET);
+        std::env::set_var("REPAN_SERVER_ANSWER_TIMEOUT_SECS", "1");
+        repan_config::init().expect("test configuration");
+        jamdb::create_jam_table(&mut rusqlite::Connection::open(&db).unwrap());
+        get_database().unwrap().create_tables().unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let app = Router::new().route("/ws", any(ws_handler));
+                axum::serve(listener, app).await.unwrap();
+            });
+        });
+        url
+    })
+}
+
+/// Connects a mock once the streamers of earlier tests are gone.
+async fn streamer(config: MockConfig) -> MockStreamer {
+    let url = server();
+    for _ in 0..50 {
+        if streamers::list().await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    MockStreamer::connect(url, SECRET, config).await.unwrap()
+}
+
+/// A logged in browser, its server function calls see its session cookie.
+struct Browser {
+    owner: Owner,
+    /// The id the streamer knows the browser's session by.
+    id: String,
+}
+
+impl Browser {
+    fn new(username: &str) -> Browser {
+        server();
+        let mut db = get_database().unwrap();
+        let user = db.add_user(username, "not a hash", false).unwrap();
+        let token = format!("{}-token", username);
+        let expires = chrono::Utc::now().timestamp() + 3600;
+        let session = db.add_session(&token, user.id, expires).unwrap();
+
+        let (parts, _) = Request::builder()
+            .header(header::COOKIE, format!("{}={}", SESSION_COOKIE, token))
+            .body(())
+            .unwrap()
+            .into_parts();
+        let owner = Owner::new();
+        owner.with(|| provide_context(parts));
+        Browser {
+            owner,
+            id: format!("session-{}", session),
+        }
+    }
+
+    async fn open_signalling(
+        &self,
+    ) -> (
+        UnboundedSender<Result<SignalRequest, ServerFnError>>,
+        BoxedStream<SignalEvent, ServerFnError>,
+    ) {
+        self.owner.set();
+        let (requests, request_rx) = mpsc::unbounded();
+        let events = open_signalling(request_rx.into()).await.unwrap();
+        (requests, events)
+    }
+}
+
+fn send(requests: &UnboundedSender<Result<SignalRequest, ServerFnError>>, request: SignalRequest) {
+    requests.unbounded_send(Ok(request)).unwrap();
+}
+
+async fn next_event(events: &mut BoxedStream<SignalEvent, ServerFnError>) -> SignalEvent {
+    match tokio::time::timeout(WAIT_TIMEOUT, events.next()).await {
+        Ok(Some(Ok(event))) => event,
+        other => panic!("Expected a signalling event, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn offer_reaches_the_streamer_and_its_answer_the_browser() {
+    let _serial = SERIAL.lock().await;
+    let mock = streamer(MockConfig::default()).await;
+    let browser = Browser::new("offerer");
+    let (requests, mut events) = browser.open_signalling().await;
+
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    let offer = mock
+        .wait_for(WAIT_TIMEOUT, |m| matches!(m.msg, ServerMsg::Offer { .. }))
+        .await
+        .expect("the offer");
+    assert_eq!(offer.id, browser.id);
+    assert_eq!(
+        offer.msg,
+        ServerMsg::Offer {
+            sdp: "v=0 offer".to_string()
+        }
+    );
+
+    assert_eq!(
+        next_event(&mut events).await,
+        SignalEvent::Answer {
+            sdp: CANNED_ANSWER.to_string()
+        }
+    );
+    assert_eq!(
+        next_event(&mut events).await,
+        SignalEvent::Ice {
+            candidate: CANNED_CANDIDATE.to_string(),
+            sdp_mline_index: 0
+        }
+    );
+    assert_eq!(
+        next_event(&mut events).await,
+        SignalEvent::Ice {
+            candidate: String::new(),
+            sdp_mline_index: 0
+        }
+    );
+
+    let candidate = "candidate:2 1 UDP 2122252543 192.168.1.2 50000 typ host".to_string();
+    send(
+        &requests,
+        SignalRequest::Ice {
+            candidate: candidate.clone(),
+            sdp_mline_index: 0,
+        },
+    );
+    let ice = mock
+        .wait_for(WAIT_TIMEOUT, |m| matches!(m.msg, ServerMsg::Ice { .. }))
+        .await
+        .expect("the browser's candidate");
+    assert_eq!(ice.id, browser.id);
+    assert_eq!(
+        ice.msg,
+        ServerMsg::Ice {
+            candidate,
+            sdp_mline_index: 0
+        }
+    );
+}
+
+#[tokio::test]
+async fn ice_restart_goes_to_the_running_pipeline() {
+    let _serial = SERIAL.lock().await;
+    let mock = streamer(MockConfig::default()).await;
+    let browser = Browser::new("restarter");
+    let (requests, mut events) = browser.open_signalling().await;
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    assert!(matches!(
+        next_event(&mut events).await,
+        SignalEvent::Answer { .. }
+    ));
+    // Up to the streamer's last, empty candidate.
+    let end_of_candidates = SignalEvent::Ice {
+        candidate: String::new(),
+        sdp_mline_index: 0,
+    };
+    while next_event(&mut events).await != end_of_candidates {}
+
+    // A new network, the channel is reopened and ICE restarted.
+    drop(requests);
+    drop(events);
+    let (requests, mut events) = browser.open_signalling().await;
+    send(
+        &requests,
+        SignalRequest::IceRestart {
+            sdp: "v=0 restart".to_string(),
+        },
+    );
+    let restart = mock
+        .wait_for(WAIT_TIMEOUT, |m| {
+            matches!(m.msg, ServerMsg::IceRestart { .. })
+        })
+        .await
+        .expect("the restart");
+    assert_eq!(restart.id, browser.id);
+    assert_eq!(
+        next_event(&mut events).await,
+        SignalEvent::Answer {
+            sdp: CANNED_ANSWER.to_string()
+        }
+    );
+    let offers = mock
+        .received()
+        .iter()
+        .filter(|m| matches!(m.msg, ServerMsg::Offer { .. }))
+        .count();
+    assert_eq!(offers, 1, "a restart keeps the pipeline");
+}
+
+#[tokio::test]
+async fn unanswered_offer_fails() {
+    let _serial = SERIAL.lock().await;
+    let _mock = streamer(MockConfig {
+        answer_offers: false,
+        ..MockConfig::default()
+    })
+    .await;
+    let browser = Browser::new("waiter");
+    let (requests, mut events) = browser.open_signalling().await;
+
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    assert!(matches!(
+        next_event(&mut events).await,
+        SignalEvent::Failed { .. }
+    ));
+}
+
+#[tokio::test]
+async fn failed_offer_reaches_the_browser() {
+    let _serial = SERIAL.lock().await;
+    let mock = streamer(MockConfig {
+        answer_offers: false,
+        ..MockConfig::default()
+    })
+    .await;
+    let browser = Browser::new("refused");
+    let (requests, mut events) = browser.open_signalling().await;
+
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    mock.wait_for(WAIT_TIMEOUT, |m| matches!(m.msg, ServerMsg::Offer { .. }))
+        .await
+        .expect("the offer reached the streamer");
+    mock.send(
+        &browser.id,
+        StreamerMsg::OfferFailed {
+            reason: "no codec in common".to_string(),
+        },
+    );
+    assert_eq!(
+        next_event(&mut events).await,
+        SignalEvent::Failed {
+            reason: "no codec in common".to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn lost_streamer_sends_its_listeners_to_the_next() {
+    let _serial = SERIAL.lock().await;
+    let mock = streamer(MockConfig::default()).await;
+    let browser = Browser::new("listener");
+    let (requests, mut events) = browser.open_signalling().await;
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    assert!(matches!(
+        next_event(&mut events).await,
+        SignalEvent::Answer { .. }
+    ));
+
+    drop(mock);
+    loop {
+        match next_event(&mut events).await {
+            SignalEvent::Lost { .. } => break,
+            SignalEvent::Ice { .. } => (),
+            other => panic!("Expected the session to be lost, got {:?}", other),
+        }
+    }
+    let _next = streamer(MockConfig {
+        name: "next".to_string(),
+        ..MockConfig::default()
+    })
+    .await;
+    assert_eq!(next_event(&mut events).await, SignalEvent::Renegotiate);
+}
+
+#[tokio::test]
+async fn streamers_have_to_sign_and_match_the_protocol() {
+    let _serial = SERIAL.lock().await;
+    let url = server();
+
+    let forged = MockStreamer::connect(url, "not the server's secret", MockConfig::default()).await;
+    assert!(forged.is_err(), "connected with a forged token");
+
+    let outdated = MockStreamer::connect(
+        url,
+        SECRET,
+        MockConfig {
+            protocol: PROTOCOL_VERSION - 1,
+            ..MockConfig::default()
+        },
+    )
+    .await;
+    let error = outdated.err().expect("an outdated streamer connected");
+    assert!(error.is::<Rejected>(), "{:?}", error);
+}
+
+#[tokio::test]
+async fn streamer_only_speaks_for_its_own_sessions() {
+    let _serial = SERIAL.lock().await;
+    let _mock = streamer(MockConfig::default()).await;
+    let browser = Browser::new("bystander");
+    let (requests, mut events) = browser.open_signalling().await;
+    send(
+        &requests,
+        SignalRequest::Offer {
+            sdp: "v=0 offer".to_string(),
+        },
+    );
+    assert!(matches!(
+        next_event(&mut events).await,
+        SignalEvent::Answer { .. }
+    ));
+    let end_of_candidates = SignalEvent::Ice {
+        candidate: String::new(),
+        sdp_mline_index: 0,
+    };
+    while next_event(&mut events).await != end_of_candidates {}
+
+    let intruder = MockStreamer::connect(
+        server(),
+        SECRET,
+        MockConfig {
+            name: "intruder".to_string(),
+            ..MockConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    intruder.send(
+        &browser.id,
+        StreamerMsg::Session {
+            state: SessionState::Closed,
+        },
+    );
+    let next = tokio::time::timeout(Duration::from_millis(300), events.next()).await;
+    assert!(next.is_err(), "the session was closed by {:?}", next);
+}